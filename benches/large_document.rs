@@ -0,0 +1,140 @@
+//! Compares the peak memory of patching a large document as a `Value`
+//! against patching it as a `LazyValue`.
+//!
+//! Run with `cargo bench --bench large_document`, the document size in MB
+//! can be changed with the `BENCH_SIZE_MB` environment variable
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    env,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use json_patch::Patch;
+use json_revisor::lazy::{self, LazyValue};
+use serde_json::{json, Value};
+
+struct PeakAlloc;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for PeakAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let current = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(current, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: PeakAlloc = PeakAlloc;
+
+/// Runs `f` and returns the peak amount of bytes allocated while it ran
+fn measure(name: &str, f: impl FnOnce()) -> usize {
+    let baseline = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(baseline, Ordering::Relaxed);
+    let start = Instant::now();
+    f();
+    let peak = PEAK.load(Ordering::Relaxed) - baseline;
+    println!(
+        "{:<8} peak {:>8.1} MB in {:>6.2}s",
+        name,
+        peak as f64 / 1024.0 / 1024.0,
+        start.elapsed().as_secs_f64()
+    );
+    peak
+}
+
+/// Writes an action dump shaped document of roughly `size` bytes
+fn generate(path: &Path, size: usize) {
+    let mut writer = BufWriter::new(File::create(path).unwrap());
+    write!(writer, "{{\"actions\":[").unwrap();
+    let mut written = 0;
+    let mut index = 0;
+    while written < size {
+        let category = ["Player", "Entity", "Game"][index % 3];
+        let action = json!({
+            "name": format!("Action {}", index),
+            "category": category,
+            "description": ["Does a thing to a target", "With some more text"],
+            "arguments": [
+                { "type": "NUMBER", "optional": false, "default": index },
+                { "type": "TEXT", "optional": true, "plural": false }
+            ],
+            "icon": { "material": "STONE", "lore": [], "amount": 1.5 }
+        })
+        .to_string();
+        if index > 0 {
+            write!(writer, ",").unwrap();
+        }
+        writer.write_all(action.as_bytes()).unwrap();
+        written += action.len() + 1;
+        index += 1;
+    }
+    write!(writer, "],\"version\":\"1.0\"}}").unwrap();
+    writer.flush().unwrap();
+}
+
+fn main() {
+    let size_mb: usize = env::var("BENCH_SIZE_MB")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(64);
+
+    let dir = env::temp_dir().join("json-revisor-bench");
+    fs::create_dir_all(&dir).unwrap();
+    let original = dir.join("original.json");
+    let value_output = dir.join("value.json");
+    let lazy_output = dir.join("lazy.json");
+    generate(&original, size_mb * 1024 * 1024);
+
+    let patch: Patch = serde_json::from_value(json!([
+        { "op": "replace", "path": "/version", "value": "2.0" },
+        { "op": "add", "path": "/actions/10/arguments/-", "value": { "type": "ITEM" } },
+        { "op": "remove", "path": "/actions/20/icon" },
+        { "op": "move", "from": "/actions/30", "path": "/actions/0" }
+    ]))
+    .unwrap();
+
+    println!("Patching a {} MB document", size_mb);
+
+    let value_peak = measure("value", || {
+        let text = fs::read_to_string(&original).unwrap();
+        let mut document: Value = serde_json::from_str(&text).unwrap();
+        json_patch::patch(&mut document, &patch).unwrap();
+        let output = serde_json::to_string_pretty(&document).unwrap();
+        fs::write(&value_output, output).unwrap();
+    });
+
+    let lazy_peak = measure("lazy", || {
+        let mut document = LazyValue::from_path(&original).unwrap();
+        lazy::patch(&mut document, &patch).unwrap();
+        let mut writer = BufWriter::new(File::create(&lazy_output).unwrap());
+        serde_json::to_writer_pretty(&mut writer, &document).unwrap();
+        writer.flush().unwrap();
+    });
+
+    assert!(
+        fs::read(&value_output).unwrap() == fs::read(&lazy_output).unwrap(),
+        "Both approaches must produce the same output"
+    );
+    println!(
+        "lazy uses {:.1}x less memory",
+        value_peak as f64 / lazy_peak as f64
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
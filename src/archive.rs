@@ -0,0 +1,156 @@
+use std::io::{Cursor, Write};
+
+use flate2::{write::GzEncoder, Compression, GzBuilder};
+use tar::{Builder, EntryType, Header};
+use zip::{write::SimpleFileOptions, CompressionMethod, DateTime, ZipWriter};
+
+/// Archive formats `package` can write
+#[derive(Clone, Copy)]
+pub enum ArchiveFormat {
+    TarGz,
+    Zip,
+}
+
+impl ArchiveFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "tar.gz" | "tgz" => Some(ArchiveFormat::TarGz),
+            "zip" => Some(ArchiveFormat::Zip),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+
+    /// Writes the entries in the given order.
+    ///
+    /// Every timestamp, owner and permission is fixed, so the same entries always give
+    /// the same bytes
+    pub fn write(self, entries: &[Entry]) -> Result<Vec<u8>, String> {
+        match self {
+            ArchiveFormat::TarGz => tar_gz(entries).map_err(|err| err.to_string()),
+            ArchiveFormat::Zip => zip(entries).map_err(|err| err.to_string()),
+        }
+    }
+}
+
+/// A file of the archive, `path` uses `/` between folders
+pub struct Entry {
+    pub path: String,
+    pub bytes: Vec<u8>,
+}
+
+fn tar_gz(entries: &[Entry]) -> std::io::Result<Vec<u8>> {
+    // No file name and no modification time in the gzip header
+    let encoder: GzEncoder<Vec<u8>> = GzBuilder::new()
+        .mtime(0)
+        .operating_system(255)
+        .write(Vec::new(), Compression::default());
+    let mut builder = Builder::new(encoder);
+    for entry in entries {
+        let mut header = Header::new_ustar();
+        header.set_entry_type(EntryType::Regular);
+        header.set_size(entry.bytes.len() as u64);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        builder.append_data(&mut header, &entry.path, entry.bytes.as_slice())?;
+    }
+    builder.into_inner()?.finish()
+}
+
+fn zip(entries: &[Entry]) -> zip::result::ZipResult<Vec<u8>> {
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    // 1980-01-01 00:00, the earliest date a zip archive can hold
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .last_modified_time(DateTime::default())
+        .unix_permissions(0o644);
+    for entry in entries {
+        let options = options.large_file(entry.bytes.len() as u64 >= u64::from(u32::MAX));
+        writer.start_file(entry.path.as_str(), options)?;
+        writer.write_all(&entry.bytes)?;
+    }
+    Ok(writer.finish()?.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    fn entries() -> Vec<Entry> {
+        // Repeats enough to be compressed, and a path too long for the ustar name field
+        let long_path = format!("x-1.0.0/{}/file.json", "folder".repeat(30));
+        vec![
+            Entry {
+                path: "x-1.0.0/manifest.json".to_string(),
+                bytes: br#"{"name": "x"}"#.to_vec(),
+            },
+            Entry {
+                path: "x-1.0.0/data/actions.json".to_string(),
+                bytes: b"[{\"name\": \"Give\"}, ".repeat(5000),
+            },
+            Entry {
+                path: long_path,
+                bytes: Vec::new(),
+            },
+        ]
+    }
+
+    #[test]
+    fn tar_gz_round_trip() {
+        let entries = entries();
+        let archive = ArchiveFormat::TarGz.write(&entries).unwrap();
+        let mut tar = tar::Archive::new(GzDecoder::new(archive.as_slice()));
+        let mut read = Vec::new();
+        for entry in tar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            assert_eq!(entry.header().mtime().unwrap(), 0);
+            assert_eq!(entry.header().mode().unwrap(), 0o644);
+            let mut bytes = Vec::new();
+            entry.read_to_end(&mut bytes).unwrap();
+            read.push((path, bytes));
+        }
+        let expected: Vec<(String, Vec<u8>)> = entries
+            .into_iter()
+            .map(|entry| (entry.path, entry.bytes))
+            .collect();
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn zip_round_trip() {
+        let entries = entries();
+        let archive = ArchiveFormat::Zip.write(&entries).unwrap();
+        let mut zip = zip::ZipArchive::new(Cursor::new(archive)).unwrap();
+        assert_eq!(zip.len(), entries.len());
+        for (index, expected) in entries.iter().enumerate() {
+            let mut file = zip.by_index(index).unwrap();
+            assert_eq!(file.name(), expected.path);
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes).unwrap();
+            assert_eq!(bytes, expected.bytes);
+        }
+    }
+
+    #[test]
+    fn same_entries_give_same_bytes() {
+        for format in [ArchiveFormat::TarGz, ArchiveFormat::Zip] {
+            assert_eq!(
+                format.write(&entries()).unwrap(),
+                format.write(&entries()).unwrap()
+            );
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use crate::error::AppError;
+
+/// Options that consume the argument following them as their value
+/// (`--jobs 4`), every other `--option` is treated as a boolean flag.
+/// `--option=value` is accepted for both.
+const VALUE_OPTIONS: &[&str] = &[
+    "message-format",
+    "jobs",
+    "name",
+    "description",
+    "version",
+    "author",
+    "license",
+    "original",
+    "changes",
+    "revise",
+    "import",
+    "modified",
+    "profile",
+    "format",
+    "output",
+    "lang",
+    "schema",
+];
+
+/// Command line arguments split into the command, its positional arguments and its options
+pub struct Arguments {
+    pub command: Option<String>,
+    pub positional: Vec<String>,
+    /// Position of every positional argument
+    positional_pos: Vec<usize>,
+    options: Vec<(String, Option<String>, usize)>,
+}
+
+impl Arguments {
+    pub fn parse(args: impl Iterator<Item = String>) -> Self {
+        let mut command = None;
+        let mut positional = Vec::new();
+        let mut positional_pos = Vec::new();
+        let mut options = Vec::new();
+
+        let mut args = args.enumerate().skip(1);
+        while let Some((pos, arg)) = args.next() {
+            if let Some(option) = arg.strip_prefix("--") {
+                if let Some((name, value)) = option.split_once('=') {
+                    options.push((name.to_string(), Some(value.to_string()), pos));
+                } else if VALUE_OPTIONS.contains(&option) {
+                    let Some((_, value)) = args.next() else {
+                        AppError::InvalidArgument {
+                            argument_pos: pos,
+                            message: "option is missing a value",
+                        }
+                        .throw();
+                    };
+                    options.push((option.to_string(), Some(value), pos));
+                } else {
+                    options.push((option.to_string(), None, pos));
+                }
+            } else if command.is_none() {
+                command = Some(arg);
+            } else {
+                positional.push(arg);
+                positional_pos.push(pos);
+            }
+        }
+
+        Self {
+            command,
+            positional,
+            positional_pos,
+            options,
+        }
+    }
+
+    /// Throws if an option not in `allowed` was given
+    pub fn expect_options(&self, allowed: &[&str]) {
+        for (name, _, pos) in &self.options {
+            if !allowed.contains(&name.as_str()) {
+                AppError::InvalidArgument {
+                    argument_pos: *pos,
+                    message: "not a valid option for this command",
+                }
+                .throw();
+            }
+        }
+    }
+
+    /// Throws if the amount of positional arguments is not `expected`
+    pub fn expect_positional(&self, expected: usize) {
+        if self.positional.len() != expected {
+            AppError::UnexpectedArgumentSize {
+                expected,
+                received: self.positional.len(),
+            }
+            .throw();
+        }
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.options.iter().any(|(option, _, _)| option == name)
+    }
+
+    /// Last value given for the option, so later options override earlier ones
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .rev()
+            .find(|(option, _, _)| option == name)
+            .and_then(|(_, value, _)| value.as_deref())
+    }
+
+    /// Every value given for an option that can be repeated
+    pub fn values(&self, name: &str) -> Vec<&str> {
+        self.options
+            .iter()
+            .filter(|(option, _, _)| option == name)
+            .filter_map(|(_, value, _)| value.as_deref())
+            .collect()
+    }
+
+    /// Position of the option on the command line, used for error reporting
+    pub fn position(&self, name: &str) -> usize {
+        self.options
+            .iter()
+            .rev()
+            .find(|(option, _, _)| option == name)
+            .map_or(0, |(_, _, pos)| *pos)
+    }
+
+    /// Position of the positional argument at `index`, for error messages
+    pub fn positional_position(&self, index: usize) -> usize {
+        self.positional_pos.get(index).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Arguments {
+        let args = ["json-revisor"]
+            .iter()
+            .chain(args)
+            .map(|arg| arg.to_string());
+        Arguments::parse(args)
+    }
+
+    #[test]
+    fn splits_command_positional_and_options() {
+        let args = parse(&[
+            "status",
+            "--force",
+            "a.json",
+            "--message-format",
+            "json",
+            "b.json",
+        ]);
+        assert_eq!(args.command.as_deref(), Some("status"));
+        assert_eq!(args.positional, ["a.json", "b.json"]);
+        assert_eq!(args.positional_position(0), 3);
+        assert_eq!(args.positional_position(1), 6);
+        assert_eq!(args.positional_position(2), 0);
+        assert!(args.flag("force"));
+        assert!(!args.flag("message-format-json"));
+        assert_eq!(args.value("message-format"), Some("json"));
+        assert_eq!(args.position("message-format"), 4);
+    }
+
+    #[test]
+    fn values_with_equals_sign() {
+        let args = parse(&[
+            "build",
+            "--message-format=json",
+            "--force=yes",
+            "--name=a=b",
+        ]);
+        assert_eq!(args.value("message-format"), Some("json"));
+        assert_eq!(args.value("force"), Some("yes"));
+        assert_eq!(args.value("name"), Some("a=b"));
+        assert!(args.positional.is_empty());
+    }
+
+    #[test]
+    fn flags_have_no_value() {
+        let args = parse(&["status", "--force", "a.json"]);
+        assert_eq!(args.value("force"), None);
+        assert_eq!(args.positional, ["a.json"]);
+        assert_eq!(args.value("missing"), None);
+        assert_eq!(args.position("missing"), 0);
+    }
+
+    #[test]
+    fn later_values_override_and_repeated_values_are_kept() {
+        let args = parse(&[
+            "init",
+            "--message-format",
+            "human",
+            "--message-format=json",
+            "--author",
+            "x",
+            "--author",
+            "y",
+        ]);
+        assert_eq!(args.value("message-format"), Some("json"));
+        assert_eq!(args.position("message-format"), 4);
+        assert_eq!(args.values("author"), ["x", "y"]);
+        assert!(args.values("name").is_empty());
+    }
+
+    #[test]
+    fn value_that_looks_like_an_option() {
+        let args = parse(&["status", "--message-format", "--force"]);
+        assert_eq!(args.value("message-format"), Some("--force"));
+        assert!(!args.flag("force"));
+    }
+}
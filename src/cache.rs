@@ -0,0 +1,124 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, create_dir_all, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::{AppError, AppErrorIo};
+
+pub const CACHE_DIR: &str = ".json-revisor";
+pub const CACHE_FILE: &str = "cache";
+
+/// Bumped whenever the layout changes, older caches are then ignored
+const CACHE_VERSION: u32 = 8;
+
+/// Content hashes of the files each command last processed,
+/// so files that did not change can be skipped
+#[derive(Serialize, Deserialize, Default)]
+pub struct Cache {
+    version: u32,
+    #[serde(default)]
+    pub build: CacheTable,
+    #[serde(default)]
+    pub update: CacheTable,
+    /// Build tables of the profiles by their name
+    #[serde(default)]
+    pub profiles: BTreeMap<String, CacheTable>,
+}
+
+/// Cache entries by the path of the original file
+pub type CacheTable = BTreeMap<String, CacheEntry>;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct CacheEntry {
+    pub original: String,
+    pub changes: String,
+    pub revise: String,
+    /// Combined hash of the schemas the output was validated with
+    pub schema: String,
+    /// Hash of the rules file, empty if there is none
+    #[serde(default)]
+    pub rules: String,
+    /// Combined hash of the transforms the output was run through
+    #[serde(default)]
+    pub transforms: String,
+    /// Combined hash of the patches of the lower change layers, empty if there are none
+    #[serde(default)]
+    pub layers: String,
+    /// Hash of the variables the patch used, empty if there are none
+    #[serde(default)]
+    pub variables: String,
+    /// Combined hash of the outputs of the files the patch includes values of
+    #[serde(default)]
+    pub references: String,
+    /// Whether update wrote the patch with guards
+    #[serde(default)]
+    pub guard: bool,
+}
+
+impl Cache {
+    fn path() -> PathBuf {
+        Path::new(CACHE_DIR).join(CACHE_FILE)
+    }
+
+    /// Loads the cache, a missing, outdated or broken cache is treated as empty
+    pub fn load() -> Self {
+        fs::read(Self::path())
+            .ok()
+            .and_then(|buf| serde_json::from_slice::<Cache>(&buf).ok())
+            .filter(|cache| cache.version == CACHE_VERSION)
+            .unwrap_or_default()
+    }
+
+    pub fn save(mut self) -> Result<(), AppError<'static>> {
+        self.version = CACHE_VERSION;
+        let path = Self::path();
+        if let Err(err) = create_dir_all(CACHE_DIR) {
+            return Err(AppError::IoErrorPath(
+                err.attach_path(PathBuf::from(CACHE_DIR).into()),
+            ));
+        }
+        let data = serde_json::to_vec(&self).expect("Cache always serializes");
+        fs::write(&path, data).map_err(|err| AppError::IoErrorPath(err.attach_path(path.into())))
+    }
+}
+
+pub fn cache_key(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+pub fn hash(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Hashes a file without reading all of it into memory
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Writer that only hashes what is written to it
+#[derive(Default)]
+pub struct HashWriter(Sha256);
+
+impl HashWriter {
+    pub fn finish(self) -> String {
+        format!("{:x}", self.0.finalize())
+    }
+}
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
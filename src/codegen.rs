@@ -0,0 +1,670 @@
+use std::collections::{BTreeSet, HashSet};
+
+use serde_json::Value;
+
+use crate::{
+    shape::{Fields, Shape},
+    upstream::identity,
+};
+
+/// Names generated types never get, so they cannot hide what the generated code refers to
+const RESERVED_NAMES: &[&str] = &[
+    "Array",
+    "BTreeMap",
+    "Boolean",
+    "Box",
+    "Deserialize",
+    "Number",
+    "Object",
+    "Option",
+    "Record",
+    "Result",
+    "Self",
+    "Serialize",
+    "String",
+    "Value",
+    "Vec",
+];
+
+const RUST_KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do", "dyn",
+    "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl", "in", "let",
+    "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return",
+    "static", "struct", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use",
+    "virtual", "where", "while", "yield",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Language {
+    Rust,
+    TypeScript,
+}
+
+impl Language {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "rust" | "rs" => Some(Self::Rust),
+            "typescript" | "ts" => Some(Self::TypeScript),
+            _ => None,
+        }
+    }
+}
+
+/// A type to generate, named after the document or schema it describes
+pub struct Model {
+    pub name: String,
+    pub shape: Shape,
+}
+
+/// Identity values of the items of an array, generated as a constant list
+pub struct Table {
+    pub name: String,
+    pub values: Vec<String>,
+}
+
+/// The tables of every array in the document, reached only through object members, whose
+/// items all have a different string for one of the identity keys
+pub fn tables(name: &str, document: &Value, identity_keys: &[String]) -> Vec<Table> {
+    let mut tables = Vec::new();
+    collect_tables(
+        document,
+        &mut vec![name.to_string()],
+        identity_keys,
+        &mut tables,
+    );
+    tables
+}
+
+fn collect_tables(
+    value: &Value,
+    path: &mut Vec<String>,
+    identity_keys: &[String],
+    tables: &mut Vec<Table>,
+) {
+    match value {
+        Value::Object(members) => {
+            for (key, value) in members {
+                path.push(key.clone());
+                collect_tables(value, path, identity_keys, tables);
+                path.pop();
+            }
+        }
+        Value::Array(items) => {
+            let Some(key) = identity(items, items, identity_keys) else {
+                return;
+            };
+            let values: Option<Vec<String>> = items
+                .iter()
+                .map(|item| item.get(key).and_then(Value::as_str).map(str::to_string))
+                .collect();
+            if let Some(values) = values {
+                let mut name = screaming_case(&format!("{} {}", path.join(" "), key));
+                if !name.ends_with('S') {
+                    name.push('S');
+                }
+                tables.push(Table { name, values });
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Source code with a type for every model and a constant list for every table and enum
+pub fn generate(language: Language, source: &str, models: &[Model], tables: &[Table]) -> String {
+    let mut generator = Generator {
+        language,
+        definitions: Vec::new(),
+        names: RESERVED_NAMES.iter().map(|name| name.to_string()).collect(),
+    };
+    for model in models {
+        let name = pascal_case(&model.name);
+        let name = generator.unique_name(&name);
+        let is_object = model.shape.kinds() == 1 && !model.shape.null && !model.shape.any;
+        match &model.shape.object {
+            Some(fields) if is_object && !fields.fields.is_empty() => {
+                generator.define_struct(name, fields);
+            }
+            _ => {
+                let definition = generator.type_of(&model.shape, &name);
+                generator.definitions.push(match language {
+                    Language::Rust => format!("pub type {} = {};\n", name, definition),
+                    Language::TypeScript => format!("export type {} = {};\n", name, definition),
+                });
+            }
+        }
+    }
+    for table in tables {
+        generator.define_table(table);
+    }
+
+    let mut code = format!(
+        "// Generated by json-revisor codegen from {}, do not edit\n",
+        source
+    );
+    if language == Language::Rust {
+        code.push_str("\nuse serde::{Deserialize, Serialize};\n");
+    }
+    for definition in generator.definitions {
+        code.push('\n');
+        code.push_str(&definition);
+    }
+    code
+}
+
+struct Generator {
+    language: Language,
+    definitions: Vec<String>,
+    /// Every type and constant name already given
+    names: HashSet<String>,
+}
+
+impl Generator {
+    fn unique_name(&mut self, name: &str) -> String {
+        let mut unique = name.to_string();
+        let mut count = 1;
+        while !self.names.insert(unique.clone()) {
+            count += 1;
+            unique = format!("{}{}", name, count);
+        }
+        unique
+    }
+
+    /// The type of the values, defining the types it is made of under names starting with
+    /// `name`
+    fn type_of(&mut self, shape: &Shape, name: &str) -> String {
+        match self.language {
+            Language::Rust => self.rust_type(shape, name),
+            Language::TypeScript => self.typescript_type(shape, name),
+        }
+    }
+
+    fn rust_type(&mut self, shape: &Shape, name: &str) -> String {
+        if shape.any || shape.kinds() != 1 {
+            return "serde_json::Value".to_string();
+        }
+        let base = if shape.boolean {
+            "bool".to_string()
+        } else if shape.number {
+            "f64".to_string()
+        } else if shape.integer {
+            "i64".to_string()
+        } else if let Some(strings) = &shape.string {
+            match strings.enumeration() {
+                Some(values) => self.define_enum(name, values),
+                None => "String".to_string(),
+            }
+        } else if let Some(items) = &shape.array {
+            format!("Vec<{}>", self.rust_type(items, &format!("{}Item", name)))
+        } else {
+            self.object_type(shape.object.as_ref().unwrap(), name)
+        };
+        if shape.null {
+            format!("Option<{}>", base)
+        } else {
+            base
+        }
+    }
+
+    fn typescript_type(&mut self, shape: &Shape, name: &str) -> String {
+        if shape.any {
+            return "unknown".to_string();
+        }
+        let mut types = Vec::new();
+        if shape.boolean {
+            types.push("boolean".to_string());
+        }
+        if shape.integer || shape.number {
+            types.push("number".to_string());
+        }
+        if let Some(strings) = &shape.string {
+            types.push(match strings.enumeration() {
+                Some(values) => self.define_enum(name, values),
+                None => "string".to_string(),
+            });
+        }
+        if let Some(items) = &shape.array {
+            let item = self.typescript_type(items, &format!("{}Item", name));
+            types.push(if item.contains(' ') {
+                format!("({})[]", item)
+            } else {
+                format!("{}[]", item)
+            });
+        }
+        if let Some(fields) = &shape.object {
+            types.push(self.object_type(fields, name));
+        }
+        if shape.null {
+            types.push("null".to_string());
+        }
+        if types.is_empty() {
+            return "unknown".to_string();
+        }
+        types.join(" | ")
+    }
+
+    /// A struct for objects with fields, a map for objects that were always empty
+    fn object_type(&mut self, fields: &Fields, name: &str) -> String {
+        if !fields.fields.is_empty() {
+            let name = self.unique_name(name);
+            return self.define_struct(name, fields);
+        }
+        match self.language {
+            Language::Rust => "std::collections::BTreeMap<String, serde_json::Value>".to_string(),
+            Language::TypeScript => "Record<string, unknown>".to_string(),
+        }
+    }
+
+    fn define_struct(&mut self, name: String, fields: &Fields) -> String {
+        let mut lines = Vec::new();
+        let mut field_names = HashSet::new();
+        for (key, field) in &fields.fields {
+            let type_name = format!("{}{}", name, pascal_case(key));
+            let field_type = self.type_of(&field.shape, &type_name);
+            let required = fields.is_required(field);
+            match self.language {
+                Language::Rust => {
+                    let mut field_name = snake_case(key);
+                    let mut count = 1;
+                    while !field_names.insert(field_name.clone()) {
+                        count += 1;
+                        field_name = format!("{}_{}", snake_case(key), count);
+                    }
+                    if field_name.trim_start_matches("r#") != key {
+                        lines.push(format!("    #[serde(rename = {:?})]", key));
+                    }
+                    let field_type = match field_type {
+                        field_type if required => field_type,
+                        field_type if field_type == "serde_json::Value" => {
+                            lines.push(
+                                "    #[serde(default, skip_serializing_if = \"serde_json::Value::is_null\")]"
+                                    .to_string(),
+                            );
+                            field_type
+                        }
+                        field_type => {
+                            lines.push(
+                                "    #[serde(default, skip_serializing_if = \"Option::is_none\")]"
+                                    .to_string(),
+                            );
+                            if field_type.starts_with("Option<") {
+                                field_type
+                            } else {
+                                format!("Option<{}>", field_type)
+                            }
+                        }
+                    };
+                    lines.push(format!("    pub {}: {},", field_name, field_type));
+                }
+                Language::TypeScript => lines.push(format!(
+                    "  {}{}: {};",
+                    property_name(key),
+                    if required { "" } else { "?" },
+                    field_type
+                )),
+            }
+        }
+
+        self.definitions.push(match self.language {
+            Language::Rust => format!(
+                "#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]\npub struct {} {{\n{}\n}}\n",
+                name,
+                lines.join("\n")
+            ),
+            Language::TypeScript => {
+                format!("export interface {} {{\n{}\n}}\n", name, lines.join("\n"))
+            }
+        });
+        name
+    }
+
+    fn define_enum(&mut self, name: &str, values: &BTreeSet<String>) -> String {
+        let name = self.unique_name(name);
+        match self.language {
+            Language::Rust => {
+                let mut variants = HashSet::new();
+                let variants: Vec<(String, &String)> = values
+                    .iter()
+                    .map(|value| {
+                        let variant = match pascal_case(value) {
+                            variant if variant == "Self" => "Self_".to_string(),
+                            variant => variant,
+                        };
+                        let mut unique = variant.clone();
+                        let mut count = 1;
+                        while !variants.insert(unique.clone()) {
+                            count += 1;
+                            unique = format!("{}{}", variant, count);
+                        }
+                        (unique, value)
+                    })
+                    .collect();
+                let declarations: Vec<String> = variants
+                    .iter()
+                    .map(|(variant, value)| {
+                        format!("    #[serde(rename = {:?})]\n    {},", value, variant)
+                    })
+                    .collect();
+                let all: Vec<String> = variants
+                    .iter()
+                    .map(|(variant, _)| format!("        {}::{},", name, variant))
+                    .collect();
+                let arms: Vec<String> = variants
+                    .iter()
+                    .map(|(variant, value)| {
+                        format!("            {}::{} => {:?},", name, variant, value)
+                    })
+                    .collect();
+                self.definitions.push(format!(
+                    "#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]\n\
+                     pub enum {name} {{\n{}\n}}\n\n\
+                     impl {name} {{\n    pub const ALL: [{name}; {}] = [\n{}\n    ];\n\n    \
+                     pub fn as_str(self) -> &'static str {{\n        match self {{\n{}\n        }}\n    }}\n}}\n",
+                    declarations.join("\n"),
+                    variants.len(),
+                    all.join("\n"),
+                    arms.join("\n"),
+                ));
+            }
+            Language::TypeScript => {
+                let constant = self.unique_name(&format!("{}_VALUES", screaming_case(&name)));
+                let values: Vec<String> = values
+                    .iter()
+                    .map(|value| format!("  {},", Value::String(value.clone())))
+                    .collect();
+                self.definitions.push(format!(
+                    "export const {} = [\n{}\n] as const;\nexport type {} = (typeof {})[number];\n",
+                    constant,
+                    values.join("\n"),
+                    name,
+                    constant
+                ));
+            }
+        }
+        name
+    }
+
+    fn define_table(&mut self, table: &Table) {
+        let name = self.unique_name(&table.name);
+        self.definitions.push(match self.language {
+            Language::Rust => {
+                let values: Vec<String> = table
+                    .values
+                    .iter()
+                    .map(|value| format!("    {:?},", value))
+                    .collect();
+                format!(
+                    "pub const {}: [&str; {}] = [\n{}\n];\n",
+                    name,
+                    values.len(),
+                    values.join("\n")
+                )
+            }
+            Language::TypeScript => {
+                let values: Vec<String> = table
+                    .values
+                    .iter()
+                    .map(|value| format!("  {},", Value::String(value.clone())))
+                    .collect();
+                format!(
+                    "export const {} = [\n{}\n] as const;\n",
+                    name,
+                    values.join("\n")
+                )
+            }
+        });
+    }
+}
+
+/// The words of a name, split at anything but letters and digits and where a lowercase
+/// letter is followed by an uppercase one
+fn words(text: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut previous_lowercase = false;
+    for c in text.chars() {
+        if !c.is_alphanumeric() {
+            words.extend((!word.is_empty()).then(|| std::mem::take(&mut word)));
+            previous_lowercase = false;
+            continue;
+        }
+        if c.is_uppercase() && previous_lowercase {
+            words.push(std::mem::take(&mut word));
+        }
+        previous_lowercase = c.is_lowercase() || c.is_ascii_digit();
+        word.push(c);
+    }
+    words.extend((!word.is_empty()).then_some(word));
+    words
+}
+
+fn pascal_case(text: &str) -> String {
+    let name: String = words(text)
+        .iter()
+        .map(|word| {
+            let mut chars = word.chars();
+            let first = chars.next().unwrap();
+            first
+                .to_uppercase()
+                .chain(chars.flat_map(char::to_lowercase))
+                .collect::<String>()
+        })
+        .collect();
+    match name.chars().next() {
+        None => "Value".to_string(),
+        Some(first) if first.is_ascii_digit() => format!("V{}", name),
+        Some(_) => name,
+    }
+}
+
+fn screaming_case(text: &str) -> String {
+    let name = words(text).join("_").to_uppercase();
+    match name.chars().next() {
+        None => "VALUES".to_string(),
+        Some(first) if first.is_ascii_digit() => format!("_{}", name),
+        Some(_) => name,
+    }
+}
+
+/// A Rust field name for the key
+fn snake_case(text: &str) -> String {
+    let name = words(text).join("_").to_lowercase();
+    match name.as_str() {
+        "" => "value".to_string(),
+        "self" | "super" | "crate" => format!("{}_", name),
+        name if RUST_KEYWORDS.contains(&name) => format!("r#{}", name),
+        name if name.starts_with(|c: char| c.is_ascii_digit()) => format!("_{}", name),
+        name => name.to_string(),
+    }
+}
+
+/// A TypeScript property name for the key, quoted unless it is an identifier
+fn property_name(key: &str) -> String {
+    let identifier = key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+    if identifier {
+        key.to_string()
+    } else {
+        Value::String(key.to_string()).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn weapons() -> (Vec<Model>, Vec<Table>) {
+        let shape = Shape::of(&[
+            json!({ "id": "sword", "kind": "weapon", "level": 1, "type": "a", "stats": { "hp": 1 } }),
+            json!({ "id": "bow", "kind": "weapon", "level": 2.5, "note": null, "stats": { "hp": 2 } }),
+        ]);
+        let models = vec![Model {
+            name: "items/weapon".to_string(),
+            shape,
+        }];
+        let document = json!({ "list": [{ "id": "a" }, { "id": "b" }], "other": [1, 2] });
+        (models, tables("items", &document, &["id".to_string()]))
+    }
+
+    #[test]
+    fn rust_types() {
+        let (models, tables) = weapons();
+        assert_eq!(
+            generate(Language::Rust, "original", &models, &tables),
+            r#"// Generated by json-revisor codegen from original, do not edit
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ItemsWeaponKind {
+    #[serde(rename = "weapon")]
+    Weapon,
+}
+
+impl ItemsWeaponKind {
+    pub const ALL: [ItemsWeaponKind; 1] = [
+        ItemsWeaponKind::Weapon,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ItemsWeaponKind::Weapon => "weapon",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ItemsWeaponStats {
+    pub hp: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ItemsWeapon {
+    pub id: String,
+    pub kind: ItemsWeaponKind,
+    pub level: f64,
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    pub note: serde_json::Value,
+    pub stats: ItemsWeaponStats,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r#type: Option<String>,
+}
+
+pub const ITEMS_LIST_IDS: [&str; 2] = [
+    "a",
+    "b",
+];
+"#
+        );
+    }
+
+    #[test]
+    fn typescript_types() {
+        let (models, tables) = weapons();
+        assert_eq!(
+            generate(Language::TypeScript, "original", &models, &tables),
+            r#"// Generated by json-revisor codegen from original, do not edit
+
+export const ITEMS_WEAPON_KIND_VALUES = [
+  "weapon",
+] as const;
+export type ItemsWeaponKind = (typeof ITEMS_WEAPON_KIND_VALUES)[number];
+
+export interface ItemsWeaponStats {
+  hp: number;
+}
+
+export interface ItemsWeapon {
+  id: string;
+  kind: ItemsWeaponKind;
+  level: number;
+  note?: null;
+  stats: ItemsWeaponStats;
+  type?: string;
+}
+
+export const ITEMS_LIST_IDS = [
+  "a",
+  "b",
+] as const;
+"#
+        );
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(
+            words("itemCount_2-xHTTP"),
+            ["item", "Count", "2", "x", "HTTP"]
+        );
+        assert_eq!(pascal_case("item_count"), "ItemCount");
+        assert_eq!(pascal_case("2d-items"), "V2dItems");
+        assert_eq!(pascal_case("--"), "Value");
+        assert_eq!(snake_case("itemCount"), "item_count");
+        assert_eq!(snake_case("type"), "r#type");
+        assert_eq!(snake_case("self"), "self_");
+        assert_eq!(snake_case("3d"), "_3d");
+        assert_eq!(snake_case(""), "value");
+        assert_eq!(screaming_case("items list"), "ITEMS_LIST");
+        assert_eq!(screaming_case("1st"), "_1ST");
+        assert_eq!(property_name("name"), "name");
+        assert_eq!(property_name("a-b"), "\"a-b\"");
+    }
+
+    #[test]
+    fn fields_and_variants_that_collide() {
+        let mut shape = Shape::default();
+        for value in ["a-b", "a_b", "self", ""] {
+            shape.observe(&json!({ "a-b": value, "a_b": 1, "self": 1 }));
+            shape.observe(&json!({ "a-b": value, "a_b": 1, "self": 1 }));
+        }
+        let models = [Model {
+            name: "Value".to_string(),
+            shape,
+        }];
+        let code = generate(Language::Rust, "original", &models, &[]);
+        for expected in [
+            "pub struct Value2 {",
+            "    #[serde(rename = \"a-b\")]\n    pub a_b: Value2AB,",
+            "    #[serde(rename = \"a_b\")]\n    pub a_b_2: i64,",
+            "    #[serde(rename = \"self\")]\n    pub self_: i64,",
+            "    #[serde(rename = \"\")]\n    Value,",
+            "    #[serde(rename = \"a-b\")]\n    AB,",
+            "    #[serde(rename = \"a_b\")]\n    AB2,",
+            "    #[serde(rename = \"self\")]\n    Self_,",
+            "pub const ALL: [Value2AB; 4]",
+        ] {
+            assert!(code.contains(expected), "{} is not in\n{}", expected, code);
+        }
+    }
+
+    #[test]
+    fn documents_that_are_not_objects() {
+        let models = [
+            Model {
+                name: "list".to_string(),
+                shape: Shape::of(&[json!([1, 2])]),
+            },
+            Model {
+                name: "mixed".to_string(),
+                shape: Shape::of(&[json!(1), json!("a")]),
+            },
+        ];
+        let code = generate(Language::Rust, "original", &models, &[]);
+        assert!(code.contains("pub type List = Vec<i64>;\n"), "{}", code);
+        assert!(
+            code.contains("pub type Mixed = serde_json::Value;\n"),
+            "{}",
+            code
+        );
+        let code = generate(Language::TypeScript, "original", &models, &[]);
+        assert!(
+            code.contains("export type Mixed = number | string;\n"),
+            "{}",
+            code
+        );
+    }
+}
@@ -1,340 +1,2655 @@
 use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     ffi::OsString,
-    fs::{create_dir, File},
-    io::{self, Read, Write},
-    path::PathBuf,
+    fs::{self, create_dir, create_dir_all, File},
+    io::{self, BufWriter, Write},
+    path::{Component, Path, PathBuf},
+    process::{self, exit},
 };
 
 use colored::Colorize;
-use json_patch::Patch;
-use serde_json::{from_value, to_string_pretty, Value};
+use json_patch::{Patch, PatchErrorKind};
+use serde::Serialize;
+use serde_json::{from_value, json, Value};
 
 use crate::{
-    config::{ProjectConfig, ProjectPaths},
+    archive::{ArchiveFormat, Entry},
+    cache::{cache_key, hash, hash_file, Cache, CacheEntry, CacheTable, HashWriter, CACHE_DIR},
+    codegen::{self, Language, Model, Table},
+    config::{
+        migrate, validate_license, validate_version, BuildVariant, ChangeLayers, ProjectConfig,
+        ProjectPaths, UpdateSettings, CONFIG_SCHEMA, CONFIG_VERSION,
+    },
+    diff,
     error::{AppError, AppErrorIo, UnwrapAppPathlessError},
-    file_trio::{get_file_trios, FilePath, FindFileTriosError, TrioInitError},
+    file_trio::{get_file_trios, FilePath, FilePathTrio, FindFileTriosError, TrioInitError},
+    impact::{self, AffectedOperation, ImpactReport, LayerPatch},
+    lazy::{self, LazyValue, LAZY_THRESHOLD},
+    merge,
+    optimize::{self, OptimizeError},
+    patch_file, pool,
+    reference::{self, BuiltDocuments, Documents, INCLUDE_KEY},
+    report::{self, Event, FileStatus},
+    rules::{is_rules_file, rules_path, Rules, Stage},
+    schema::{SchemaMapping, Schemas, GLOB_OPTIONS},
+    shape::Shape,
+    transform::{self, Transform, TransformInput, TransformStep, Transforms},
+    upstream::{self, ChangeKind, FileChange, ReportFormat, UpstreamReport},
+    variables::Variables,
     CONFIG_FILE,
 };
 
-/*
-original - have all the files
-changes - have all the files matching changes, but not required
-changed - empty
-*/
-pub fn build(original_path: OsString, matches: OsString, result: OsString) {
-    let trios = match get_file_trios(PathBuf::from("."), original_path, matches, result) {
-        Ok(it) => it,
-        Err(err) => match err {
+fn file_trios(
+    original_path: OsString,
+    changes: &ChangeLayers,
+    result: OsString,
+) -> Result<Vec<FilePathTrio>, AppError<'static>> {
+    let lower: Vec<OsString> = changes.lower().iter().map(OsString::from).collect();
+    let top = OsString::from(changes.top());
+    get_file_trios(PathBuf::from("."), original_path, top, &lower, result).map_err(
+        |err| match err {
             FindFileTriosError::TrioInitError(err) => match err {
                 TrioInitError::InconsistentFileTypes(err) => AppError::InconsistentFileTypes(err),
                 TrioInitError::IoError(err) => AppError::IoErrorPath(err),
             },
             FindFileTriosError::IoErrorWithPath(err) => AppError::IoErrorPath(err),
             FindFileTriosError::IoError(err) => AppError::IoError(err),
+        },
+    )
+}
+
+/// What happened to a single file in build or update
+enum FileOutcome {
+    /// The output was written
+    Processed(CacheEntry),
+    /// The output is already up to date and was left untouched
+    UpToDate(CacheEntry),
+    Skipped,
+    Warning(&'static str),
+}
+
+/// Emits the events of processed files in order and collects their cache entries.
+///
+/// Returns the amount of files that failed
+fn report_outcomes(
+    command: &str,
+    profile: Option<&str>,
+    trios: &[FilePathTrio],
+    outcomes: Vec<Result<FileOutcome, AppError>>,
+    mut warning_count: usize,
+    cache_table: &mut CacheTable,
+) -> usize {
+    cache_table.clear();
+
+    let mut processed_count = 0;
+    let mut up_to_date_count = 0;
+    let mut error_count = 0;
+    for (trio, outcome) in trios.iter().zip(outcomes) {
+        match outcome {
+            Ok(FileOutcome::Processed(entry)) => {
+                report::emit(Event::FileProcessed {
+                    command,
+                    original: &trio.original,
+                    changes: &trio.changes,
+                    revise: &trio.changed,
+                });
+                cache_table.insert(cache_key(&trio.original), entry);
+                processed_count += 1;
+            }
+            Ok(FileOutcome::UpToDate(entry)) => {
+                cache_table.insert(cache_key(&trio.original), entry);
+                up_to_date_count += 1;
+            }
+            Ok(FileOutcome::Skipped) => (),
+            Ok(FileOutcome::Warning(message)) => {
+                report::emit(Event::Warning {
+                    path: &trio.original,
+                    message,
+                });
+                warning_count += 1;
+            }
+            Err(err) => {
+                err.report();
+                error_count += 1;
+            }
         }
-        .throw(),
+    }
+    report::emit(Event::Summary {
+        command,
+        profile,
+        processed: processed_count,
+        up_to_date: up_to_date_count,
+        warnings: warning_count,
+        errors: error_count,
+    });
+    error_count
+}
+
+fn load_cache(no_cache: bool) -> Cache {
+    if no_cache {
+        Cache::default()
+    } else {
+        Cache::load()
+    }
+}
+
+/// Saves the cache, then exits if any file failed
+fn finish(cache: Cache, error_count: usize) {
+    if let Err(err) = cache.save() {
+        err.throw();
+    }
+    if error_count > 0 {
+        exit(101);
+    }
+}
+
+/*
+original - have all the files
+changes - have all the files matching changes, but not required
+changed - empty
+*/
+pub fn build(
+    original_path: OsString,
+    variants: &[BuildVariant],
+    jobs: usize,
+    no_cache: bool,
+    schemas: &[SchemaMapping],
+    variables: &Variables,
+) {
+    let schemas = Schemas::load(schemas).unwrap_or_else(|err| err.throw());
+    let mut cache = load_cache(no_cache);
+    let mut error_count = 0;
+    for variant in variants {
+        let transforms = Transforms::load(&variant.transforms).unwrap_or_else(|err| err.throw());
+        let cache_table = match &variant.profile {
+            Some(profile) => cache.profiles.entry(profile.clone()).or_default(),
+            None => &mut cache.build,
+        };
+        error_count += build_variant(
+            original_path.clone(),
+            variant,
+            jobs,
+            cache_table,
+            &schemas,
+            &transforms,
+            variables,
+        )
+        .unwrap_or_else(|err| err.throw());
+    }
+    finish(cache, error_count);
+}
+
+/// Builds one variant into its revise folder, returns the amount of files that failed.
+///
+/// Errors that stop the whole build are returned, so `package` can clean up after them
+fn build_variant(
+    original_path: OsString,
+    variant: &BuildVariant,
+    jobs: usize,
+    cache_table: &mut CacheTable,
+    schemas: &Schemas,
+    transforms: &Transforms,
+    variables: &Variables,
+) -> Result<usize, AppError<'static>> {
+    let (dirs, files): (Vec<_>, Vec<_>) = file_trios(
+        original_path,
+        &variant.changes,
+        OsString::from(&variant.revise),
+    )?
+    .into_iter()
+    .partition(|trio| trio.file_type.is_dir());
+
+    // Directories come before their contents, so they are made up front
+    let mut warning_count = 0;
+    for trio in dirs {
+        if !trio.changes.exists() {
+            report::emit(Event::Warning {
+                path: &trio.original,
+                message: "does not have a matching changes folder, creating folder...",
+            });
+            warning_count += 1;
+            if let Err(err) = create_dir(&trio.changes) {
+                return Err(AppError::IoErrorPath(err.attach_path(trio.changes)));
+            }
+        }
+        if !trio.changed.exists() {
+            if let Err(err) = create_dir_all(&trio.changed) {
+                return Err(AppError::IoErrorPath(err.attach_path(trio.changed)));
+            }
+        }
+    }
+
+    // Files are built in levels, so the files a patch includes are already built
+    let order = BuildOrder::new(&files.iter().collect::<Vec<_>>());
+    let included = order.included();
+    let mut documents = BuiltDocuments::new();
+    let mut outcomes: Vec<Option<_>> = files.iter().map(|_| None).collect();
+    for level in &order.levels {
+        let context = BuildContext {
+            cache: cache_table,
+            schemas,
+            transforms,
+            substitutions: Substitutions {
+                variables,
+                documents: Documents::Built(&documents),
+            },
+        };
+        let built = pool::map(level, jobs, |&index| {
+            build_file(&files[index], &order.dependencies[index], &context)
+        });
+        for (&index, outcome) in level.iter().zip(built) {
+            let relative = files[index].relative();
+            if included.contains(&relative) {
+                documents.insert(relative, built_document(&files[index], &outcome));
+            }
+            outcomes[index] = Some(outcome);
+        }
+    }
+    let outcomes = outcomes
+        .into_iter()
+        .map(|outcome| outcome.expect("Every file is in a level"))
+        .collect();
+    Ok(report_outcomes(
+        "build",
+        variant.profile.as_deref(),
+        &files,
+        outcomes,
+        warning_count,
+        cache_table,
+    ))
+}
+
+/// What every file of a build shares
+struct BuildContext<'a> {
+    cache: &'a CacheTable,
+    schemas: &'a Schemas,
+    transforms: &'a Transforms,
+    substitutions: Substitutions<'a>,
+}
+
+/// The output of a file for the files that include it
+fn built_document(
+    trio: &FilePathTrio,
+    outcome: &Result<FileOutcome, AppError>,
+) -> Result<(Value, String), String> {
+    match outcome {
+        Ok(FileOutcome::Processed(entry) | FileOutcome::UpToDate(entry)) => {
+            let document = json_from_path(trio.changed.clone())
+                .map_err(|_| "its output cannot be read".to_string())?;
+            Ok((document, entry.revise.clone()))
+        }
+        Ok(FileOutcome::Skipped | FileOutcome::Warning(_)) => Err("it was not built".to_string()),
+        Err(_) => Err("it failed to build".to_string()),
+    }
+}
+
+fn build_file(
+    trio: &FilePathTrio,
+    dependencies: &[PathBuf],
+    context: &BuildContext,
+) -> Result<FileOutcome, AppError<'static>> {
+    let BuildContext {
+        cache,
+        schemas,
+        transforms,
+        substitutions,
+    } = context;
+    let original = &trio.original;
+    let matching = &trio.changes;
+    let result = &trio.changed;
+
+    if !matching.exists() {
+        let mut file = match File::create(matching) {
+            Ok(it) => it,
+            Err(err) => return Err(AppError::IoErrorPath(err.attach_path(matching.clone()))),
+        };
+        if let Err(err) = file.write_all(patch_file::format(&Patch(Vec::new())).as_bytes()) {
+            return Err(AppError::IoErrorPath(err.attach_path(matching.clone())));
+        };
+        return Ok(FileOutcome::Warning(
+            "does not have a matching changes file, creating file...",
+        ));
+    }
+
+    let original_size = match original.metadata() {
+        Ok(it) => it.len(),
+        Err(err) => return Err(AppError::IoErrorPath(err.attach_path(original.clone()))),
     };
+    let original_hash = hash_path(original)?;
+    let changes_bytes = read_file(matching)?;
+    let changes_hash = hash(&changes_bytes);
+    let rules_file: FilePath = rules_path(matching).into();
+    let rules_bytes = if rules_file.exists() {
+        Some(read_file(&rules_file)?)
+    } else {
+        None
+    };
+    let rules_hash = rules_bytes.as_deref().map(hash).unwrap_or_default();
+    let revise_hash = existing_hash(result)?;
+    let relative = trio.relative();
+    let schema_hash = schemas.hash_for(&relative);
+    let transform_hash = transforms.hash_for(&relative);
+    let variables_hash = substitutions.variables.hash_for(&changes_bytes);
+    let references_hash = substitutions.documents.hash_for(dependencies);
+    let (layers, layers_hash) = load_layers(trio, substitutions)?;
+
+    if let Some(entry) = cache.get(&cache_key(original)) {
+        if entry.original == original_hash
+            && entry.changes == changes_hash
+            && entry.variables == variables_hash
+            && entry.references == references_hash
+            && entry.layers == layers_hash
+            && entry.rules == rules_hash
+            && entry.transforms == transform_hash
+            && entry.schema == schema_hash
+            && Some(&entry.revise) == revise_hash.as_ref()
+        {
+            return Ok(FileOutcome::UpToDate(entry.clone()));
+        }
+    }
+
+    let patch = substituted_patch(&changes_bytes, matching, substitutions)?;
+    let rules = match &rules_bytes {
+        Some(bytes) => Some(rules_from_bytes(bytes, &rules_file)?),
+        None => None,
+    };
+
+    // Big documents are only parsed where the patch touches them,
+    // layers, rules and transforms need the whole document
+    let (output_hash, written) = if original_size > LAZY_THRESHOLD
+        && layers.is_empty()
+        && rules.is_none()
+        && transform_hash.is_empty()
+    {
+        let mut document = LazyValue::from_path(original).map_err(|err| {
+            if err.is_io() {
+                AppError::IoErrorPath(io::Error::from(err).attach_path(original.clone()))
+            } else {
+                AppError::InvalidFileFormat {
+                    file_path: original.clone(),
+                    expected: "JSON file",
+                }
+            }
+        })?;
+        if let Err(err) = lazy::patch(&mut document, &patch) {
+            return Err(patch_error(
+                original,
+                matching,
+                err.operation,
+                err.path,
+                err.kind,
+            ));
+        }
+        if !schema_hash.is_empty() {
+            // Validation needs the whole document
+            validate(
+                &document.clone().into_value(),
+                &patch,
+                result,
+                &relative,
+                schemas,
+            )?;
+        }
+        write_output(&document, result, revise_hash.as_ref())?
+    } else {
+        let mut document = json_from_path(original.clone())?;
+        apply_layers(&mut document, &layers, original)?;
+        apply_changes(&mut document, &patch, rules.as_ref(), original, matching)?;
+        let document = transform::run_all(
+            &transforms.matching(&relative),
+            document,
+            &transform_input(trio, &relative),
+            original,
+        )?;
+        validate(&document, &patch, result, &relative, schemas)?;
+        write_output(&document, result, revise_hash.as_ref())?
+    };
+
+    let entry = CacheEntry {
+        original: original_hash,
+        changes: changes_hash,
+        revise: output_hash,
+        schema: schema_hash,
+        rules: rules_hash,
+        transforms: transform_hash,
+        layers: layers_hash,
+        variables: variables_hash,
+        references: references_hash,
+        guard: false,
+    };
+    if written {
+        Ok(FileOutcome::Processed(entry))
+    } else {
+        Ok(FileOutcome::UpToDate(entry))
+    }
+}
+
+/*
+original - have all the files
+changes - empty
+changed - have all files but changed
+*/
+pub fn update(
+    original_path: OsString,
+    variant: &BuildVariant,
+    jobs: usize,
+    no_cache: bool,
+    guard: bool,
+    variables: &Variables,
+) {
+    let transforms = Transforms::load(&variant.transforms).unwrap_or_else(|err| err.throw());
+    let (dirs, files): (Vec<_>, Vec<_>) = file_trios(
+        original_path,
+        &variant.changes,
+        OsString::from(&variant.revise),
+    )
+    .unwrap_or_else(|err| err.throw())
+    .into_iter()
+    .partition(|trio| trio.file_type.is_dir());
+
+    let mut warning_count = 0;
+    for trio in dirs {
+        if !trio.changes.exists() {
+            report::emit(Event::Warning {
+                path: &trio.original,
+                message: "does not have a matching changes folder, creating folder...",
+            });
+            warning_count += 1;
+            if let Err(err) = create_dir(&trio.changes) {
+                err.attach_path(trio.changes).throw();
+            }
+        }
+    }
+
+    // References keep what the revise files they point into have now
+    let substitutions = Substitutions {
+        variables,
+        documents: Documents::Folder(Path::new(&variant.revise)),
+    };
+    let mut cache = load_cache(no_cache);
+    let outcomes = pool::map(&files, jobs, |trio| {
+        update_file(trio, &cache.update, &transforms, &substitutions, guard)
+    });
+    let error_count = report_outcomes(
+        "update",
+        None,
+        &files,
+        outcomes,
+        warning_count,
+        &mut cache.update,
+    );
+    finish(cache, error_count);
+}
+
+fn update_file(
+    trio: &FilePathTrio,
+    cache: &CacheTable,
+    transforms: &Transforms,
+    substitutions: &Substitutions,
+    guard: bool,
+) -> Result<FileOutcome, AppError<'static>> {
+    let variables = substitutions.variables;
+    let changes = &trio.changes;
+
+    if !trio.changed.exists() {
+        return Ok(FileOutcome::Skipped);
+    }
+
+    let original_bytes = read_file(&trio.original)?;
+    let changed_bytes = read_file(&trio.changed)?;
+    let existing_bytes = if changes.exists() {
+        read_file(changes)?
+    } else {
+        Vec::new()
+    };
+    let changes_hash = changes.exists().then(|| hash(&existing_bytes));
+    let variables_hash = variables.hash_for(&existing_bytes);
+    let rules_file: FilePath = rules_path(changes).into();
+    let rules_bytes = if rules_file.exists() {
+        Some(read_file(&rules_file)?)
+    } else {
+        None
+    };
+    let rules_hash = rules_bytes.as_deref().map(hash).unwrap_or_default();
+    let relative = trio.relative();
+    let transform_hash = transforms.hash_for(&relative);
+    let (layers, layers_hash) = load_layers(trio, substitutions)?;
+
+    let original_hash = hash(&original_bytes);
+    let changed_hash = hash(&changed_bytes);
+    if let Some(entry) = cache.get(&cache_key(&trio.original)) {
+        if entry.original == original_hash
+            && entry.revise == changed_hash
+            && Some(&entry.changes) == changes_hash.as_ref()
+            && entry.layers == layers_hash
+            && entry.rules == rules_hash
+            && entry.transforms == transform_hash
+            && entry.variables == variables_hash
+            && entry.guard == guard
+        {
+            return Ok(FileOutcome::UpToDate(entry.clone()));
+        }
+    }
+
+    // Only what the lower layers do not already do goes into the top layer
+    let mut original_json = json_from_bytes(&original_bytes, &trio.original)?;
+    apply_layers(&mut original_json, &layers, &trio.original)?;
+    let changed_json = json_from_bytes(&changed_bytes, &trio.changed)?;
+
+    let rules = match &rules_bytes {
+        Some(bytes) => Some(rules_from_bytes(bytes, &rules_file)?),
+        None => None,
+    };
+    let (mut diff, base, reproduces) = diff_with_steps(
+        original_json,
+        &changed_json,
+        rules.as_ref(),
+        &transforms.matching(&relative),
+        trio,
+    )?;
+    variables.parameterize(&mut diff, &existing_bytes, &base, |value| {
+        substitutions.expand(value)
+    });
+    // Also guards the operations written back with their placeholders and references
+    if guard {
+        diff = diff::guard(&base, &diff);
+    }
+    let diff = patch_file::format(&diff);
+
+    let entry = CacheEntry {
+        original: original_hash,
+        changes: hash(diff.as_bytes()),
+        revise: changed_hash,
+        schema: String::new(),
+        rules: rules_hash,
+        transforms: transform_hash,
+        layers: layers_hash,
+        variables: variables.hash_for(diff.as_bytes()),
+        references: String::new(),
+        guard,
+    };
+    if Some(&entry.changes) == changes_hash.as_ref() && reproduces {
+        return Ok(FileOutcome::UpToDate(entry));
+    }
+
+    write_file(changes, diff.as_bytes())?;
+    if !reproduces {
+        return Ok(FileOutcome::Warning(
+            "has after rules or transforms that change the revise file again, building it will not reproduce it",
+        ));
+    }
+    Ok(FileOutcome::Processed(entry))
+}
+
+/// Patch for a file with rules or transforms, which is applied after the before rules
+/// and only holds what the after rules and transforms do not already do.
+///
+/// Returns the patch, the document it applies to and whether building reproduces `changed`
+fn diff_with_steps(
+    original: Value,
+    changed: &Value,
+    rules: Option<&Rules>,
+    transforms: &[&Transform],
+    trio: &FilePathTrio,
+) -> Result<(Patch, Value, bool), AppError<'static>> {
+    let mut base = original;
+    if let Some(rules) = rules {
+        apply_rules(
+            rules,
+            Stage::Before,
+            &mut base,
+            &trio.original,
+            &trio.changes,
+        )?;
+    }
+    let has_after = rules.is_some_and(|rules| rules.has(Stage::After));
+    if !has_after && transforms.is_empty() {
+        return Ok((diff::diff(&base, changed), base, true));
+    }
+
+    let relative = trio.relative();
+    let input = transform_input(trio, &relative);
+    let finish = |mut document: Value| -> Result<Value, AppError<'static>> {
+        if let Some(rules) = rules {
+            apply_rules(
+                rules,
+                Stage::After,
+                &mut document,
+                &trio.original,
+                &trio.changes,
+            )?;
+        }
+        transform::run_all(transforms, document, &input, &trio.original)
+    };
+    let reproduces = |patch: &Patch| {
+        let mut document = base.clone();
+        json_patch::patch(&mut document, patch).is_ok()
+            && finish(document).is_ok_and(|document| document == *changed)
+    };
+
+    let without_steps = diff::diff(&finish(base.clone())?, changed);
+    if reproduces(&without_steps) {
+        return Ok((without_steps, base, true));
+    }
+    let patch = diff::diff(&base, changed);
+    let reproduced = reproduces(&patch);
+    Ok((patch, base, reproduced))
+}
+
+/// Shows which revise files have changes that have not been saved with `update`
+pub fn status(
+    original_path: OsString,
+    changes: &ChangeLayers,
+    result: OsString,
+    transforms: &[TransformStep],
+    variables: &Variables,
+) {
+    let transforms = Transforms::load(transforms).unwrap_or_else(|err| err.throw());
+    let trios: Vec<FilePathTrio> = file_trios(original_path, changes, result)
+        .unwrap_or_else(|err| err.throw())
+        .into_iter()
+        .filter(|trio| !trio.file_type.is_dir())
+        .collect();
+    let patched_trios: Vec<&FilePathTrio> =
+        trios.iter().filter(|trio| trio.changes.exists()).collect();
+    let mut patched = patched_files(&patched_trios, &transforms, variables).into_iter();
+
+    let mut file_count = 0;
+    let mut error_count = 0;
+    for trio in &trios {
+        let status = if !trio.changes.exists() {
+            FileStatus::NoChanges
+        } else if !trio.changed.exists() {
+            patched.next();
+            FileStatus::NotBuilt
+        } else {
+            let compared = patched
+                .next()
+                .expect("Every file with changes was patched")
+                .and_then(|(patched, _)| {
+                    json_from_path(trio.changed.clone()).map(|revised| patched == revised)
+                });
+            match compared {
+                Ok(true) => FileStatus::Clean,
+                Ok(false) => FileStatus::Modified,
+                Err(err) => {
+                    err.report();
+                    error_count += 1;
+                    continue;
+                }
+            }
+        };
+
+        report::emit(Event::FileStatus {
+            original: &trio.original,
+            status,
+        });
+        file_count += 1;
+    }
+    report::emit(Event::Summary {
+        profile: None,
+        command: "status",
+        processed: file_count,
+        up_to_date: 0,
+        warnings: 0,
+        errors: error_count,
+    });
+    if error_count > 0 {
+        exit(1);
+    }
+}
+
+/// Applies every patch without writing anything, reporting all patches that fail
+/// and all patched documents that do not match their schema
+pub fn check(
+    original_path: OsString,
+    changes: &ChangeLayers,
+    result: OsString,
+    schemas: &[SchemaMapping],
+    transforms: &[TransformStep],
+    variables: &Variables,
+) {
+    let schemas = Schemas::load(schemas).unwrap_or_else(|err| err.throw());
+    let transforms = Transforms::load(transforms).unwrap_or_else(|err| err.throw());
+    let all_trios = file_trios(original_path, changes, result).unwrap_or_else(|err| err.throw());
+    let trios: Vec<&FilePathTrio> = all_trios
+        .iter()
+        .filter(|trio| !trio.file_type.is_dir() && trio.changes.exists())
+        .collect();
+    let patched = patched_files(&trios, &transforms, variables);
+
+    let mut patch_count = 0;
+    let mut error_count = 0;
+    for (trio, result) in trios.into_iter().zip(patched) {
+        patch_count += 1;
+        let checked = result.and_then(|(document, patch)| {
+            validate(&document, &patch, &trio.changed, &trio.relative(), &schemas)
+        });
+        match checked {
+            Ok(()) => report::emit(Event::FileProcessed {
+                command: "check",
+                original: &trio.original,
+                changes: &trio.changes,
+                revise: &trio.changed,
+            }),
+            Err(err) => {
+                err.report();
+                error_count += 1;
+            }
+        }
+    }
+    report::emit(Event::Summary {
+        profile: None,
+        command: "check",
+        processed: patch_count,
+        up_to_date: 0,
+        warnings: 0,
+        errors: error_count,
+    });
+    if error_count > 0 {
+        exit(1);
+    }
+}
+
+/// Rewrites every patch into the smallest patch that gives the same result
+pub fn optimize(
+    original_path: OsString,
+    changes: &ChangeLayers,
+    result: OsString,
+    variables: &Variables,
+) {
+    let revise = PathBuf::from(&result);
+    let substitutions = Substitutions {
+        variables,
+        documents: Documents::Folder(&revise),
+    };
+    let trios = file_trios(original_path, changes, result).unwrap_or_else(|err| err.throw());
+
+    let mut optimized_count = 0;
+    let mut up_to_date_count = 0;
+    let mut warning_count = 0;
+    let mut error_count = 0;
+    for trio in trios {
+        if trio.file_type.is_dir() || !trio.changes.exists() {
+            continue;
+        }
+
+        match optimize_file(&trio, &substitutions) {
+            Ok(true) => {
+                report::emit(Event::FileProcessed {
+                    command: "optimize",
+                    original: &trio.original,
+                    changes: &trio.changes,
+                    revise: &trio.changed,
+                });
+                optimized_count += 1;
+            }
+            Ok(false) => up_to_date_count += 1,
+            Err(None) => {
+                report::emit(Event::Warning {
+                    path: &trio.changes,
+                    message: "cannot be optimized without changing its result, leaving it as is",
+                });
+                warning_count += 1;
+            }
+            Err(Some(err)) => {
+                err.report();
+                error_count += 1;
+            }
+        }
+    }
+    report::emit(Event::Summary {
+        profile: None,
+        command: "optimize",
+        processed: optimized_count,
+        up_to_date: up_to_date_count,
+        warnings: warning_count,
+        errors: error_count,
+    });
+    if error_count > 0 {
+        exit(101);
+    }
+}
+
+/// Whether the patch got smaller, `Err(None)` if the optimized patch could not be proven equal
+fn optimize_file(
+    trio: &FilePathTrio,
+    substitutions: &Substitutions,
+) -> Result<bool, Option<AppError<'static>>> {
+    let mut original = json_from_path(trio.original.clone())?;
+    let patch = patch_from_value(json_from_path(trio.changes.clone())?, &trio.changes)?;
+    // The patch applies to what the lower layers and the before rules made of the original,
+    // its own placeholders and references are kept as they are
+    let (layers, _) = load_layers(trio, substitutions)?;
+    apply_layers(&mut original, &layers, &trio.original)?;
+    if let Some(rules) = load_rules(&trio.changes)? {
+        apply_rules(
+            &rules,
+            Stage::Before,
+            &mut original,
+            &trio.original,
+            &trio.changes,
+        )?;
+    }
+
+    let optimized = match optimize::optimize(&original, &patch) {
+        Ok(it) => it,
+        Err(OptimizeError::PatchFailed(err)) => {
+            return Err(Some(patch_error(
+                &trio.original,
+                &trio.changes,
+                err.operation,
+                err.path,
+                err.kind,
+            )))
+        }
+        Err(OptimizeError::NotEquivalent) => return Err(None),
+    };
+    if optimized == patch {
+        return Ok(false);
+    }
+    write_file(&trio.changes, patch_file::format(&optimized).as_bytes())?;
+    Ok(true)
+}
+
+/// Rewrites patch files in the canonical layout, with `check` only reports the ones that are not.
+///
+/// Formats every patch in the change layers when no files are given
+pub fn fmt(changes: &ChangeLayers, files: Vec<String>, check: bool) {
+    let files: Vec<PathBuf> = if !files.is_empty() {
+        files.into_iter().map(PathBuf::from).collect()
+    } else {
+        let mut files = Vec::new();
+        for layer in changes.iter().map(Path::new).filter(|layer| layer.is_dir()) {
+            files.extend(
+                json_files(layer, true)
+                    .unwrap_or_else(|err| err.throw())
+                    .into_iter()
+                    .filter(|file| !is_rules_file(file))
+                    .map(|file| layer.join(file)),
+            );
+        }
+        files
+    };
+
+    let mut formatted_count = 0;
+    let mut up_to_date_count = 0;
+    let mut error_count = 0;
+    for path in files {
+        let path: FilePath = path.into();
+        match format_file(&path, check) {
+            Ok(true) => up_to_date_count += 1,
+            Ok(false) if check => {
+                AppError::Unformatted { file_path: path }.report();
+                error_count += 1;
+            }
+            Ok(false) => {
+                report::emit(Event::FileFormatted { path: &path });
+                formatted_count += 1;
+            }
+            Err(err) => {
+                err.report();
+                error_count += 1;
+            }
+        }
+    }
+    report::emit(Event::Summary {
+        profile: None,
+        command: "fmt",
+        processed: formatted_count,
+        up_to_date: up_to_date_count,
+        warnings: 0,
+        errors: error_count,
+    });
+    if error_count > 0 {
+        exit(1);
+    }
+}
+
+/// Whether the patch file already is in the canonical layout, rewriting it if not
+/// unless only checking
+fn format_file(path: &FilePath, check: bool) -> Result<bool, AppError<'static>> {
+    let bytes = read_file(path)?;
+    let patch = patch_from_value(json_from_bytes(&bytes, path)?, path)?;
+    let formatted = patch_file::format(&patch);
+    if bytes == formatted.as_bytes() {
+        return Ok(true);
+    }
+    if !check {
+        write_file(path, formatted.as_bytes())?;
+    }
+    Ok(false)
+}
+
+/// Reads the original and builds it the way `build` does, without writing it
+fn patched_json(
+    trio: &FilePathTrio,
+    transforms: &Transforms,
+    substitutions: &Substitutions,
+) -> Result<(Value, Patch), AppError<'static>> {
+    let original = &trio.original;
+    let changes = &trio.changes;
+    let mut original_json = json_from_path(original.clone())?;
+    let patch = substituted_patch(&read_file(changes)?, changes, substitutions)?;
+    let rules = load_rules(changes)?;
+    let (layers, _) = load_layers(trio, substitutions)?;
+
+    apply_layers(&mut original_json, &layers, original)?;
+    apply_changes(
+        &mut original_json,
+        &patch,
+        rules.as_ref(),
+        original,
+        changes,
+    )?;
+    let relative = trio.relative();
+    let document = transform::run_all(
+        &transforms.matching(&relative),
+        original_json,
+        &transform_input(trio, &relative),
+        original,
+    )?;
+    Ok((document, patch))
+}
+
+/// What the placeholders and references in patch values are filled in with
+struct Substitutions<'a> {
+    variables: &'a Variables,
+    documents: Documents<'a>,
+}
+
+impl Substitutions<'_> {
+    /// Fills in a single value, false if it has a placeholder or reference that cannot be
+    fn expand(&self, value: &mut Value) -> bool {
+        self.variables.substitute_value(value).is_ok()
+            && reference::resolve_value(value, &self.documents).is_ok()
+    }
+}
+
+/// Parses a patch file and fills in the placeholders and references of its values
+fn substituted_patch(
+    bytes: &[u8],
+    path: &FilePath,
+    substitutions: &Substitutions,
+) -> Result<Patch, AppError<'static>> {
+    let mut patch = patch_from_value(json_from_bytes(bytes, path)?, path)?;
+    substitutions
+        .variables
+        .substitute(&mut patch)
+        .map_err(|name| AppError::UndefinedVariable {
+            file_path: path.clone(),
+            name,
+        })?;
+    reference::resolve(&mut patch, &substitutions.documents).map_err(|err| {
+        AppError::InvalidReference {
+            file_path: path.clone(),
+            reference: err.reference,
+            message: err.message,
+        }
+    })?;
+    Ok(patch)
+}
+
+/// Files the patches of every layer of the file include values of.
+///
+/// Patches that cannot be read are left to the command to report
+fn file_references(trio: &FilePathTrio) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for changes in trio.lower.iter().chain([&trio.changes]) {
+        let Ok(bytes) = fs::read(changes) else {
+            continue;
+        };
+        // Most patches include nothing, those do not need to be parsed here
+        if !String::from_utf8_lossy(&bytes).contains(INCLUDE_KEY) {
+            continue;
+        }
+        if let Ok(Ok(referenced)) =
+            serde_json::from_slice::<Patch>(&bytes).map(|patch| reference::references(&patch))
+        {
+            for file in referenced {
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+        }
+    }
+    files
+}
+
+/// The order files have to be built in so the files they include come first
+struct BuildOrder {
+    /// Indices of the files, each level only includes files of the levels before it
+    levels: Vec<Vec<usize>>,
+    /// Files each file includes, relative to the revise folder
+    dependencies: Vec<Vec<PathBuf>>,
+}
+
+impl BuildOrder {
+    /// Exits when files include each other in a cycle
+    fn new(files: &[&FilePathTrio]) -> Self {
+        let indices: HashMap<PathBuf, usize> = files
+            .iter()
+            .enumerate()
+            .map(|(index, trio)| (trio.relative(), index))
+            .collect();
+        let dependencies: Vec<Vec<PathBuf>> =
+            files.iter().map(|trio| file_references(trio)).collect();
+        let edges: Vec<Vec<usize>> = dependencies
+            .iter()
+            .map(|files| {
+                files
+                    .iter()
+                    .filter_map(|file| indices.get(file).copied())
+                    .collect()
+            })
+            .collect();
+        let levels = reference::levels(&edges).unwrap_or_else(|cycle| {
+            AppError::ReferenceCycle {
+                files: cycle
+                    .iter()
+                    .map(|&index| files[index].changes.clone())
+                    .collect(),
+            }
+            .throw()
+        });
+        BuildOrder {
+            levels,
+            dependencies,
+        }
+    }
+
+    /// Files some other file includes
+    fn included(&self) -> HashSet<PathBuf> {
+        self.dependencies.iter().flatten().cloned().collect()
+    }
+}
+
+/// Builds the files in memory like `patched_json`, in the order their references need.
+///
+/// The results are in the order of `files`
+fn patched_files(
+    files: &[&FilePathTrio],
+    transforms: &Transforms,
+    variables: &Variables,
+) -> Vec<Result<(Value, Patch), AppError<'static>>> {
+    let order = BuildOrder::new(files);
+    let included = order.included();
+    let mut documents = BuiltDocuments::new();
+    let mut results: Vec<Option<_>> = files.iter().map(|_| None).collect();
+    for index in order.levels.into_iter().flatten() {
+        let substitutions = Substitutions {
+            variables,
+            documents: Documents::Built(&documents),
+        };
+        let result = patched_json(files[index], transforms, &substitutions);
+        let relative = files[index].relative();
+        if included.contains(&relative) {
+            let document = match &result {
+                Ok((document, _)) => Ok((document.clone(), String::new())),
+                Err(_) => Err("it failed to build".to_string()),
+            };
+            documents.insert(relative, document);
+        }
+        results[index] = Some(result);
+    }
+    results
+        .into_iter()
+        .map(|result| result.expect("Every file is in a level"))
+        .collect()
+}
+
+/// Patch of the file in a lower change layer
+struct Layer {
+    changes: FilePath,
+    patch: Patch,
+    rules: Option<Rules>,
+}
+
+/// Patches the lower layers have for the file, lowest first, and their combined hash.
+///
+/// A layer without a patch for the file is left out
+fn load_layers(
+    trio: &FilePathTrio,
+    substitutions: &Substitutions,
+) -> Result<(Vec<Layer>, String), AppError<'static>> {
+    let mut layers = Vec::new();
+    let mut hashed = String::new();
+    for changes in &trio.lower {
+        if !changes.exists() {
+            continue;
+        }
+        let bytes = read_file(changes)?;
+        let rules_file: FilePath = rules_path(changes).into();
+        let rules = if rules_file.exists() {
+            let rules_bytes = read_file(&rules_file)?;
+            hashed.push_str(&hash(&rules_bytes));
+            Some(rules_from_bytes(&rules_bytes, &rules_file)?)
+        } else {
+            None
+        };
+        hashed.push_str(&format!(
+            "{}:{}:{}\n",
+            changes.display(),
+            hash(&bytes),
+            substitutions.variables.hash_for(&bytes)
+        ));
+        layers.push(Layer {
+            patch: substituted_patch(&bytes, changes, substitutions)?,
+            changes: changes.clone(),
+            rules,
+        });
+    }
+    if layers.is_empty() {
+        return Ok((layers, String::new()));
+    }
+    Ok((layers, hash(hashed.as_bytes())))
+}
+
+fn apply_layers(
+    document: &mut Value,
+    layers: &[Layer],
+    original: &FilePath,
+) -> Result<(), AppError<'static>> {
+    for layer in layers {
+        apply_changes(
+            document,
+            &layer.patch,
+            layer.rules.as_ref(),
+            original,
+            &layer.changes,
+        )?;
+    }
+    Ok(())
+}
+
+/// Applies the before rules, the patch and then the after rules
+fn apply_changes(
+    document: &mut Value,
+    patch: &Patch,
+    rules: Option<&Rules>,
+    original: &FilePath,
+    changes: &FilePath,
+) -> Result<(), AppError<'static>> {
+    if let Some(rules) = rules {
+        apply_rules(rules, Stage::Before, document, original, changes)?;
+    }
+    if let Err(err) = json_patch::patch(document, patch) {
+        return Err(patch_error(
+            original,
+            changes,
+            err.operation,
+            err.path,
+            err.kind,
+        ));
+    }
+    if let Some(rules) = rules {
+        apply_rules(rules, Stage::After, document, original, changes)?;
+    }
+    Ok(())
+}
+
+fn apply_rules(
+    rules: &Rules,
+    stage: Stage,
+    document: &mut Value,
+    original: &FilePath,
+    changes: &FilePath,
+) -> Result<(), AppError<'static>> {
+    rules
+        .apply(stage, document)
+        .map_err(|err| AppError::RuleFailed {
+            target_file: original.clone(),
+            rules_file: rules_path(changes).into(),
+            stage: err.stage.name(),
+            rule: err.rule,
+            pointer: err.pointer,
+            reason: err.error.to_string(),
+        })
+}
+
+/// Rules file of the patch, if it has one
+fn load_rules(changes: &FilePath) -> Result<Option<Rules>, AppError<'static>> {
+    let path: FilePath = rules_path(changes).into();
+    if !path.exists() {
+        return Ok(None);
+    }
+    rules_from_bytes(&read_file(&path)?, &path).map(Some)
+}
+
+fn rules_from_bytes(bytes: &[u8], path: &FilePath) -> Result<Rules, AppError<'static>> {
+    Rules::from_slice(bytes).map_err(|message| AppError::InvalidRules {
+        file_path: path.clone(),
+        message,
+    })
+}
+
+/// A failed `test` means the original changed under a guarded operation
+fn patch_error(
+    original: &FilePath,
+    changes: &FilePath,
+    operation: usize,
+    pointer: String,
+    kind: PatchErrorKind,
+) -> AppError<'static> {
+    match kind {
+        PatchErrorKind::TestFailed => AppError::UpstreamChanged {
+            target_file: original.clone(),
+            patch_file: changes.clone(),
+            operation,
+            pointer,
+        },
+        _ => AppError::PatchError {
+            target_file: original.clone(),
+            patch_file: changes.clone(),
+        },
+    }
+}
+
+fn transform_input<'a>(trio: &'a FilePathTrio, relative: &'a Path) -> TransformInput<'a> {
+    TransformInput {
+        relative,
+        original: &trio.original,
+        revise: &trio.changed,
+    }
+}
+
+/// Validates a patched document against every schema its output path matches
+fn validate(
+    document: &Value,
+    patch: &Patch,
+    output: &FilePath,
+    relative: &Path,
+    schemas: &Schemas,
+) -> Result<(), AppError<'static>> {
+    for schema in schemas.matching(relative) {
+        let violations = schema.validate(document, patch);
+        if !violations.is_empty() {
+            return Err(AppError::SchemaViolations {
+                file_path: output.clone(),
+                schema_path: schema.path.clone(),
+                violations,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn patch_from_value(changes_json: Value, changes: &FilePath) -> Result<Patch, AppError<'static>> {
+    from_value::<Patch>(changes_json).map_err(|_| AppError::InvalidFileFormat {
+        file_path: changes.clone(),
+        expected: "JSON patch file",
+    })
+}
+
+fn json_from_path(path: FilePath) -> Result<Value, AppError<'static>> {
+    json_from_bytes(&read_file(&path)?, &path)
+}
+
+fn json_from_bytes(bytes: &[u8], path: &FilePath) -> Result<Value, AppError<'static>> {
+    serde_json::from_slice(bytes).map_err(|_| AppError::InvalidFileFormat {
+        file_path: path.clone(),
+        expected: "JSON file",
+    })
+}
+
+fn read_file(path: &FilePath) -> Result<Vec<u8>, AppError<'static>> {
+    fs::read(path).map_err(|err| AppError::IoErrorPath(err.attach_path(path.clone())))
+}
+
+fn write_file(path: &FilePath, bytes: &[u8]) -> Result<(), AppError<'static>> {
+    fs::write(path, bytes).map_err(|err| AppError::IoErrorPath(err.attach_path(path.clone())))
+}
+
+fn hash_path(path: &FilePath) -> Result<String, AppError<'static>> {
+    hash_file(path).map_err(|err| AppError::IoErrorPath(err.attach_path(path.clone())))
+}
+
+/// Hash of the file if it exists
+fn existing_hash(path: &FilePath) -> Result<Option<String>, AppError<'static>> {
+    if path.exists() {
+        Ok(Some(hash_path(path)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Writes the pretty printed document unless the file already has the same content.
+///
+/// Returns the hash of the output and whether it was written
+fn write_output(
+    document: &impl Serialize,
+    path: &FilePath,
+    existing_hash: Option<&String>,
+) -> Result<(String, bool), AppError<'static>> {
+    let mut hasher = HashWriter::default();
+    serde_json::to_writer_pretty(&mut hasher, document).expect("Documents always serialize");
+    let output_hash = hasher.finish();
+    if Some(&output_hash) == existing_hash {
+        return Ok((output_hash, false));
+    }
+
+    let written = File::create(path).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut writer, document)?;
+        writer.flush()
+    });
+    match written {
+        Ok(()) => Ok((output_hash, true)),
+        Err(err) => Err(AppError::IoErrorPath(err.attach_path(path.clone()))),
+    }
+}
+
+/// Project fields given to `init` on the command line
+#[derive(Default)]
+pub struct InitOptions {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub version: Option<String>,
+    pub authors: Vec<String>,
+    pub license: Option<String>,
+    pub original: Option<String>,
+    pub changes: Option<String>,
+    pub revise: Option<String>,
+    pub import: Option<Import>,
+    /// Use the defaults for everything that was not given instead of asking
+    pub yes: bool,
+    /// Overwrite an existing project.json
+    pub force: bool,
+}
+
+/// An existing JSON file or folder that becomes the original of a new project
+pub struct Import {
+    pub source: String,
+    /// Link the original folder to the source instead of copying it
+    pub in_place: bool,
+    /// Already modified copy of the source, the changes folder is generated from it
+    pub modified: Option<String>,
+}
+
+pub fn init(options: InitOptions) {
+    const DEFAULT_VERSION: &str = "1.0.0";
+    const DEFAULT_LICENSE: &str = "MIT";
+
+    refuse_overwrite(options.force);
+
+    if let Some(version) = &options.version {
+        if let Err(message) = validate_version(version) {
+            AppError::InvalidOption {
+                option: "version",
+                message,
+            }
+            .throw();
+        }
+    }
+    if let Some(license) = &options.license {
+        if let Err(message) = validate_license(license) {
+            AppError::InvalidOption {
+                option: "license",
+                message,
+            }
+            .throw();
+        }
+    }
+
+    if let Some(import) = &options.import {
+        let original = options.original.as_deref().unwrap_or("original");
+        check_import(import, original, options.force);
+    }
+
+    let interactive = !options.yes;
+    if interactive {
+        println!(
+            "This utility walks you through in creating a project.json file by asking some questions.\nYou can use Ctrl+C to exit any time"
+        );
+    }
+
+    let name = options.name.unwrap_or_else(|| {
+        let path = std::env::current_dir().expect("This program must be a binary");
+
+        let default = path
+            .file_name()
+            .expect("Program directory name always has a name")
+            .to_string_lossy()
+            .to_string();
+
+        if interactive {
+            prompt(&format!("Name ({}): ", default), &default, |_| Ok(()))
+        } else {
+            default
+        }
+    });
+
+    let description = options.description.unwrap_or_else(|| {
+        if interactive {
+            prompt("Description: ", "", |_| Ok(()))
+        } else {
+            String::new()
+        }
+    });
+
+    let version = options.version.unwrap_or_else(|| {
+        if interactive {
+            prompt(
+                &format!("Version ({}): ", DEFAULT_VERSION),
+                DEFAULT_VERSION,
+                validate_version,
+            )
+        } else {
+            DEFAULT_VERSION.to_string()
+        }
+    });
+
+    let authors = if !options.authors.is_empty() || !interactive {
+        options.authors
+    } else {
+        let author = prompt("Author: ", "", |_| Ok(()));
+        if author.is_empty() {
+            vec![]
+        } else {
+            vec![author]
+        }
+    };
+
+    let license = options.license.unwrap_or_else(|| {
+        if interactive {
+            prompt(
+                &format!("License: ({}): ", DEFAULT_LICENSE),
+                DEFAULT_LICENSE,
+                validate_license,
+            )
+        } else {
+            DEFAULT_LICENSE.to_string()
+        }
+    });
+
+    let config = ProjectConfig {
+        schema: None,
+        config_version: CONFIG_VERSION,
+        name,
+        description,
+        version,
+        authors,
+        license,
+        paths: ProjectPaths {
+            original: options.original.unwrap_or_else(|| "original".to_string()),
+            changes: ChangeLayers::single(options.changes.unwrap_or_else(|| "changes".to_string())),
+            revise: options.revise.unwrap_or_else(|| "revise".to_string()),
+        },
+        schemas: Vec::new(),
+        transforms: Vec::new(),
+        profiles: BTreeMap::new(),
+        variables: BTreeMap::new(),
+        identity_keys: Vec::new(),
+        update: UpdateSettings::default(),
+    };
+
+    let contents =
+        serde_json::to_string_pretty(&config).expect("Correct steps taken to create config");
+
+    if interactive {
+        println!("Config: \n{}", contents);
+        let answer = prompt("Is this OK? (yes)", "yes", |_| Ok(()));
+        if answer != "y" && answer != "yes" {
+            println!("Aborted");
+            return;
+        }
+    }
+
+    scaffold(&config.paths, contents.as_bytes(), options.import.as_ref());
+}
+
+pub fn init_default(force: bool) {
+    refuse_overwrite(force);
+    let contents = include_bytes!("static/project.json");
+    let config: ProjectConfig =
+        serde_json::from_slice(contents).expect("The default config is always valid");
+    scaffold(&config.paths, contents, None);
+}
+
+fn refuse_overwrite(force: bool) {
+    if !force && Path::new(CONFIG_FILE).exists() {
+        AppError::FileAlreadyExists {
+            file_path: PathBuf::from(CONFIG_FILE).into(),
+        }
+        .throw();
+    }
+}
 
-    let mut generate_count = 0;
-    for trio in trios {
-        let file_type = trio.file_type;
-        let original = trio.original;
-        let matching = trio.changes;
-        let result = trio.changed;
-
-        if !matching.exists() {
-            if file_type.is_dir() {
-                println!(
-                    "{}{}{}",
-                    "Warning: Path ".yellow(),
-                    original.to_string_lossy().yellow(),
-                    " does not have a matching changes folder, creating folder...".yellow(),
-                );
-                create_dir(&result).expect("All dirs required to be made should already be made");
-            } else if file_type.is_file() {
-                println!(
-                    "{}{}{}",
-                    "Warning: Path ".yellow(),
-                    original.to_string_lossy().yellow(),
-                    " does not have a matching changes file, creating file...".yellow(),
-                );
-                let mut file = File::create(&matching)
-                    .expect("Directory creation goes first and dirs should be made first");
-                match file.write_all(b"[]") {
-                    Ok(it) => it,
-                    Err(err) => err.attach_path(matching).throw(),
-                };
-            }
-            continue;
+/// Asks a question until the answer is valid, an empty answer picks the default
+fn prompt(question: &str, default: &str, validate: fn(&str) -> Result<(), String>) -> String {
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    loop {
+        print!("{}", question);
+        stdout.flush().unwrap_app();
+
+        let mut answer = String::new();
+        if stdin.read_line(&mut answer).unwrap_app() == 0 {
+            // Nothing more to read, so asking again would loop forever
+            println!();
+            return default.to_string();
         }
+        let answer = match answer.trim() {
+            "" => default,
+            answer => answer,
+        };
+        match validate(answer) {
+            Ok(()) => return answer.to_string(),
+            Err(message) => println!("{}", message.red()),
+        }
+    }
+}
 
-        if !result.exists() {
-            if file_type.is_dir() {
-                create_dir(&result).expect("All dirs required to be made should already be made");
-                continue;
-            }
+/// Throws before anything is written if the import cannot succeed
+fn check_import(import: &Import, original: &str, force: bool) {
+    for path in [Some(&import.source), import.modified.as_ref()]
+        .into_iter()
+        .flatten()
+    {
+        if let Err(err) = fs::metadata(path) {
+            err.attach_path(PathBuf::from(path).into()).throw();
         }
+    }
 
-        if file_type.is_dir() {
-            continue;
+    // Imported files would silently replace the ones already there
+    let occupied = fs::read_dir(original).is_ok_and(|mut dir| dir.next().is_some());
+    if occupied && !force {
+        AppError::FileAlreadyExists {
+            file_path: PathBuf::from(original).into(),
         }
+        .throw();
+    }
+}
 
-        let mut original_json = json_from_path(original.clone());
-        let changes = json_from_path(matching.clone());
+/// Writes project.json and creates the project's folders and .gitignore,
+/// filling the original and changes folders from the import if there is one
+fn scaffold(paths: &ProjectPaths, config: &[u8], import: Option<&Import>) {
+    if let Err(err) = write_file(&PathBuf::from(CONFIG_FILE).into(), config) {
+        err.throw();
+    }
+    println!("{} {}", "Created".bright_green(), CONFIG_FILE);
 
-        let res = from_value::<Patch>(changes);
-        let patch = match res {
-            Ok(it) => it,
-            Err(_err) => AppError::InvalidFileFormat {
-                file_path: matching.into(),
-                expected: "JSON patch file",
-            }
-            .throw(),
-        };
+    // Linking in place needs the original folder to not exist yet
+    let imported = import.map(|import| {
+        import_original(import, Path::new(&paths.original)).unwrap_or_else(|err| err.throw())
+    });
 
-        if let Err(_) = json_patch::patch(&mut original_json, &patch) {
-            AppError::PatchError {
-                target_file: original.into(),
-                patch_file: matching.into(),
-            }
-            .throw();
-        };
+    let dirs = [&paths.original, &paths.revise]
+        .into_iter()
+        .chain(paths.changes.iter());
+    for dir in dirs {
+        if Path::new(dir).is_dir() {
+            continue;
+        }
+        if let Err(err) = create_dir_all(dir) {
+            err.attach_path(PathBuf::from(dir).into()).throw();
+        }
+        println!("{} {}/", "Created".bright_green(), dir);
+    }
 
-        let mut patched_file = File::create(&result).expect("All files should have a base dir");
-        match patched_file.write_all(to_string_pretty(&original_json).unwrap().as_bytes()) {
-            Ok(it) => it,
-            Err(err) => err.attach_path(result.into()).throw(),
+    write_gitignore(paths);
+
+    if let (Some(import), Some(files)) = (import, imported) {
+        if let Some(modified) = &import.modified {
+            generate_changes(&files, Path::new(modified), paths).unwrap_or_else(|err| err.throw());
         }
-        generate_count += 1;
     }
-    let msg = format!("Successfully generated {} files", generate_count);
-    println!("{}", msg.bright_green());
 }
 
-/*
-original - have all the files
-changes - empty
-changed - have all files but changed
-*/
-pub fn update(original_path: OsString, matches: OsString, result: OsString) {
-    // TODO: This repeats the build function which should be fixed (later)
-    let trios = match get_file_trios(PathBuf::from("."), original_path, matches, result) {
-        Ok(it) => it,
-        Err(err) => match err {
-            FindFileTriosError::TrioInitError(err) => match err {
-                TrioInitError::InconsistentFileTypes(err) => AppError::InconsistentFileTypes(err),
-                TrioInitError::IoError(err) => AppError::IoErrorPath(err),
-            },
-            FindFileTriosError::IoErrorWithPath(err) => AppError::IoErrorPath(err),
-            FindFileTriosError::IoError(err) => AppError::IoError(err),
-        }
-        .throw(),
+/// Leaves the generated revise folder and the cache out of git
+fn write_gitignore(paths: &ProjectPaths) {
+    const GITIGNORE: &str = ".gitignore";
+    let ignored = [
+        format!(
+            "/{}/",
+            paths.revise.trim_start_matches("./").trim_end_matches('/')
+        ),
+        format!("/{}/", CACHE_DIR),
+    ];
+    let existing = fs::read_to_string(GITIGNORE).unwrap_or_default();
+    let missing: Vec<&String> = ignored
+        .iter()
+        .filter(|line| {
+            !existing
+                .lines()
+                .any(|existing| existing.trim() == line.as_str())
+        })
+        .collect();
+    if missing.is_empty() {
+        return;
+    }
+    let mut contents = existing.clone();
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    for line in missing {
+        contents.push_str(line);
+        contents.push('\n');
+    }
+    if let Err(err) = write_file(&PathBuf::from(GITIGNORE).into(), contents.as_bytes()) {
+        err.throw();
+    }
+    let action = if existing.is_empty() {
+        "Created"
+    } else {
+        "Updated"
     };
+    println!("{} {}", action.bright_green(), GITIGNORE);
+}
 
-    let mut changes_count = 0;
-    for trio in trios {
-        let original = trio.original;
-        let changes = trio.changes;
-        let changed = trio.changed;
-
-        if original.is_dir() {
-            if !changes.exists() {
-                println!(
-                    "{}{}{}",
-                    "Warning: Path ".yellow(),
-                    original.to_string_lossy().yellow(),
-                    " does not have a matching changes folder, creating folder...".yellow(),
-                );
-                create_dir(&changes).expect("All dirs required to be made should already be made");
-            }
-            continue;
-        }
+/// Copies or links the imported source into the original folder.
+///
+/// Returns the imported JSON files relative to the original folder
+fn import_original(import: &Import, original: &Path) -> Result<Vec<PathBuf>, AppError<'static>> {
+    let source = Path::new(&import.source);
+    let io_error = |path: &Path| {
+        let path: FilePath = path.into();
+        move |err: io::Error| AppError::IoErrorPath(err.attach_path(path))
+    };
 
-        if !changed.exists() {
-            continue;
-        }
+    let files = if source.is_dir() {
+        json_files(source, true)?
+    } else {
+        vec![PathBuf::from(
+            source.file_name().unwrap_or(source.as_os_str()),
+        )]
+    };
 
-        let original_json: Value = json_from_path(original);
-        let changed_json: Value = json_from_path(changed);
+    // A link left by an earlier in place import is replaced, never written through
+    if original.is_symlink() {
+        fs::remove_file(original)
+            .or_else(|_| fs::remove_dir(original))
+            .map_err(io_error(original))?;
+    }
 
-        let diff = json_patch::diff(&original_json, &changed_json);
+    if import.in_place {
+        let target = source.canonicalize().map_err(io_error(source))?;
+        if source.is_dir() {
+            if original.is_dir() {
+                fs::remove_dir(original).map_err(io_error(original))?;
+            }
+            symlink(&target, original).map_err(io_error(original))?;
+        } else {
+            let link = original.join(&files[0]);
+            create_dir_all(original).map_err(io_error(original))?;
+            symlink(&target, &link).map_err(io_error(&link))?;
+        }
+        println!(
+            "{} {}/ to {}",
+            "Linked".bright_green(),
+            original.display(),
+            target.display()
+        );
+    } else {
+        for file in &files {
+            let from = if source.is_dir() {
+                source.join(file)
+            } else {
+                source.to_path_buf()
+            };
+            let to = original.join(file);
+            if is_same_file(&from, &to) {
+                continue;
+            }
+            if let Some(parent) = to.parent() {
+                create_dir_all(parent).map_err(io_error(parent))?;
+            }
+            fs::copy(&from, &to).map_err(io_error(&from))?;
+        }
+        println!(
+            "{} {} files into {}/",
+            "Imported".bright_green(),
+            files.len(),
+            original.display()
+        );
+    }
+    Ok(files)
+}
 
-        let mut changes_file = match File::create(&changes) {
-            Ok(it) => it,
-            Err(err) => err.attach_path(changes.into()).throw(),
-        };
+/// Copying a file onto itself would truncate it
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
 
-        match File::write_all(&mut changes_file, diff.to_string().as_bytes()) {
-            Ok(_) => (),
-            Err(err) => err.attach_path(changes.into()).throw(),
-        };
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
 
-        changes_count += 1;
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    if target.is_dir() {
+        std::os::windows::fs::symlink_dir(target, link)
+    } else {
+        std::os::windows::fs::symlink_file(target, link)
     }
-    let msg = format!("Successfully applied {} changes", changes_count);
-    println!("{}", msg.bright_green());
 }
 
-// Too lazy to return error
-fn json_from_path(path: FilePath) -> Value {
-    let mut file = match File::open(&path) {
-        Ok(it) => it,
-        Err(err) => err.attach_path(path.into()).throw(),
+/// Writes the difference between every imported original and its modified copy
+/// into the changes folder
+fn generate_changes(
+    files: &[PathBuf],
+    modified: &Path,
+    paths: &ProjectPaths,
+) -> Result<(), AppError<'static>> {
+    let modified_path = |file: &Path| {
+        if modified.is_dir() {
+            modified.join(file)
+        } else {
+            modified.to_path_buf()
+        }
     };
-    let mut buf = String::new();
-    match file.read_to_string(&mut buf) {
-        Ok(_) => (),
-        Err(err) => err.attach_path(path.into()).throw(),
+
+    if modified.is_dir() {
+        for file in json_files(modified, true)? {
+            if !files.contains(&file) {
+                report::emit(Event::Warning {
+                    path: &modified.join(file),
+                    message: "has no original file, its changes cannot be imported",
+                });
+            }
+        }
     }
-    serde_json::from_str(&buf).unwrap_or_else(|_| {
-        AppError::InvalidFileFormat {
-            file_path: path.into(),
-            expected: "JSON file",
+
+    let mut changed_count = 0;
+    for file in files {
+        let original: FilePath = Path::new(&paths.original).join(file).into();
+        let changed: FilePath = modified_path(file).into();
+        let changes: FilePath = Path::new(paths.changes.top()).join(file).into();
+
+        let original_json = json_from_path(original)?;
+        let diff = if changed.exists() {
+            diff::diff(&original_json, &json_from_path(changed)?)
+        } else {
+            Patch(Vec::new())
+        };
+        if !diff.0.is_empty() {
+            changed_count += 1;
         }
-        .throw();
-    })
-}
 
-pub fn init() {
+        if let Some(parent) = changes.parent() {
+            create_dir_all(parent)
+                .map_err(|err| AppError::IoErrorPath(err.attach_path(parent.into())))?;
+        }
+        write_file(&changes, patch_file::format(&diff).as_bytes())?;
+    }
     println!(
-        "This utility walks you through in creating a project.json file by asking some questions.\nYou can use Ctrl+C to exit any time"
+        "{} {} patches in {}/, {} with changes",
+        "Generated".bright_green(),
+        files.len(),
+        paths.changes.top(),
+        changed_count
     );
+    Ok(())
+}
 
-    let stdin = io::stdin();
-    let mut stdout = io::stdout();
+/// Paths of every JSON file in the folder relative to it, sorted so imports are stable
+fn json_files(root: &Path, warn_skipped: bool) -> Result<Vec<PathBuf>, AppError<'static>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        let path = root.join(&dir);
+        let entries = fs::read_dir(&path)
+            .map_err(|err| AppError::IoErrorPath(err.attach_path(path.as_path().into())))?;
+        for entry in entries {
+            let entry = entry.map_err(AppError::IoError)?;
+            let relative = dir.join(entry.file_name());
+            if entry.path().is_dir() {
+                dirs.push(relative);
+            } else if relative.extension().is_some_and(|ext| ext == "json") {
+                files.push(relative);
+            } else if warn_skipped {
+                report::emit(Event::Warning {
+                    path: &entry.path(),
+                    message: "is not a JSON file, skipping...",
+                });
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
 
-    let name = {
-        let path = std::env::current_dir().expect("This program must be a binary");
+/// Prints what upstream added, removed and changed between two versions of the original folder
+pub fn upstream_diff(old: &Path, new: &Path, identity_keys: &[String], format: ReportFormat) {
+    // Warnings would end up in the middle of a Markdown or JSON report
+    let warn_skipped = matches!(format, ReportFormat::Terminal);
+    upstream_report(old, new, identity_keys, warn_skipped)
+        .unwrap_or_else(|err| err.throw())
+        .print(format);
+}
 
-        let default = path
-            .file_name()
-            .expect("Program directory name always has a name")
-            .to_string_lossy()
-            .to_string();
+/// The files git passes to a merge driver
+pub struct MergeFiles {
+    /// `%O`, the version both sides started from
+    pub ancestor: PathBuf,
+    /// `%A`, our version, which the result is written into
+    pub current: PathBuf,
+    /// `%B`, their version
+    pub other: PathBuf,
+    /// `%P`, where the file is in the repository, to check the merge against its original
+    pub path: Option<PathBuf>,
+}
 
-        print!("Name ({}): ", default);
-        stdout.flush().unwrap_app();
+/// Merges two versions of a patch file for git operation by operation.
+///
+/// Exits with 1 when conflicts are left in the file. Versions that cannot be merged by
+/// their content become a single conflict of the whole file
+pub fn merge_driver(files: MergeFiles) {
+    let read = |path: &Path| {
+        let path: FilePath = path.into();
+        read_file(&path)
+            .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+            .unwrap_or_else(|err| err.throw())
+    };
+    let ancestor = read(&files.ancestor);
+    let current = read(&files.current);
+    let other = read(&files.other);
 
-        let mut name = String::new();
-        stdin.read_line(&mut name).unwrap_app();
-        let name = name.trim();
-        if name.is_empty() {
-            default
-        } else {
-            name.to_string()
+    let (merged, clean) = match merge_versions(&files, &ancestor, &current, &other) {
+        Ok(merged) => merged,
+        Err(reason) => {
+            report::emit(Event::Warning {
+                path: files.path.as_deref().unwrap_or(&files.current),
+                message: &format!("cannot be merged by its content, {}", reason),
+            });
+            (merge::conflict_file(&current, &other), false)
         }
     };
+    write_file(&files.current.as_path().into(), merged.as_bytes())
+        .unwrap_or_else(|err| err.throw());
+    if !clean {
+        exit(1);
+    }
+}
 
-    let description = {
-        print!("Description: ");
-        stdout.flush().unwrap_app();
+/// The merged file and whether it is free of conflicts
+fn merge_versions(
+    files: &MergeFiles,
+    ancestor: &str,
+    current: &str,
+    other: &str,
+) -> Result<(String, bool), String> {
+    let parse = |text: &str| {
+        serde_json::from_str::<Value>(text)
+            .map_err(|_| "one of its versions is not valid JSON".to_string())
+    };
+    let current = parse(current)?;
+    let other = parse(other)?;
+    // A file both sides added has no ancestor
+    let ancestor = if ancestor.trim().is_empty() {
+        match current {
+            Value::Object(_) => json!({}),
+            _ => json!([]),
+        }
+    } else {
+        parse(ancestor)?
+    };
 
-        let mut description = String::new();
-        stdin.read_line(&mut description).unwrap_app();
-        description.trim().to_string()
+    // Rules files are merged as plain documents
+    if ancestor.is_object() && current.is_object() && other.is_object() {
+        let merged = merge::merge(&ancestor, &current, &other);
+        if let Some(pointer) = merged.conflicts.first() {
+            return Err(format!("both sides changed `{}`", pointer));
+        }
+        let mut text =
+            serde_json::to_string_pretty(&merged.ours).expect("JSON values always serialize");
+        text.push('\n');
+        return Ok((text, true));
+    }
+
+    let patch = |version: Value| {
+        from_value::<Patch>(version)
+            .map_err(|_| "one of its versions is not a JSON patch".to_string())
     };
+    let merged = merge::merge_patches(&patch(ancestor)?, &patch(current)?, &patch(other)?);
+    let ours = patch_file::format(&merged.ours);
+    if !merged.conflicts.is_empty() {
+        let theirs = patch_file::format(&merged.theirs);
+        return Ok((merge::conflict_lines(&ours, &theirs), false));
+    }
 
-    let version = {
-        const DEFAULT_VERSION: &str = "1.0.0";
-        print!("Version ({}): ", DEFAULT_VERSION);
-        stdout.flush().unwrap_app();
+    // Without its path there is no original to check the merge against
+    if let Some(base) = files.path.as_deref().and_then(|path| patch_base(path).ok()) {
+        let mut document = base;
+        if json_patch::patch(&mut document, &merged.ours).is_err() {
+            return Err("the merged operations do not apply to the original".to_string());
+        }
+    }
+    Ok((ours, true))
+}
 
-        let mut version = String::new();
-        stdin.read_line(&mut version).unwrap_app();
-        let version = version.trim();
-        if version.is_empty() {
-            DEFAULT_VERSION.to_string()
-        } else {
-            version.to_string()
+/// The document the patch file at `path` is applied to: its original with the change
+/// layers below it and the `before` rules of its own layer
+fn patch_base(path: &Path) -> Result<Value, String> {
+    let root = path
+        .ancestors()
+        .skip(1)
+        .find(|dir| dir.join(CONFIG_FILE).exists())
+        .ok_or_else(|| format!("there is no {} in its folders", CONFIG_FILE))?;
+    let config = ProjectConfig::load(&root.join(CONFIG_FILE))
+        .map_err(|err| err.message())?
+        .config;
+    let in_project = path.strip_prefix(root).unwrap_or(path);
+
+    let layers: Vec<PathBuf> = config
+        .paths
+        .changes
+        .iter()
+        .map(|layer| {
+            Path::new(layer)
+                .components()
+                .filter(|component| *component != Component::CurDir)
+                .collect()
+        })
+        .collect();
+    let (index, relative) = layers
+        .iter()
+        .enumerate()
+        .find_map(|(index, layer)| Some((index, in_project.strip_prefix(layer).ok()?)))
+        .ok_or("it is not in a changes folder of the project")?;
+
+    let original: FilePath = root.join(&config.paths.original).join(relative).into();
+    if !original.exists() {
+        return Err("it does not have an original file".to_string());
+    }
+    let mut document = json_from_path(original.clone()).map_err(|err| err.message())?;
+    for layer in &layers[..index] {
+        let changes: FilePath = root.join(layer).join(relative).into();
+        if !changes.exists() {
+            continue;
+        }
+        let patch = patch_from_value(
+            json_from_path(changes.clone()).map_err(|err| err.message())?,
+            &changes,
+        )
+        .map_err(|err| err.message())?;
+        let rules = load_rules(&changes).map_err(|err| err.message())?;
+        apply_changes(&mut document, &patch, rules.as_ref(), &original, &changes)
+            .map_err(|err| err.message())?;
+    }
+    let changes: FilePath = root.join(&layers[index]).join(relative).into();
+    if let Some(rules) = load_rules(&changes).map_err(|err| err.message())? {
+        apply_rules(&rules, Stage::Before, &mut document, &original, &changes)
+            .map_err(|err| err.message())?;
+    }
+    Ok(document)
+}
+
+/// Prints a patch file as a readable listing of its operations for `git diff`, with the
+/// values they replace where the original of the file can be found.
+///
+/// Files that are not patches are printed as they are
+pub fn textconv(path: &Path) {
+    let bytes = read_file(&path.into()).unwrap_or_else(|err| err.throw());
+    let Ok(value) = serde_json::from_slice::<Value>(&bytes) else {
+        print!("{}", String::from_utf8_lossy(&bytes));
+        return;
+    };
+    let patch = match from_value::<Patch>(value.clone()) {
+        Ok(patch) if !is_rules_file(path) => patch,
+        _ => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&value).expect("JSON values always serialize")
+            );
+            return;
         }
     };
+    let base = textconv_base(path).ok();
+    print!("{}", patch_file::listing(&patch, base.as_ref()));
+}
 
-    let authors = {
-        print!("Author: ");
-        stdout.flush().unwrap_app();
+/// The document a patch file in a changes folder is applied to.
+///
+/// Older versions are given as a temporary copy named `XXXXXX_<file name>`, those are
+/// matched to the only original file with the same name in the top layer
+fn textconv_base(path: &Path) -> Result<Value, String> {
+    if let Ok(base) = patch_base(path) {
+        return Ok(base);
+    }
+    let name = path
+        .file_name()
+        .ok_or("it is not a file")?
+        .to_string_lossy()
+        .into_owned();
+    let name = match name.split_once('_') {
+        Some((prefix, rest)) if prefix.len() == 6 => rest,
+        _ => &name,
+    };
+    let config = ProjectConfig::load(Path::new(CONFIG_FILE))
+        .map_err(|err| err.message())?
+        .config;
+    let matches: Vec<PathBuf> = json_files(Path::new(&config.paths.original), false)
+        .map_err(|err| err.message())?
+        .into_iter()
+        .filter(|file| file.file_name().is_some_and(|file_name| file_name == name))
+        .collect();
+    match matches.as_slice() {
+        [relative] => patch_base(&Path::new(config.paths.changes.top()).join(relative)),
+        [] => Err("there is no original file with its name".to_string()),
+        _ => Err("there are several original files with its name".to_string()),
+    }
+}
+
+/// Name of the file that describes the contents of a package
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// How `package` builds and writes the archive
+pub struct PackageOptions {
+    /// The build to package, its revise folder is replaced with a staging folder
+    pub variant: BuildVariant,
+    pub format: ArchiveFormat,
+    /// Path of the archive, `<name>-<version>.<extension>` if not given
+    pub output: Option<String>,
+    pub jobs: usize,
+}
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+    name: &'a str,
+    version: &'a str,
+    description: &'a str,
+    license: &'a str,
+    authors: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<&'a str>,
+    files: Vec<ManifestFile>,
+    /// The original files the package was built from
+    original: Vec<ManifestFile>,
+}
+
+#[derive(Serialize)]
+struct ManifestFile {
+    path: String,
+    sha256: String,
+}
+
+/// Builds the project from scratch into a staging folder and writes it into an archive
+/// with a manifest.json, the same project always gives the same bytes
+pub fn package(config: &ProjectConfig, options: PackageOptions, variables: &Variables) {
+    let schemas = Schemas::load(&config.schemas).unwrap_or_else(|err| err.throw());
+    let transforms =
+        Transforms::load(&options.variant.transforms).unwrap_or_else(|err| err.throw());
+    let output: FilePath = PathBuf::from(
+        options
+            .output
+            .unwrap_or_else(|| format!("{}.{}", release_name(config), options.format.extension())),
+    )
+    .into();
 
-        let mut author = String::new();
-        stdin.read_line(&mut author).unwrap_app();
-        
-        vec![author.trim().to_string()]
+    let staging = staging_dir().unwrap_or_else(|err| err.throw());
+    let variant = BuildVariant {
+        revise: staging.to_string_lossy().into_owned(),
+        ..options.variant
     };
+    // A table of its own, so every file is built and the cache of `build` stays as it is
+    let packaged = build_variant(
+        OsString::from(&config.paths.original),
+        &variant,
+        options.jobs,
+        &mut CacheTable::default(),
+        &schemas,
+        &transforms,
+        variables,
+    )
+    .and_then(|error_count| match error_count {
+        0 => write_package(config, &variant, &staging, options.format, &output).map(Some),
+        _ => Ok(None),
+    });
+    let _ = fs::remove_dir_all(&staging);
+    match packaged {
+        Ok(Some(count)) => report::emit(Event::Summary {
+            command: "package",
+            profile: variant.profile.as_deref(),
+            processed: count,
+            up_to_date: 0,
+            warnings: 0,
+            errors: 0,
+        }),
+        Ok(None) => exit(101),
+        Err(err) => err.throw(),
+    }
+}
 
-    let license = {
-        const DEFAULT_LICENSE: &str = "MIT";
-        print!("License: ({}): ", DEFAULT_LICENSE);
-        stdout.flush().unwrap_app();
+/// Makes a new folder in the cache folder to build a package in, its name is unique
+/// so packages built at the same time do not share one
+fn staging_dir() -> Result<PathBuf, AppError<'static>> {
+    if let Err(err) = create_dir_all(CACHE_DIR) {
+        return Err(AppError::IoErrorPath(
+            err.attach_path(PathBuf::from(CACHE_DIR).into()),
+        ));
+    }
+    for attempt in 0.. {
+        let staging = Path::new(CACHE_DIR).join(format!("package-{}-{}", process::id(), attempt));
+        match create_dir(&staging) {
+            Ok(()) => return Ok(staging),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(AppError::IoErrorPath(err.attach_path(staging.into()))),
+        }
+    }
+    unreachable!("There is always a name left to try")
+}
 
-        let mut license = String::new();
-        stdin.read_line(&mut license).unwrap_app();
-        let license = license.trim();
-        if license.is_empty() {
-            DEFAULT_LICENSE.to_string()
-        } else {
-            license.to_string()
+/// `<name>-<version>` with only characters that are safe in file names,
+/// the folder every file of the archive is in
+fn release_name(config: &ProjectConfig) -> String {
+    format!("{}-{}", config.name, config.version)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// Writes the archive, returns the amount of built files in it
+fn write_package(
+    config: &ProjectConfig,
+    variant: &BuildVariant,
+    staging: &Path,
+    format: ArchiveFormat,
+    output: &FilePath,
+) -> Result<usize, AppError<'static>> {
+    let root = release_name(config);
+    let mut entries = Vec::new();
+    let mut files = Vec::new();
+    for file in json_files(staging, false)? {
+        let bytes = read_file(&staging.join(&file).into())?;
+        let path = slash_path(&file);
+        if path == MANIFEST_FILE {
+            return Err(AppError::PackageFailed {
+                file_path: output.clone(),
+                message: format!("the build has a {} of its own", MANIFEST_FILE),
+            });
         }
-    };
+        files.push(ManifestFile {
+            path: path.clone(),
+            sha256: hash(&bytes),
+        });
+        entries.push(Entry {
+            path: format!("{}/{}", root, path),
+            bytes,
+        });
+    }
 
-    let config = ProjectConfig {
-        name,
-        description,
-        version,
-        authors,
-        license,
-        paths: ProjectPaths {
-            original: "original".to_string(),
-            changes: "changes".to_string(),
-            revise: "revise".to_string(),
-        },
+    let original = Path::new(&config.paths.original);
+    let mut originals = Vec::new();
+    for file in json_files(original, false)? {
+        let path = original.join(&file);
+        originals.push(ManifestFile {
+            path: slash_path(&file),
+            sha256: hash_file(&path)
+                .map_err(|err| AppError::IoErrorPath(err.attach_path(path.into())))?,
+        });
+    }
+
+    let count = files.len();
+    let manifest = Manifest {
+        name: &config.name,
+        version: &config.version,
+        description: &config.description,
+        license: &config.license,
+        authors: &config.authors,
+        profile: variant.profile.as_deref(),
+        files,
+        original: originals,
     };
+    let mut bytes = serde_json::to_vec_pretty(&manifest).expect("Manifests always serialize");
+    bytes.push(b'\n');
+    entries.insert(
+        0,
+        Entry {
+            path: format!("{}/{}", root, MANIFEST_FILE),
+            bytes,
+        },
+    );
 
-    let config =
-        serde_json::to_string_pretty(&config).expect("Correct steps taken to create config");
+    let archive = format
+        .write(&entries)
+        .map_err(|message| AppError::PackageFailed {
+            file_path: output.clone(),
+            message,
+        })?;
+    write_file(output, &archive)?;
+    Ok(count)
+}
+
+/// The path with `/` between its folders on every platform
+fn slash_path(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Lists every patch operation whose target upstream modified, removed or shifted in the
+/// new original, most urgent first
+pub fn impact(
+    original_path: OsString,
+    changes: &ChangeLayers,
+    result: OsString,
+    new_original: &Path,
+    identity_keys: &[String],
+    variables: &Variables,
+    format: ReportFormat,
+) {
+    let revise = PathBuf::from(&result);
+    let substitutions = Substitutions {
+        variables,
+        documents: Documents::Folder(&revise),
+    };
+    let trios = file_trios(original_path, changes, result).unwrap_or_else(|err| err.throw());
 
-    println!("Config: \n{}", config);
-    print!("Is this OK? (yes)");
-    stdout.flush().unwrap_app();
+    let mut operations = Vec::new();
+    let mut error_count = 0;
+    for trio in trios {
+        if trio.file_type.is_dir() {
+            continue;
+        }
+        match file_impact(&trio, new_original, identity_keys, &substitutions) {
+            Ok(affected) => operations.extend(affected),
+            Err(err) => {
+                err.report();
+                error_count += 1;
+            }
+        }
+    }
+    ImpactReport::new(operations).print(format);
+    if error_count > 0 {
+        exit(101);
+    }
+}
 
-    let mut buf = String::new();
-    stdin.read_line(&mut buf).unwrap_app();
-    let buf = buf.trim();
+fn file_impact(
+    trio: &FilePathTrio,
+    new_original: &Path,
+    identity_keys: &[String],
+    substitutions: &Substitutions,
+) -> Result<Vec<AffectedOperation>, AppError<'static>> {
+    let mut patches = Vec::new();
+    for changes in trio.lower.iter().chain([&trio.changes]) {
+        if !changes.exists() {
+            continue;
+        }
+        patches.push(LayerPatch {
+            file: changes
+                .strip_prefix(".")
+                .unwrap_or(changes)
+                .to_string_lossy()
+                .into_owned(),
+            patch: substituted_patch(&read_file(changes)?, changes, substitutions)?,
+        });
+    }
+    if patches.is_empty() {
+        return Ok(Vec::new());
+    }
 
-    if !buf.is_empty() && buf != "y" && buf != "yes" {
-        println!("Aborted");
+    let original = json_from_path(trio.original.clone())?;
+    let new_path: FilePath = new_original.join(trio.relative()).into();
+    let new = if new_path.exists() {
+        Some(json_from_path(new_path)?)
     } else {
-        let mut file = match File::create(CONFIG_FILE) {
-            Ok(it) => it,
-            // Unsure of a good way to construct a Rc<Path>
-            Err(err) => err.attach_path(PathBuf::from(CONFIG_FILE).into()).throw(),
+        None
+    };
+    Ok(impact::analyze(
+        &original,
+        new.as_ref(),
+        &patches,
+        identity_keys,
+    ))
+}
+
+/// Compares every JSON file of two versions of the original folder, or two single files
+fn upstream_report(
+    old: &Path,
+    new: &Path,
+    identity_keys: &[String],
+    warn_skipped: bool,
+) -> Result<UpstreamReport, AppError<'static>> {
+    if old.is_file() && new.is_file() {
+        let name = new.file_name().unwrap_or(new.as_os_str());
+        let changes = upstream::compare(
+            &json_from_path(old.into())?,
+            &json_from_path(new.into())?,
+            identity_keys,
+        );
+        let files = if changes.is_empty() {
+            Vec::new()
+        } else {
+            vec![FileChange {
+                path: name.to_string_lossy().into_owned(),
+                kind: ChangeKind::Changed,
+                changes,
+            }]
         };
+        return Ok(UpstreamReport { files });
+    }
 
-        match file.write(config.as_bytes()) {
-            Ok(_) => (),
-            Err(err) => err.attach_path(PathBuf::from(CONFIG_FILE).into()).throw(),
+    let old_files = json_files(old, warn_skipped)?;
+    let new_files = json_files(new, warn_skipped)?;
+    let all: BTreeSet<&PathBuf> = old_files.iter().chain(&new_files).collect();
+    let mut files = Vec::new();
+    for file in all {
+        let path = file.to_string_lossy().replace('\\', "/");
+        let kind = match (old_files.contains(file), new_files.contains(file)) {
+            (true, true) => ChangeKind::Changed,
+            (false, _) => ChangeKind::Added,
+            (true, false) => ChangeKind::Removed,
         };
+        if kind != ChangeKind::Changed {
+            files.push(FileChange {
+                path,
+                kind,
+                changes: Vec::new(),
+            });
+            continue;
+        }
+
+        let old_path: FilePath = old.join(file).into();
+        let new_path: FilePath = new.join(file).into();
+        let old_bytes = read_file(&old_path)?;
+        let new_bytes = read_file(&new_path)?;
+        if old_bytes == new_bytes {
+            continue;
+        }
+        let changes = upstream::compare(
+            &json_from_bytes(&old_bytes, &old_path)?,
+            &json_from_bytes(&new_bytes, &new_path)?,
+            identity_keys,
+        );
+        if !changes.is_empty() {
+            files.push(FileChange {
+                path,
+                kind,
+                changes,
+            });
+        }
     }
+    Ok(UpstreamReport { files })
 }
 
-pub fn init_default() {
-    let mut file = match File::create(CONFIG_FILE) {
-        Ok(it) => it,
-        // Unsure of a good way to construct a Rc<Path>
-        Err(err) => err.attach_path(PathBuf::from(CONFIG_FILE).into()).throw(),
+/// Where `codegen` takes the type model from
+pub enum CodegenSource {
+    /// The documents the build wrote into its revise folder
+    Built(BuildVariant),
+    /// A JSON Schema file
+    Schema(PathBuf),
+}
+
+/// Generates type definitions for the documents, and constant tables of their enums and of
+/// the arrays with identity keys. The code is printed if no output file is given
+pub fn codegen(
+    source: CodegenSource,
+    language: Language,
+    output: Option<&Path>,
+    identity_keys: &[String],
+) {
+    let (label, models, tables) = match source {
+        CodegenSource::Built(variant) => {
+            let revise = Path::new(&variant.revise);
+            let files = json_files(revise, output.is_some()).unwrap_or_else(|err| err.throw());
+            let mut models = Vec::new();
+            let mut tables = Vec::new();
+            for file in files {
+                let document =
+                    json_from_path(revise.join(&file).into()).unwrap_or_else(|err| err.throw());
+                let name = slash_path(&file.with_extension(""));
+                tables.extend(codegen::tables(&name, &document, identity_keys));
+                models.push(Model {
+                    name,
+                    shape: Shape::of([&document]),
+                });
+            }
+            (slash_path(revise), models, tables)
+        }
+        CodegenSource::Schema(path) => {
+            let schema = json_from_path(path.as_path().into()).unwrap_or_else(|err| err.throw());
+            let name = match schema.get("title").and_then(Value::as_str) {
+                Some(title) => title.to_string(),
+                None => path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+            };
+            let model = Model {
+                name,
+                shape: Shape::from_schema(&schema),
+            };
+            (slash_path(&path), vec![model], Vec::<Table>::new())
+        }
+    };
+
+    let code = codegen::generate(language, &label, &models, &tables);
+    let Some(output) = output else {
+        print!("{}", code);
+        return;
+    };
+    write_file(&output.into(), code.as_bytes()).unwrap_or_else(|err| err.throw());
+    report::emit(Event::Summary {
+        command: "codegen",
+        profile: None,
+        processed: models.len(),
+        up_to_date: 0,
+        warnings: 0,
+        errors: 0,
+    });
+}
+
+/// Infers a JSON Schema allowing every JSON file matching `pattern` in the original folder,
+/// and in the revise folder if `revise` is given. The schema is printed if no output file
+/// is given
+pub fn infer_schema(
+    original: &Path,
+    revise: Option<&Path>,
+    pattern: &glob::Pattern,
+    output: Option<&Path>,
+) {
+    let mut shape = Shape::default();
+    let mut count = 0;
+    for root in [Some(original), revise].into_iter().flatten() {
+        // Warnings would end up in the middle of the printed schema
+        let files = json_files(root, output.is_some()).unwrap_or_else(|err| err.throw());
+        for file in files {
+            if !pattern.matches_path_with(&file, GLOB_OPTIONS) {
+                continue;
+            }
+            let document =
+                json_from_path(root.join(&file).into()).unwrap_or_else(|err| err.throw());
+            shape.observe(&document);
+            count += 1;
+        }
+    }
+    let warnings = usize::from(count == 0);
+    if count == 0 {
+        let message = "has no JSON files matching the glob";
+        if output.is_some() {
+            report::emit(Event::Warning {
+                path: original,
+                message,
+            });
+        } else {
+            // Stdout only has the schema
+            eprintln!(
+                "{}",
+                format!("Warning: Path {} {}", original.display(), message).yellow()
+            );
+        }
+    }
+
+    let mut schema = shape.to_schema();
+    if let Value::Object(schema) = &mut schema {
+        schema.insert(
+            "$schema".to_string(),
+            Value::String("https://json-schema.org/draft/2020-12/schema".to_string()),
+        );
+    }
+    let mut text = serde_json::to_string_pretty(&schema).expect("JSON values always serialize");
+    text.push('\n');
+    let Some(output) = output else {
+        print!("{}", text);
+        return;
     };
+    write_file(&output.into(), text.as_bytes()).unwrap_or_else(|err| err.throw());
+    report::emit(Event::Summary {
+        command: "infer-schema",
+        profile: None,
+        processed: count,
+        up_to_date: 0,
+        warnings,
+        errors: 0,
+    });
+}
 
-    match file.write(include_bytes!("static/project.json")) {
-        Ok(_) => (),
-        Err(err) => err.attach_path(PathBuf::from(CONFIG_FILE).into()).throw(),
+/// Upgrades project.json to the current layout
+pub fn migrate_config() {
+    let path: FilePath = PathBuf::from(CONFIG_FILE).into();
+    let mut value = match json_from_path(path.clone()) {
+        Ok(it) => it,
+        Err(AppError::IoErrorPath(_)) => AppError::FileNotFound {
+            file_name: CONFIG_FILE,
+        }
+        .throw(),
+        Err(err) => err.throw(),
     };
+    let migrations = migrate(&mut value).unwrap_or_else(|err| err.throw());
+    if migrations.is_empty() {
+        println!("{} is already up to date", CONFIG_FILE);
+        return;
+    }
+
+    let config = ProjectConfig::from_value(value).unwrap_or_else(|err| err.throw());
+    let config =
+        serde_json::to_string_pretty(&config).expect("Correct steps taken to create config");
+    if let Err(err) = write_file(&path, config.as_bytes()) {
+        err.throw();
+    }
+    for migration in migrations {
+        println!("{} {}", "Migrated:".bright_green(), migration);
+    }
+}
+
+pub fn print_config_schema() {
+    println!("{}", CONFIG_SCHEMA);
 }
 
 pub fn print_help() {
     println!(
         r#"=== Help ===
 help - Brings up this help menu
+init - Create project.json, the project folders and a .gitignore by asking some questions
+init-default - Create the same with the default project.json
 build - Build the modified changes by applying the changes in the changes folder
     {0}
 update - Update the changes by modifying the changes folder - 
-    {0}"#,
+    {0}
+status - Show which files in the revise folder have changes not saved by update
+check - Check that every patch in the changes folder still applies
+optimize - Rewrite every patch into the smallest patch that gives the same result
+fmt [files...] - Rewrite patch files with one operation per line in a stable order
+migrate-config - Upgrade project.json from an older layout
+config-schema - Print the JSON Schema of project.json for editor autocompletion
+upstream-diff <old> <new> - Show what upstream added, removed and changed between two
+    versions of the original folder
+merge-driver <ancestor> <current> <other> [path] - Merge two versions of a patch file by
+    what they change, for a git merge driver called with %O %A %B %P
+textconv <patch-file> - Print a patch file as a readable list of what its operations
+    change, for a git diff textconv
+package - Build the project from scratch into a .tar.gz or .zip archive with a manifest.json
+impact <new-original> - List the patch operations that upstream changes in a new version
+    of the original folder break or that need a review
+codegen --lang <rust|typescript> - Print type definitions and constant tables for the
+    documents in the revise folder
+infer-schema [glob] - Print a JSON Schema of the files in the original folder matching the
+    glob, **/*.json by default
+
+Options:
+    --message-format <human|json> - Print events as JSON lines instead of text
+        (build, update, status, check, optimize, fmt and package)
+    --jobs <n> - Amount of files processed at the same time, defaults to the CPU count
+        (build, update and package)
+    --no-cache - Process every file, even the ones that did not change since the last run
+        (build and update)
+    --profile <name> - Build the profile of project.json into its own folder (build),
+        or package or generate types for the profile (package and codegen)
+    --all-profiles - Build every profile of project.json (build)
+    --guard - Test the original value before every replace and remove, so build fails
+        when upstream changed it instead of overwriting the change (update)
+    --name, --description, --version, --author, --license <value> - Project fields,
+        --author can be repeated (init)
+    --original, --changes, --revise <path> - Project folders (init)
+    --import <path> - Copy an existing JSON file or folder into the original folder (init)
+    --in-place - Link the original folder to the imported path instead of copying it (init)
+    --modified <path> - Generate the changes folder from a modified copy of the import (init)
+    --yes - Use the defaults for every field not given instead of asking (init)
+    --force - Overwrite an existing project.json (init and init-default)
+    --check - Only report patch files that are not formatted (fmt)
+    --format <terminal|markdown|json> - How the report is printed, defaults to terminal
+        (upstream-diff and impact)
+    --format <tar.gz|zip> - Archive format, defaults to the extension of --output or tar.gz
+        (package)
+    --output <path> - Path of the archive, defaults to <name>-<version>.tar.gz (package),
+        or file to write the code or schema into instead of printing it
+        (codegen and infer-schema)
+    --lang <rust|typescript> - Language of the generated code (codegen)
+    --schema <path> - Generate the types of a JSON Schema instead of the documents (codegen)
+    --with-revise - Also infer the schema from the files in the revise folder (infer-schema)"#,
         "WARNING: THIS PROCESS IS IRREVERSIBLE".red()
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merged(ancestor: &str, current: &str, other: &str) -> Result<(String, bool), String> {
+        let files = MergeFiles {
+            ancestor: PathBuf::from("ancestor"),
+            current: PathBuf::from("current"),
+            other: PathBuf::from("other"),
+            path: None,
+        };
+        merge_versions(&files, ancestor, current, other)
+    }
+
+    #[test]
+    fn merges_patches_without_their_path() {
+        let ours = r#"[{"op":"replace","path":"/a","value":{"$include":"b.json#/c"}}]"#;
+        let theirs = r#"[{"op":"replace","path":"/n","value":2}]"#;
+        let expected = "[\n  {\"op\":\"replace\",\"path\":\"/a\",\"value\":{\"$include\":\"b.json#/c\"}},\n  {\"op\":\"replace\",\"path\":\"/n\",\"value\":2}\n]\n";
+        assert_eq!(merged("[]", ours, theirs), Ok((expected.to_string(), true)));
+        // Added on both sides
+        assert_eq!(merged("", ours, theirs), Ok((expected.to_string(), true)));
+    }
+
+    #[test]
+    fn conflicts_are_marked() {
+        let (text, clean) = merged(
+            "",
+            r#"[{"op":"add","path":"/a","value":1}]"#,
+            r#"[{"op":"add","path":"/a","value":2}]"#,
+        )
+        .unwrap();
+        assert!(!clean);
+        assert_eq!(
+            text,
+            "[\n<<<<<<< ours\n  {\"op\":\"add\",\"path\":\"/a\",\"value\":1}\n=======\n  {\"op\":\"add\",\"path\":\"/a\",\"value\":2}\n>>>>>>> theirs\n]\n"
+        );
+    }
+
+    #[test]
+    fn rules_files_are_merged_as_documents() {
+        assert_eq!(
+            merged("", r#"{"a":1}"#, r#"{"b":2}"#),
+            Ok(("{\n  \"a\": 1,\n  \"b\": 2\n}\n".to_string(), true))
+        );
+        assert!(merged(r#"{"a":1}"#, r#"{"a":2}"#, r#"{"a":3}"#).is_err());
+        assert!(merged("[]", "[]", "not json").is_err());
+    }
+}
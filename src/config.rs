@@ -1,7 +1,28 @@
-use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, fs, path::Path};
+
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use serde_json::{Map, Value};
+
+use crate::{error::AppError, schema::SchemaMapping, transform::TransformStep, CONFIG_FILE};
+
+/// Version of the `project.json` layout this build understands.
+///
+/// Older layouts are upgraded with [`migrate`]
+pub const CONFIG_VERSION: u64 = 1;
+
+/// JSON Schema of `project.json` for editor autocompletion
+pub const CONFIG_SCHEMA: &str = include_str!("static/project.schema.json");
 
 #[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct ProjectConfig {
+    #[serde(rename = "$schema", default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<String>,
+    pub config_version: u64,
+
     pub name: String,
     pub description: String,
     pub version: String,
@@ -9,11 +30,465 @@ pub struct ProjectConfig {
 
     pub authors: Vec<String>,
     pub paths: ProjectPaths,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schemas: Vec<SchemaMapping>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transforms: Vec<TransformStep>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Profile>,
+
+    /// Values for the `${name}` placeholders of patch values
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variables: BTreeMap<String, String>,
+
+    /// Members that identify the objects of an array, tried in order when comparing
+    /// two upstream versions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub identity_keys: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "UpdateSettings::is_default")]
+    pub update: UpdateSettings,
 }
 
 #[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct ProjectPaths {
     pub original: String,
-    pub changes: String,
+    pub changes: ChangeLayers,
+    pub revise: String,
+}
+
+/// Changes folders applied one after another, lowest first.
+///
+/// Written as a single folder or a list of folders, `update` only writes into the last one
+#[derive(Clone)]
+pub struct ChangeLayers(Vec<String>);
+
+impl ChangeLayers {
+    pub fn single(folder: String) -> Self {
+        ChangeLayers(vec![folder])
+    }
+
+    /// The layer `update` writes to
+    pub fn top(&self) -> &str {
+        self.0.last().expect("There is always at least one layer")
+    }
+
+    /// Every layer below the top one, lowest first
+    pub fn lower(&self) -> &[String] {
+        &self.0[..self.0.len() - 1]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &String> {
+        self.0.iter()
+    }
+}
+
+impl Serialize for ChangeLayers {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0.as_slice() {
+            [folder] => serializer.serialize_str(folder),
+            layers => layers.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ChangeLayers {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct LayersVisitor;
+
+        impl<'de> Visitor<'de> for LayersVisitor {
+            type Value = ChangeLayers;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a folder or a list of folders")
+            }
+
+            fn visit_str<E: de::Error>(self, folder: &str) -> Result<Self::Value, E> {
+                Ok(ChangeLayers::single(folder.to_string()))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut layers = Vec::new();
+                while let Some(folder) = seq.next_element()? {
+                    layers.push(folder);
+                }
+                if layers.is_empty() {
+                    return Err(de::Error::invalid_length(0, &"at least one folder"));
+                }
+                Ok(ChangeLayers(layers))
+            }
+        }
+
+        deserializer.deserialize_any(LayersVisitor)
+    }
+}
+
+/// A variant of the build with its own output folder, built with `build --profile <name>`
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Change layers to apply, the ones of `paths.changes` if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changes: Option<ChangeLayers>,
+    /// Names of the transforms to run, every transform if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transforms: Option<Vec<String>>,
     pub revise: String,
 }
+
+/// What a single build applies and where it writes the output
+pub struct BuildVariant {
+    /// Name of the profile, `None` for the build of `paths`
+    pub profile: Option<String>,
+    pub changes: ChangeLayers,
+    pub transforms: Vec<TransformStep>,
+    pub revise: String,
+}
+
+/// Defaults for the options of `update`
+#[derive(Deserialize, Serialize, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateSettings {
+    /// Always write patches as if `--guard` was given
+    #[serde(default)]
+    pub guard: bool,
+}
+
+impl UpdateSettings {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// A loaded config and the migrations that had to be applied to it in memory
+pub struct LoadedConfig {
+    pub config: ProjectConfig,
+    pub migrations: Vec<&'static str>,
+}
+
+impl ProjectConfig {
+    pub fn load(path: &Path) -> Result<LoadedConfig, AppError<'static>> {
+        let buf = match fs::read_to_string(path) {
+            Ok(it) => it,
+            Err(_) => {
+                return Err(AppError::FileNotFound {
+                    file_name: CONFIG_FILE,
+                })
+            }
+        };
+        let mut value: Value =
+            serde_json::from_str(&buf).map_err(|err| AppError::InvalidConfig {
+                message: format!("it is not valid JSON, {}", err),
+            })?;
+        let migrations = migrate(&mut value)?;
+        let config = Self::from_value(value)?;
+        Ok(LoadedConfig { config, migrations })
+    }
+
+    /// The build of `paths` with every transform
+    pub fn default_variant(&self) -> BuildVariant {
+        BuildVariant {
+            profile: None,
+            changes: self.paths.changes.clone(),
+            transforms: self.transforms.clone(),
+            revise: self.paths.revise.clone(),
+        }
+    }
+
+    /// The build of a profile, its transforms keep the order of `transforms`
+    pub fn profile_variant(&self, name: &str) -> Result<BuildVariant, AppError<'static>> {
+        let Some(profile) = self.profiles.get(name) else {
+            return Err(AppError::InvalidOption {
+                option: "profile",
+                message: format!("there is no profile named `{}` in {}", name, CONFIG_FILE),
+            });
+        };
+        let transforms = match &profile.transforms {
+            None => self.transforms.clone(),
+            Some(names) => {
+                if let Some(unknown) = names
+                    .iter()
+                    .find(|name| !self.transforms.iter().any(|step| step.name == **name))
+                {
+                    return Err(AppError::InvalidConfig {
+                        message: format!(
+                            "profile `{}` uses the transform `{}`, which is not in `transforms`",
+                            name, unknown
+                        ),
+                    });
+                }
+                self.transforms
+                    .iter()
+                    .filter(|step| names.contains(&step.name))
+                    .cloned()
+                    .collect()
+            }
+        };
+        Ok(BuildVariant {
+            profile: Some(name.to_string()),
+            changes: profile
+                .changes
+                .clone()
+                .unwrap_or_else(|| self.paths.changes.clone()),
+            transforms,
+            revise: profile.revise.clone(),
+        })
+    }
+
+    /// Strictly deserializes an up to date config, pointing out the key that is wrong
+    pub fn from_value(value: Value) -> Result<Self, AppError<'static>> {
+        serde_path_to_error::deserialize(value).map_err(|err| {
+            let path = err.path().to_string();
+            let inner = err.into_inner().to_string();
+            let hint = suggestion(&inner)
+                .map(|suggestion| format!(", did you mean `{}`?", suggestion))
+                .unwrap_or_default();
+            AppError::InvalidConfig {
+                message: if path == "." {
+                    format!("{}{}", inner, hint)
+                } else {
+                    format!("at `{}`, {}{}", path, inner, hint)
+                },
+            }
+        })
+    }
+}
+
+/// Upgrades an older `project.json` layout to [`CONFIG_VERSION`].
+///
+/// Returns a description of every change that was made
+pub fn migrate(value: &mut Value) -> Result<Vec<&'static str>, AppError<'static>> {
+    let Some(object) = value.as_object_mut() else {
+        return Err(AppError::InvalidConfig {
+            message: "it has to be an object".to_string(),
+        });
+    };
+    let version = match object.get("config_version") {
+        None => 0,
+        Some(version) => version.as_u64().ok_or_else(|| AppError::InvalidConfig {
+            message: "`config_version` has to be a whole number".to_string(),
+        })?,
+    };
+    if version > CONFIG_VERSION {
+        return Err(AppError::InvalidConfig {
+            message: format!(
+                "config version {} is newer than the supported version {}, update json-revisor",
+                version, CONFIG_VERSION
+            ),
+        });
+    }
+
+    let mut migrations = Vec::new();
+    if version < 1 {
+        migrate_to_1(object, &mut migrations);
+    }
+    Ok(migrations)
+}
+
+/// Version 0 had no `config_version` and called the revise path `output`
+fn migrate_to_1(object: &mut Map<String, Value>, migrations: &mut Vec<&'static str>) {
+    if let Some(Value::Object(paths)) = object.get_mut("paths") {
+        if let Some(output) = paths.remove("output") {
+            if paths.contains_key("revise") {
+                migrations.push("dropped `paths.output` in favour of the existing `paths.revise`");
+            } else {
+                paths.insert("revise".to_string(), output);
+                migrations.push("renamed `paths.output` to `paths.revise`");
+            }
+        }
+    }
+    object.insert("config_version".to_string(), Value::from(1));
+    migrations.push("added `config_version`");
+}
+
+/// Finds the closest expected field for serde's "unknown field" errors
+fn suggestion(message: &str) -> Option<&str> {
+    let rest = message.strip_prefix("unknown field `")?;
+    let (unknown, expected) = rest.split_once('`')?;
+    expected
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .map(|field| (edit_distance(unknown, field), field))
+        .filter(|(distance, field)| *distance <= field.len() / 3 + 1)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, field)| field)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substituted = previous + usize::from(a != *b);
+            previous = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(previous + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// `version` has to be a semantic version such as `1.0.0`
+pub fn validate_version(version: &str) -> Result<(), String> {
+    semver::Version::parse(version)
+        .map(drop)
+        .map_err(|err| format!("`{}` is not a semantic version, {}", version, err))
+}
+
+/// `license` has to be an SPDX license expression such as `MIT OR Apache-2.0`
+pub fn validate_license(license: &str) -> Result<(), String> {
+    spdx::Expression::parse(license).map(drop).map_err(|err| {
+        format!(
+            "`{}` is not an SPDX license expression, {}",
+            license, err.reason
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn config_error<T>(result: Result<T, AppError>) -> String {
+        match result {
+            Err(AppError::InvalidConfig { message }) => message,
+            Err(_) => panic!("not an invalid config error"),
+            Ok(_) => panic!("the config is valid"),
+        }
+    }
+
+    fn valid<T>(result: Result<T, AppError>) -> T {
+        result.unwrap_or_else(|err| panic!("{}", err.message()))
+    }
+
+    fn config() -> Value {
+        json!({
+            "config_version": 1,
+            "name": "a",
+            "description": "",
+            "version": "1.0.0",
+            "license": "MIT",
+            "authors": [],
+            "paths": { "original": "original", "changes": "changes", "revise": "revise" },
+        })
+    }
+
+    #[test]
+    fn migrates_version_0() {
+        let mut value = config();
+        value.as_object_mut().unwrap().remove("config_version");
+        value["paths"] = json!({ "original": "original", "changes": "changes", "output": "out" });
+
+        let migrations = valid(migrate(&mut value));
+        assert_eq!(
+            migrations,
+            [
+                "renamed `paths.output` to `paths.revise`",
+                "added `config_version`"
+            ]
+        );
+        let config = valid(ProjectConfig::from_value(value));
+        assert_eq!(config.config_version, CONFIG_VERSION);
+        assert_eq!(config.paths.revise, "out");
+    }
+
+    #[test]
+    fn keeps_revise_over_output() {
+        let mut value = config();
+        value.as_object_mut().unwrap().remove("config_version");
+        value["paths"]["output"] = json!("out");
+
+        let migrations = valid(migrate(&mut value));
+        assert_eq!(
+            migrations,
+            [
+                "dropped `paths.output` in favour of the existing `paths.revise`",
+                "added `config_version`"
+            ]
+        );
+        let config = valid(ProjectConfig::from_value(value));
+        assert_eq!(config.paths.revise, "revise");
+    }
+
+    #[test]
+    fn current_version_is_left_alone() {
+        let mut value = config();
+        assert!(valid(migrate(&mut value)).is_empty());
+        assert_eq!(value, config());
+    }
+
+    #[test]
+    fn rejects_newer_and_invalid_versions() {
+        let mut value = config();
+        value["config_version"] = json!(CONFIG_VERSION + 1);
+        assert!(config_error(migrate(&mut value)).contains("is newer than the supported version"));
+
+        value["config_version"] = json!("1");
+        assert_eq!(
+            config_error(migrate(&mut value)),
+            "`config_version` has to be a whole number"
+        );
+        assert_eq!(
+            config_error(migrate(&mut json!([]))),
+            "it has to be an object"
+        );
+    }
+
+    #[test]
+    fn suggests_the_closest_key() {
+        let mut value = config();
+        value["paths"]["revsie"] = value["paths"]["revise"].take();
+        value["paths"].as_object_mut().unwrap().remove("revise");
+        let message = config_error(ProjectConfig::from_value(value));
+        assert!(
+            message.starts_with("at `paths.revsie`, unknown field `revsie`"),
+            "{}",
+            message
+        );
+        assert!(message.ends_with(", did you mean `revise`?"), "{}", message);
+
+        let mut value = config();
+        value["unrelated"] = json!(true);
+        let message = config_error(ProjectConfig::from_value(value));
+        assert!(!message.contains("did you mean"), "{}", message);
+    }
+
+    #[test]
+    fn suggestion_picks_the_nearest_expected_field() {
+        let message = "unknown field `identity_key`, expected one of `identity_keys`, `update`";
+        assert_eq!(suggestion(message), Some("identity_keys"));
+        assert_eq!(suggestion("unknown field `zzz`, expected `update`"), None);
+        assert_eq!(suggestion("missing field `name`"), None);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn change_layers() {
+        let mut value = config();
+        value["paths"]["changes"] = json!(["base", "local"]);
+        let config = valid(ProjectConfig::from_value(value));
+        assert_eq!(config.paths.changes.top(), "local");
+        assert_eq!(config.paths.changes.lower(), ["base"]);
+        assert_eq!(
+            serde_json::to_value(&config.paths.changes).unwrap(),
+            json!(["base", "local"])
+        );
+        assert_eq!(
+            serde_json::to_value(ChangeLayers::single("changes".to_string())).unwrap(),
+            json!("changes")
+        );
+
+        let mut value = self::config();
+        value["paths"]["changes"] = json!([]);
+        assert!(config_error(ProjectConfig::from_value(value)).starts_with("at `paths.changes`"));
+    }
+}
@@ -0,0 +1,326 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+};
+
+use json_patch::{CopyOperation, MoveOperation, Patch, PatchOperation, TestOperation};
+use serde_json::Value;
+
+/// Roughly how many values move detection may visit. Every move candidate is checked by
+/// patching a copy of the whole original, so bigger documents get fewer candidates and the
+/// biggest ones none
+const MOVE_BUDGET: usize = 20_000_000;
+
+/// Patch that turns `original` into `changed`.
+///
+/// Unlike [`json_patch::diff`] a subtree that was moved or copied becomes a `move` or
+/// `copy` instead of an `add` of the whole subtree. Subtrees are matched by their
+/// structural hash and every move is applied to the original to make sure the
+/// patch still gives `changed`
+pub fn diff(original: &Value, changed: &Value) -> Patch {
+    let mut patch = json_patch::diff(original, changed);
+    let mut checks = MOVE_BUDGET / (count_values(original) + patch.0.len());
+    while let Some(moved) = find_move(original, changed, &patch, &mut checks) {
+        patch = moved;
+    }
+    detect_copies(original, changed, &mut patch);
+    patch
+}
+
+/// Puts a `test` of the current value before every replace and remove, so applying the
+/// patch fails when upstream changed the value instead of silently overwriting it
+pub fn guard(original: &Value, patch: &Patch) -> Patch {
+    let mut guarded = Vec::with_capacity(patch.0.len() * 2);
+    let mut document = original.clone();
+    for op in &patch.0 {
+        let path = match op {
+            PatchOperation::Replace(op) => Some(&op.path),
+            PatchOperation::Remove(op) => Some(&op.path),
+            _ => None,
+        };
+        let guarded_already = matches!(
+            guarded.last(),
+            Some(PatchOperation::Test(test)) if Some(&test.path) == path
+        );
+        if let (Some(path), false) = (path, guarded_already) {
+            if let Some(value) = document.pointer(path) {
+                guarded.push(PatchOperation::Test(TestOperation {
+                    path: path.clone(),
+                    value: value.clone(),
+                }));
+            }
+        }
+        if json_patch::patch(&mut document, std::slice::from_ref(op)).is_err() {
+            // Not a patch of this original, there is nothing to guard
+            return patch.clone();
+        }
+        guarded.push(op.clone());
+    }
+    Patch(guarded)
+}
+
+/// Pairs an added value with an equal removed value and turns the pair into a `move`.
+///
+/// Gives up once `checks` whole patches were applied
+fn find_move(
+    original: &Value,
+    changed: &Value,
+    patch: &Patch,
+    checks: &mut usize,
+) -> Option<Patch> {
+    *checks = checks.checked_sub(1)?;
+    // What each remove takes out of the document at the time it is applied
+    let mut removed: HashMap<u64, Vec<(usize, Value)>> = HashMap::new();
+    let mut document = original.clone();
+    for (index, op) in patch.0.iter().enumerate() {
+        if let PatchOperation::Remove(remove) = op {
+            if let Some(value) = document.pointer(&remove.path) {
+                removed
+                    .entry(structural_hash(value))
+                    .or_default()
+                    .push((index, value.clone()));
+            }
+        }
+        if json_patch::patch(&mut document, std::slice::from_ref(op)).is_err() {
+            return None;
+        }
+    }
+
+    for (add_index, op) in patch.0.iter().enumerate() {
+        let PatchOperation::Add(add) = op else {
+            continue;
+        };
+        let Some(candidates) = removed.get(&structural_hash(&add.value)) else {
+            continue;
+        };
+        for (remove_index, value) in candidates {
+            if *value != add.value {
+                continue;
+            }
+            let PatchOperation::Remove(remove) = &patch.0[*remove_index] else {
+                unreachable!("Only removes are collected")
+            };
+            let operation = PatchOperation::Move(MoveOperation {
+                from: remove.path.clone(),
+                path: add.path.clone(),
+            });
+
+            // The move can take the place of either operation, depending on
+            // which one the operations in between rely on
+            for (keep, drop) in [(add_index, *remove_index), (*remove_index, add_index)] {
+                *checks = checks.checked_sub(1)?;
+                let mut operations = patch.0.clone();
+                operations[keep] = operation.clone();
+                operations.remove(drop);
+                let candidate = Patch(operations);
+                if gives(original, changed, &candidate) {
+                    return Some(candidate);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Turns adds of containers that are somewhere else in the document at that point of the
+/// patch into copies.
+///
+/// A copy of an equal value gives the same document as the add, so the patch is only
+/// walked once, and values moved there by an earlier operation are found too
+fn detect_copies(original: &Value, changed: &Value, patch: &mut Patch) {
+    let mut sources: HashMap<u64, Vec<String>> = HashMap::new();
+    for document in [original, changed] {
+        hash_tree(document, &mut String::new(), &mut |pointer, hash, value| {
+            if is_copyable(value) {
+                sources.entry(hash).or_default().push(pointer.to_string());
+            }
+        });
+    }
+
+    // The document as it is before each operation
+    let mut document = original.clone();
+    for op in &mut patch.0 {
+        let copy = match &*op {
+            PatchOperation::Add(add) if is_copyable(&add.value) => sources
+                .get(&structural_hash(&add.value))
+                .into_iter()
+                .flatten()
+                .filter(|pointer| {
+                    **pointer != add.path && document.pointer(pointer) == Some(&add.value)
+                })
+                // Sources that are still there in the changed document are the likely ones
+                .min_by_key(|pointer| changed.pointer(pointer) != Some(&add.value))
+                .map(|from| {
+                    PatchOperation::Copy(CopyOperation {
+                        from: from.clone(),
+                        path: add.path.clone(),
+                    })
+                }),
+            _ => None,
+        };
+        if let Some(copy) = copy {
+            *op = copy;
+        }
+        if json_patch::patch(&mut document, std::slice::from_ref(op)).is_err() {
+            return;
+        }
+    }
+}
+
+/// Scalars are equal by coincidence too often to say anything about where they came from
+fn is_copyable(value: &Value) -> bool {
+    match value {
+        Value::Array(array) => !array.is_empty(),
+        Value::Object(object) => !object.is_empty(),
+        _ => false,
+    }
+}
+
+fn gives(original: &Value, changed: &Value, patch: &Patch) -> bool {
+    let mut document = original.clone();
+    json_patch::patch(&mut document, &patch.0).is_ok() && document == *changed
+}
+
+/// How many values the document has, containers included
+fn count_values(value: &Value) -> usize {
+    1 + match value {
+        Value::Array(array) => array.iter().map(count_values).sum(),
+        Value::Object(object) => object.values().map(count_values).sum(),
+        _ => 0,
+    }
+}
+
+/// Hash of the value that only depends on its contents
+fn structural_hash(value: &Value) -> u64 {
+    hash_tree(value, &mut String::new(), &mut |_, _, _| ())
+}
+
+/// Hashes every subtree once, bottom up, and visits each with its pointer
+fn hash_tree(value: &Value, pointer: &mut String, visit: &mut dyn FnMut(&str, u64, &Value)) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    match value {
+        Value::Null => 0u8.hash(&mut hasher),
+        Value::Bool(bool) => (1u8, bool).hash(&mut hasher),
+        Value::Number(number) => (2u8, number.to_string()).hash(&mut hasher),
+        Value::String(string) => (3u8, string).hash(&mut hasher),
+        Value::Array(array) => {
+            (4u8, array.len()).hash(&mut hasher);
+            for (index, item) in array.iter().enumerate() {
+                let len = pointer.len();
+                pointer.push('/');
+                pointer.push_str(&index.to_string());
+                hash_tree(item, pointer, visit).hash(&mut hasher);
+                pointer.truncate(len);
+            }
+        }
+        Value::Object(object) => {
+            (5u8, object.len()).hash(&mut hasher);
+            for (key, item) in object {
+                let len = pointer.len();
+                pointer.push('/');
+                pointer.push_str(&key.replace('~', "~0").replace('/', "~1"));
+                key.hash(&mut hasher);
+                hash_tree(item, pointer, visit).hash(&mut hasher);
+                pointer.truncate(len);
+            }
+        }
+    }
+    let hash = hasher.finish();
+    visit(pointer, hash, value);
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{from_value, json};
+
+    use super::*;
+
+    /// Diffs the documents, checks the patch gives `changed` and returns it as JSON
+    fn diff_json(original: Value, changed: Value) -> Value {
+        let patch = diff(&original, &changed);
+        assert!(gives(&original, &changed, &patch), "{:?}", patch);
+        serde_json::to_value(patch).unwrap()
+    }
+
+    #[test]
+    fn moved_subtree_becomes_move() {
+        let patch = diff_json(
+            json!({ "a": { "x": [1, 2, 3] }, "b": 1 }),
+            json!({ "c": { "x": [1, 2, 3] }, "b": 1 }),
+        );
+        assert_eq!(patch, json!([{ "op": "move", "from": "/a", "path": "/c" }]));
+    }
+
+    #[test]
+    fn moved_array_item_becomes_move() {
+        let patch = diff_json(
+            json!({ "items": [{ "id": 1 }], "other": [] }),
+            json!({ "items": [], "other": [{ "id": 1 }] }),
+        );
+        assert_eq!(
+            patch,
+            json!([{ "op": "move", "from": "/items/0", "path": "/other/0" }])
+        );
+    }
+
+    #[test]
+    fn copied_subtree_becomes_copy() {
+        let patch = diff_json(
+            json!({ "a": { "x": [1, 2] } }),
+            json!({ "a": { "x": [1, 2] }, "b": { "x": [1, 2] } }),
+        );
+        assert_eq!(patch, json!([{ "op": "copy", "from": "/a", "path": "/b" }]));
+    }
+
+    #[test]
+    fn copy_from_moved_source() {
+        let patch = diff_json(
+            json!({ "a": { "x": [1, 2] } }),
+            json!({ "c": { "x": [1, 2] }, "d": { "x": [1, 2] } }),
+        );
+        assert_eq!(
+            patch,
+            json!([
+                { "op": "move", "from": "/a", "path": "/c" },
+                { "op": "copy", "from": "/c", "path": "/d" }
+            ])
+        );
+    }
+
+    #[test]
+    fn equal_scalars_stay_adds() {
+        let patch = diff_json(json!({ "a": 1 }), json!({ "a": 1, "b": 1 }));
+        assert_eq!(patch, json!([{ "op": "add", "path": "/b", "value": 1 }]));
+    }
+
+    #[test]
+    fn moves_stop_when_the_budget_is_used() {
+        let original = json!({ "a": { "x": [1, 2, 3] } });
+        let changed = json!({ "c": { "x": [1, 2, 3] } });
+        let patch = json_patch::diff(&original, &changed);
+        assert!(find_move(&original, &changed, &patch, &mut 0).is_none());
+        assert!(find_move(&original, &changed, &patch, &mut 10).is_some());
+    }
+
+    #[test]
+    fn guard_tests_replaced_and_removed_values() {
+        let original = json!({ "a": 1, "b": [1, 2] });
+        let patch: Patch = from_value(json!([
+            { "op": "replace", "path": "/a", "value": 2 },
+            { "op": "remove", "path": "/b/0" },
+            { "op": "add", "path": "/c", "value": 3 }
+        ]))
+        .unwrap();
+        assert_eq!(
+            serde_json::to_value(guard(&original, &patch)).unwrap(),
+            json!([
+                { "op": "test", "path": "/a", "value": 1 },
+                { "op": "replace", "path": "/a", "value": 2 },
+                { "op": "test", "path": "/b/0", "value": 1 },
+                { "op": "remove", "path": "/b/0" },
+                { "op": "add", "path": "/c", "value": 3 }
+            ])
+        );
+    }
+}
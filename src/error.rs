@@ -2,17 +2,20 @@ use std::{
     io::{self},
     path::Path,
     process::exit,
-    rc::Rc,
+    sync::Arc,
 };
 
-use colored::Colorize;
-
 use thiserror::Error;
 
-use crate::file_trio::InconsistentFileTypes;
+use crate::{
+    file_trio::InconsistentFileTypes,
+    report::{self, Event},
+    schema::Violation,
+    CONFIG_FILE,
+};
 
-// Make it easy to replace when threads are maybe needed
-pub type ErrorPath = Rc<Path>;
+// Shared between the worker threads of build and update
+pub type ErrorPath = Arc<Path>;
 
 pub enum AppError<'a> {
     IoError(io::Error),
@@ -36,12 +39,140 @@ pub enum AppError<'a> {
         target_file: ErrorPath,
         patch_file: ErrorPath,
     },
+    /// A guard of the patch failed
+    UpstreamChanged {
+        target_file: ErrorPath,
+        patch_file: ErrorPath,
+        operation: usize,
+        pointer: String,
+    },
     InconsistentFileTypes(InconsistentFileTypes),
+    InvalidConfig {
+        message: String,
+    },
+    InvalidOption {
+        option: &'a str,
+        message: String,
+    },
+    FileAlreadyExists {
+        file_path: ErrorPath,
+    },
+    Unformatted {
+        file_path: ErrorPath,
+    },
+    InvalidRules {
+        file_path: ErrorPath,
+        message: String,
+    },
+    RuleFailed {
+        target_file: ErrorPath,
+        rules_file: ErrorPath,
+        stage: &'static str,
+        rule: usize,
+        pointer: String,
+        reason: String,
+    },
+    TransformFailed {
+        file_path: ErrorPath,
+        transform: String,
+        message: String,
+    },
+    UndefinedVariable {
+        file_path: ErrorPath,
+        name: String,
+    },
+    InvalidReference {
+        file_path: ErrorPath,
+        reference: String,
+        message: String,
+    },
+    /// Patch files that include values of each other, the first file is repeated at the end
+    ReferenceCycle {
+        files: Vec<ErrorPath>,
+    },
+    SchemaViolations {
+        file_path: ErrorPath,
+        schema_path: ErrorPath,
+        violations: Vec<Violation>,
+    },
+    PackageFailed {
+        file_path: ErrorPath,
+        message: String,
+    },
 }
 
 impl AppError<'_> {
-    pub fn throw(self) -> ! {
-        let message: String = match self {
+    /// Stable identifier of the error kind for the JSON message format
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::IoError(_) => "io-error",
+            AppError::IoErrorPath(_) => "io-error",
+            AppError::UnexpectedArgumentSize { .. } => "unexpected-argument-size",
+            AppError::InvalidArgument { .. } => "invalid-argument",
+            AppError::InvalidFileFormat { .. } => "invalid-file-format",
+            AppError::FileNotFound { .. } => "file-not-found",
+            AppError::PatchError { .. } => "patch-error",
+            AppError::UpstreamChanged { .. } => "upstream-changed",
+            AppError::InconsistentFileTypes(_) => "inconsistent-file-types",
+            AppError::InvalidConfig { .. } => "invalid-config",
+            AppError::InvalidOption { .. } => "invalid-option",
+            AppError::FileAlreadyExists { .. } => "file-already-exists",
+            AppError::Unformatted { .. } => "unformatted",
+            AppError::InvalidRules { .. } => "invalid-rules",
+            AppError::RuleFailed { .. } => "rule-failed",
+            AppError::TransformFailed { .. } => "transform-failed",
+            AppError::UndefinedVariable { .. } => "undefined-variable",
+            AppError::InvalidReference { .. } => "invalid-reference",
+            AppError::ReferenceCycle { .. } => "reference-cycle",
+            AppError::SchemaViolations { .. } => "schema-violation",
+            AppError::PackageFailed { .. } => "package-failed",
+        }
+    }
+
+    /// Paths the error is about
+    pub fn paths(&self) -> Vec<&Path> {
+        match self {
+            AppError::IoErrorPath(err) => vec![&err.path],
+            AppError::InvalidFileFormat { file_path, .. } => vec![file_path],
+            AppError::FileNotFound { file_name } => vec![Path::new(file_name)],
+            AppError::PatchError {
+                target_file,
+                patch_file,
+            }
+            | AppError::UpstreamChanged {
+                target_file,
+                patch_file,
+                ..
+            } => vec![target_file, patch_file],
+            AppError::InconsistentFileTypes(err) => err.paths(),
+            AppError::InvalidConfig { .. } => vec![Path::new(CONFIG_FILE)],
+            AppError::FileAlreadyExists { file_path }
+            | AppError::Unformatted { file_path }
+            | AppError::InvalidRules { file_path, .. }
+            | AppError::TransformFailed { file_path, .. }
+            | AppError::UndefinedVariable { file_path, .. }
+            | AppError::InvalidReference { file_path, .. }
+            | AppError::PackageFailed { file_path, .. } => vec![file_path],
+            AppError::ReferenceCycle { files } => files.iter().map(|file| &**file).collect(),
+            AppError::RuleFailed {
+                target_file,
+                rules_file,
+                ..
+            } => vec![target_file, rules_file],
+            AppError::SchemaViolations {
+                file_path,
+                schema_path,
+                ..
+            } => vec![file_path, schema_path],
+            AppError::IoError(_)
+            | AppError::UnexpectedArgumentSize { .. }
+            | AppError::InvalidArgument { .. }
+            | AppError::InvalidOption { .. } => vec![],
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
             AppError::IoErrorPath(err) => err.to_string(),
             AppError::IoError(err) => err.to_string(),
             AppError::UnexpectedArgumentSize { expected, received } => format!(
@@ -63,14 +194,145 @@ impl AppError<'_> {
                 target_file,
                 patch_file,
             } => format!("Cannot patch file {:?} with {:?}", target_file, patch_file),
+            AppError::UpstreamChanged {
+                target_file,
+                patch_file,
+                operation,
+                pointer,
+            } => format!(
+                "File {:?} changed at \"{}\" since {:?} was made (test operation {} failed), \
+                 review the change before updating the patch",
+                target_file, pointer, patch_file, operation
+            ),
             AppError::InconsistentFileTypes(err) => err.to_string(),
+            AppError::InvalidConfig { message } => format!("Invalid {}, {}", CONFIG_FILE, message),
+            AppError::InvalidOption { option, message } => {
+                format!("Invalid value for --{}, {}", option, message)
+            }
+            AppError::FileAlreadyExists { file_path } => {
+                format!(
+                    "File {:?} already exists, use --force to overwrite it",
+                    file_path
+                )
+            }
+            AppError::Unformatted { file_path } => format!(
+                "Patch file {:?} is not formatted, run `json-revisor fmt`",
+                file_path
+            ),
+            AppError::InvalidRules { file_path, message } => {
+                format!("Rules file {:?} is invalid, {}", file_path, message)
+            }
+            AppError::RuleFailed {
+                target_file,
+                rules_file,
+                stage,
+                rule,
+                pointer,
+                reason,
+            } => format!(
+                "Cannot apply {} rule {} of {:?} to {:?} at \"{}\", {}",
+                stage, rule, rules_file, target_file, pointer, reason
+            ),
+            AppError::TransformFailed {
+                file_path,
+                transform,
+                message,
+            } => format!(
+                "Transform `{}` failed on {:?}, {}",
+                transform, file_path, message
+            ),
+            AppError::UndefinedVariable { file_path, name } => format!(
+                "Patch file {:?} uses `${{{}}}`, which is not defined",
+                file_path, name
+            ),
+            AppError::InvalidReference {
+                file_path,
+                reference,
+                message,
+            } => format!(
+                "Patch file {:?} includes `{}`, {}",
+                file_path, reference, message
+            ),
+            AppError::ReferenceCycle { files } => format!(
+                "Patch files include each other in a cycle, {}",
+                files
+                    .iter()
+                    .map(|file| format!("{:?}", file))
+                    .collect::<Vec<String>>()
+                    .join(" -> ")
+            ),
+            AppError::SchemaViolations {
+                file_path,
+                schema_path,
+                violations,
+            } => violations
+                .iter()
+                .map(|violation| schema_violation_message(file_path, schema_path, violation))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            AppError::PackageFailed { file_path, message } => {
+                format!(
+                    "Cannot package the project into {:?}, {}",
+                    file_path, message
+                )
+            }
+        }
+    }
+
+    /// Reports the error without exiting
+    pub fn report(&self) {
+        if let AppError::SchemaViolations {
+            file_path,
+            schema_path,
+            violations,
+        } = self
+        {
+            // Every violation is its own event so they can be told apart
+            for violation in violations {
+                report::emit(Event::Error {
+                    code: self.code(),
+                    message: &schema_violation_message(file_path, schema_path, violation),
+                    paths: self.paths(),
+                    pointer: Some(&violation.pointer),
+                    operation: violation.operation,
+                });
+            }
+            return;
+        }
+        let (pointer, operation) = match self {
+            AppError::UpstreamChanged {
+                operation, pointer, ..
+            } => (Some(pointer.as_str()), Some(*operation)),
+            AppError::RuleFailed { pointer, .. } => (Some(pointer.as_str()), None),
+            _ => (None, None),
         };
-        eprintln!("{}{}", "Error: ".red(), message.red());
+        report::emit(Event::Error {
+            code: self.code(),
+            message: &self.message(),
+            paths: self.paths(),
+            pointer,
+            operation,
+        });
+    }
+
+    pub fn throw(self) -> ! {
+        self.report();
         // panic!();
         exit(101);
     }
 }
 
+fn schema_violation_message(file_path: &Path, schema_path: &Path, violation: &Violation) -> String {
+    let cause = match violation.operation {
+        Some(operation) => format!("caused by patch operation {}", operation),
+        None => "not caused by a patch".to_string(),
+    };
+    format!(
+        "File {:?} does not match schema {:?} at \"{}\", {} ({})",
+        file_path, schema_path, violation.pointer, violation.message, cause
+    )
+}
+
 #[derive(Debug, Error)]
 #[error("{} at {:?}", error, path)]
 pub struct IoErrorWithPath {
@@ -78,7 +340,7 @@ pub struct IoErrorWithPath {
     path: ErrorPath,
 }
 
-impl<'a> IoErrorWithPath {
+impl IoErrorWithPath {
     pub fn new(error: io::Error, path: ErrorPath) -> Self {
         Self { error, path }
     }
@@ -95,9 +357,7 @@ impl<T> UnwrapAppPathlessError<T> for io::Result<T> {
     fn unwrap_app(self: Result<T, io::Error>) -> T {
         match self {
             Ok(it) => it,
-            Err(err) => {
-                err.throw()
-            }
+            Err(err) => err.throw(),
         }
     }
 }
@@ -109,7 +369,7 @@ pub trait AppErrorIo {
 
 impl AppErrorIo for io::Error {
     fn attach_path(self: io::Error, path: ErrorPath) -> IoErrorWithPath {
-        return IoErrorWithPath::new(self, path);
+        IoErrorWithPath::new(self, path)
     }
     fn throw(self: io::Error) -> ! {
         AppError::IoError(self).throw()
@@ -3,7 +3,7 @@ use std::{
     fs::{read_dir, FileType},
     io::{self},
     path::{Component, Path, PathBuf},
-    rc::Rc,
+    sync::Arc,
     vec,
 };
 
@@ -21,18 +21,20 @@ pub fn get_file_trios(
     mut root: PathBuf,
     original: OsString,
     matches: OsString,
+    lower: &[OsString],
     result: OsString,
 ) -> Result<Vec<FilePathTrio>, FindFileTriosError> {
     root.push(original);
-    _get_file_trios(root, matches, result)
+    _get_file_trios(root, matches, lower, result)
 }
 
 fn _get_file_trios(
     path: PathBuf,
     matches: OsString,
+    lower: &[OsString],
     results: OsString,
 ) -> Result<Vec<FilePathTrio>, FindFileTriosError> {
-    let current_file = {
+    let mut current_file = {
         let matching_path =
             replace_item(&path, 1, &matches).expect("First index should always exist");
         let result_path =
@@ -43,7 +45,15 @@ fn _get_file_trios(
             result_path.into(),
         )
     }
-    .map_err(|err| FindFileTriosError::TrioInitError(err))?;
+    .map_err(FindFileTriosError::TrioInitError)?;
+    current_file.lower = lower
+        .iter()
+        .map(|layer| {
+            replace_item(&path, 1, layer)
+                .expect("First index should always exist")
+                .into()
+        })
+        .collect();
 
     if path.is_dir() {
         let mut res: Vec<FilePathTrio> = Vec::new();
@@ -62,7 +72,7 @@ fn _get_file_trios(
             })?;
             let mut path = path.clone();
             path.push(entry.file_name());
-            let mut pairs = _get_file_trios(path, matches.clone(), results.clone())?;
+            let mut pairs = _get_file_trios(path, matches.clone(), lower, results.clone())?;
             res.append(&mut pairs);
         }
         Ok(res)
@@ -94,7 +104,7 @@ pub fn replace_item(
 }
 
 // TODO: Not sure what to call this
-pub type FilePath = Rc<Path>;
+pub type FilePath = Arc<Path>;
 
 #[derive(Debug)]
 pub struct FilePathTrio {
@@ -102,9 +112,16 @@ pub struct FilePathTrio {
     pub original: FilePath,
     pub changes: FilePath,
     pub changed: FilePath,
+    /// Where the lower change layers would keep the patch of this file, lowest first
+    pub lower: Vec<FilePath>,
 }
 
 impl FilePathTrio {
+    /// Path of the file relative to the original, changes and revise folders
+    pub fn relative(&self) -> PathBuf {
+        self.original.components().skip(2).collect()
+    }
+
     fn new(
         original: FilePath,
         matching: FilePath,
@@ -112,7 +129,7 @@ impl FilePathTrio {
     ) -> Result<Self, TrioInitError> {
         let file_type = match original.metadata() {
             Ok(it) => it,
-            Err(err) => return Err(TrioInitError::IoError(err.attach_path(original.into()))),
+            Err(err) => return Err(TrioInitError::IoError(err.attach_path(original))),
         }
         .file_type();
 
@@ -123,9 +140,9 @@ impl FilePathTrio {
                     return Err(TrioInitError::InconsistentFileTypes(
                         InconsistentFileTypes {
                             file_type_a: file_type,
-                            path_a: original.into(),
+                            path_a: original,
                             file_type_b: matching_type,
-                            path_b: matching.into(),
+                            path_b: matching,
                         },
                     ));
                 }
@@ -140,9 +157,9 @@ impl FilePathTrio {
                     return Err(TrioInitError::InconsistentFileTypes(
                         InconsistentFileTypes {
                             file_type_a: file_type,
-                            path_a: original.into(),
+                            path_a: original,
                             file_type_b: result_type,
-                            path_b: result.into(),
+                            path_b: result,
                         },
                     ));
                 }
@@ -155,6 +172,7 @@ impl FilePathTrio {
             original,
             changes: matching,
             changed: result,
+            lower: Vec::new(),
         })
     }
 }
@@ -180,6 +198,12 @@ pub struct InconsistentFileTypes {
     path_b: ErrorPath,
 }
 
+impl InconsistentFileTypes {
+    pub fn paths(&self) -> Vec<&Path> {
+        vec![&self.path_a, &self.path_b]
+    }
+}
+
 #[derive(Debug, Error)]
 #[error("Index out of bounds")]
 pub struct IndexOutOfBoundsError;
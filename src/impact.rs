@@ -0,0 +1,612 @@
+use std::slice;
+
+use colored::{ColoredString, Colorize};
+use json_patch::{Patch, PatchOperation};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::upstream::{code, escape_token, identity, identity_label, preview, ReportFormat};
+
+/// How much attention an operation needs after upstream changed what it targets,
+/// most urgent first
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum Impact {
+    /// The operation fails or changes another value than it did
+    Broken,
+    /// The operation still applies, but upstream changed what it works on
+    NeedsReview,
+    /// The operation still has the same effect
+    Safe,
+}
+
+impl Impact {
+    fn label(self) -> &'static str {
+        match self {
+            Impact::Broken => "broken",
+            Impact::NeedsReview => "needs-review",
+            Impact::Safe => "safe",
+        }
+    }
+
+    fn colorize(self, text: String) -> ColoredString {
+        match self {
+            Impact::Broken => text.red(),
+            Impact::NeedsReview => text.yellow(),
+            Impact::Safe => text.green(),
+        }
+    }
+}
+
+/// An operation of a patch file whose target upstream modified, removed or shifted
+#[derive(Serialize)]
+pub struct AffectedOperation {
+    pub file: String,
+    /// Index of the operation in its patch file
+    pub index: usize,
+    pub op: &'static str,
+    pub path: String,
+    pub impact: Impact,
+    pub reason: String,
+    /// Where the target is in the new original when upstream moved it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_path: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ImpactReport {
+    pub operations: Vec<AffectedOperation>,
+}
+
+/// The patch of a change layer and the path it is reported under
+pub struct LayerPatch {
+    pub file: String,
+    pub patch: Patch,
+}
+
+/// What became of a pointer of the current original in the new one, with the
+/// pointer it has there
+enum Target {
+    Same {
+        pointer: String,
+    },
+    Modified {
+        pointer: String,
+        old: Value,
+        new: Value,
+    },
+    Shifted {
+        pointer: String,
+    },
+    /// Upstream removed the value at `pointer` of the current original, which is the
+    /// target or one of its parents
+    Removed {
+        pointer: String,
+    },
+    /// The target is created by the operation, but upstream now has a value there
+    AddedUpstream {
+        pointer: String,
+        new: Value,
+    },
+    /// The operation does not apply to the current original either
+    Missing,
+}
+
+impl Target {
+    fn pointer(&self) -> Option<&str> {
+        match self {
+            Target::Same { pointer }
+            | Target::Modified { pointer, .. }
+            | Target::Shifted { pointer }
+            | Target::AddedUpstream { pointer, .. } => Some(pointer),
+            Target::Removed { .. } | Target::Missing => None,
+        }
+    }
+}
+
+/// Every operation of the patches, applied in order, that upstream affected.
+///
+/// Both originals are patched along the way, so later operations are compared at the
+/// place earlier operations left them. Items of arrays are followed by the same
+/// identity keys as `upstream-diff`
+pub fn analyze(
+    original: &Value,
+    new: Option<&Value>,
+    patches: &[LayerPatch],
+    identity_keys: &[String],
+) -> Vec<AffectedOperation> {
+    let mut operations = Vec::new();
+    let Some(new) = new else {
+        for layer in patches {
+            for (index, op) in layer.patch.0.iter().enumerate() {
+                operations.push(AffectedOperation {
+                    file: layer.file.clone(),
+                    index,
+                    op: op_name(op),
+                    path: op_path(op).to_string(),
+                    impact: Impact::Broken,
+                    reason: "upstream removed the file".to_string(),
+                    new_path: None,
+                });
+            }
+        }
+        return operations;
+    };
+
+    let mut current = original.clone();
+    let mut updated = new.clone();
+    for layer in patches {
+        for (index, op) in layer.patch.0.iter().enumerate() {
+            let (finding, rebased) = assess(op, &current, &updated, identity_keys);
+            if let Some((impact, reason, new_path)) = finding {
+                operations.push(AffectedOperation {
+                    file: layer.file.clone(),
+                    index,
+                    op: op_name(op),
+                    path: op_path(op).to_string(),
+                    impact,
+                    reason,
+                    new_path,
+                });
+            }
+            // Operations that fail are reported by `check`, the rest goes on without them
+            let _ = json_patch::patch(&mut current, slice::from_ref(op));
+            if let Some(rebased) = rebased {
+                let _ = json_patch::patch(&mut updated, slice::from_ref(&rebased));
+            }
+        }
+    }
+    operations
+}
+
+type Finding = (Impact, String, Option<String>);
+
+/// How upstream affected the operation, and the operation at the place its targets
+/// have in the new original
+fn assess(
+    op: &PatchOperation,
+    current: &Value,
+    updated: &Value,
+    identity_keys: &[String],
+) -> (Option<Finding>, Option<PatchOperation>) {
+    let path = op_path(op);
+    let target = locate(current, updated, path, identity_keys);
+    let source = op_from(op).map(|from| locate(current, updated, from, identity_keys));
+    let insertion = matches!(
+        op,
+        PatchOperation::Add(_) | PatchOperation::Move(_) | PatchOperation::Copy(_)
+    ) && parent_is_array(current, path);
+
+    let mut findings: Vec<Finding> = Vec::new();
+    match &target {
+        Target::Same { .. } | Target::Missing => (),
+        Target::Removed { pointer } if insertion && pointer == path => findings.push((
+            Impact::NeedsReview,
+            "upstream removed the item it is inserted before".to_string(),
+            None,
+        )),
+        Target::Removed { pointer } => findings.push((
+            Impact::Broken,
+            format!("upstream removed `{}`", pointer),
+            None,
+        )),
+        Target::Shifted { pointer } if insertion && parent(pointer) == parent(path) => findings
+            .push((
+                Impact::NeedsReview,
+                format!(
+                    "upstream moved the item it is inserted before to `{}`",
+                    pointer
+                ),
+                Some(pointer.clone()),
+            )),
+        Target::Shifted { pointer } => findings.push((
+            Impact::Broken,
+            format!("upstream moved it to `{}`", pointer),
+            Some(pointer.clone()),
+        )),
+        // Inserting does not depend on the value of the item after it
+        Target::Modified { .. } if insertion => (),
+        Target::Modified { old, new, .. } => findings.push(match op {
+            PatchOperation::Test(_) => (
+                Impact::Broken,
+                format!(
+                    "upstream changed the value it tests from {} to {}",
+                    preview(old),
+                    preview(new)
+                ),
+                None,
+            ),
+            // The change upstream made may be what the removal was meant to get rid of
+            PatchOperation::Remove(_) => (
+                Impact::NeedsReview,
+                format!(
+                    "upstream changed the value it removes from {} to {}",
+                    preview(old),
+                    preview(new)
+                ),
+                None,
+            ),
+            _ if op_value(op) == Some(new) => (
+                Impact::Safe,
+                "upstream now has the same value".to_string(),
+                None,
+            ),
+            _ => (
+                Impact::NeedsReview,
+                format!(
+                    "upstream changed the value it replaces from {} to {}",
+                    preview(old),
+                    preview(new)
+                ),
+                None,
+            ),
+        }),
+        Target::AddedUpstream { new, .. } if op_value(op) == Some(new) => findings.push((
+            Impact::Safe,
+            "upstream added the same value".to_string(),
+            None,
+        )),
+        Target::AddedUpstream { new, .. } => findings.push((
+            Impact::NeedsReview,
+            format!("upstream added {} at the same path", preview(new)),
+            None,
+        )),
+    }
+    match &source {
+        Some(Target::Removed { pointer }) => findings.push((
+            Impact::Broken,
+            format!("upstream removed `{}`", pointer),
+            None,
+        )),
+        Some(Target::Shifted { pointer }) => findings.push((
+            Impact::Broken,
+            format!("upstream moved the value it takes to `{}`", pointer),
+            None,
+        )),
+        Some(Target::Modified { old, new, .. }) => findings.push((
+            Impact::NeedsReview,
+            format!(
+                "upstream changed the value it takes from {} to {}",
+                preview(old),
+                preview(new)
+            ),
+            None,
+        )),
+        _ => (),
+    }
+
+    let rebased = target.pointer().and_then(|pointer| {
+        let from = match &source {
+            Some(source) => Some(source.pointer()?),
+            None => None,
+        };
+        Some(rebase(op, pointer, from))
+    });
+    (
+        findings.into_iter().min_by_key(|finding| finding.0),
+        rebased,
+    )
+}
+
+/// Follows the pointer through the current original and the same values of the new one
+fn locate(current: &Value, updated: &Value, pointer: &str, identity_keys: &[String]) -> Target {
+    if !pointer.is_empty() && !pointer.starts_with('/') {
+        return Target::Missing;
+    }
+    let tokens: Vec<String> = pointer.split('/').skip(1).map(unescape_token).collect();
+    let (mut old, mut new) = (current, updated);
+    let mut old_pointer = String::new();
+    let mut new_pointer = String::new();
+    let mut shifted = false;
+    for (position, token) in tokens.iter().enumerate() {
+        let last = position + 1 == tokens.len();
+        old_pointer = format!("{}/{}", old_pointer, escape_token(token));
+        match old {
+            Value::Object(old_members) => {
+                let new_value = new.as_object().and_then(|members| members.get(token));
+                let Some(old_value) = old_members.get(token) else {
+                    if !last {
+                        return Target::Missing;
+                    }
+                    let pointer = format!("{}/{}", new_pointer, escape_token(token));
+                    return match new_value {
+                        Some(new) => Target::AddedUpstream {
+                            pointer,
+                            new: new.clone(),
+                        },
+                        None if shifted => Target::Shifted { pointer },
+                        None => Target::Same { pointer },
+                    };
+                };
+                let Some(new_value) = new_value else {
+                    return Target::Removed {
+                        pointer: old_pointer,
+                    };
+                };
+                new_pointer = format!("{}/{}", new_pointer, escape_token(token));
+                old = old_value;
+                new = new_value;
+            }
+            Value::Array(old_items) => {
+                let Some(new_items) = new.as_array() else {
+                    return Target::Removed {
+                        pointer: old_pointer,
+                    };
+                };
+                // Appending stays appending
+                if last && (token == "-" || token.parse() == Ok(old_items.len())) {
+                    let end = if token == "-" {
+                        token.clone()
+                    } else {
+                        new_items.len().to_string()
+                    };
+                    let pointer = format!("{}/{}", new_pointer, end);
+                    return if shifted {
+                        Target::Shifted { pointer }
+                    } else {
+                        Target::Same { pointer }
+                    };
+                }
+                let Some(index) = token
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|&index| index < old_items.len())
+                else {
+                    return Target::Missing;
+                };
+                let new_index = match identity(old_items, new_items, identity_keys) {
+                    Some(key) => {
+                        let label = identity_label(&old_items[index][key]);
+                        new_items
+                            .iter()
+                            .position(|item| identity_label(&item[key]) == label)
+                    }
+                    None => Some(index).filter(|&index| index < new_items.len()),
+                };
+                let Some(new_index) = new_index else {
+                    return Target::Removed {
+                        pointer: old_pointer,
+                    };
+                };
+                shifted |= new_index != index;
+                new_pointer = format!("{}/{}", new_pointer, new_index);
+                old = &old_items[index];
+                new = &new_items[new_index];
+            }
+            _ => return Target::Missing,
+        }
+    }
+
+    if shifted {
+        Target::Shifted {
+            pointer: new_pointer,
+        }
+    } else if old != new {
+        Target::Modified {
+            pointer: new_pointer,
+            old: old.clone(),
+            new: new.clone(),
+        }
+    } else {
+        Target::Same {
+            pointer: new_pointer,
+        }
+    }
+}
+
+fn parent(pointer: &str) -> &str {
+    pointer.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+fn parent_is_array(document: &Value, pointer: &str) -> bool {
+    !pointer.is_empty()
+        && document
+            .pointer(parent(pointer))
+            .is_some_and(Value::is_array)
+}
+
+fn unescape_token(token: &str) -> String {
+    token.replace("~1", "/").replace("~0", "~")
+}
+
+/// The operation with its pointers replaced by the ones of the new original
+fn rebase(op: &PatchOperation, path: &str, from: Option<&str>) -> PatchOperation {
+    let mut op = op.clone();
+    match &mut op {
+        PatchOperation::Add(op) => op.path = path.to_string(),
+        PatchOperation::Remove(op) => op.path = path.to_string(),
+        PatchOperation::Replace(op) => op.path = path.to_string(),
+        PatchOperation::Test(op) => op.path = path.to_string(),
+        PatchOperation::Move(op) => {
+            op.path = path.to_string();
+            op.from = from.unwrap_or(&op.from).to_string();
+        }
+        PatchOperation::Copy(op) => {
+            op.path = path.to_string();
+            op.from = from.unwrap_or(&op.from).to_string();
+        }
+    }
+    op
+}
+
+fn op_name(op: &PatchOperation) -> &'static str {
+    match op {
+        PatchOperation::Add(_) => "add",
+        PatchOperation::Remove(_) => "remove",
+        PatchOperation::Replace(_) => "replace",
+        PatchOperation::Move(_) => "move",
+        PatchOperation::Copy(_) => "copy",
+        PatchOperation::Test(_) => "test",
+    }
+}
+
+fn op_path(op: &PatchOperation) -> &str {
+    match op {
+        PatchOperation::Add(op) => &op.path,
+        PatchOperation::Remove(op) => &op.path,
+        PatchOperation::Replace(op) => &op.path,
+        PatchOperation::Move(op) => &op.path,
+        PatchOperation::Copy(op) => &op.path,
+        PatchOperation::Test(op) => &op.path,
+    }
+}
+
+fn op_from(op: &PatchOperation) -> Option<&str> {
+    match op {
+        PatchOperation::Move(op) => Some(&op.from),
+        PatchOperation::Copy(op) => Some(&op.from),
+        _ => None,
+    }
+}
+
+fn op_value(op: &PatchOperation) -> Option<&Value> {
+    match op {
+        PatchOperation::Add(op) => Some(&op.value),
+        PatchOperation::Replace(op) => Some(&op.value),
+        _ => None,
+    }
+}
+
+impl ImpactReport {
+    /// Orders the operations so the most urgent come first
+    pub fn new(mut operations: Vec<AffectedOperation>) -> Self {
+        operations.sort_by_key(|operation| operation.impact);
+        ImpactReport { operations }
+    }
+
+    fn summary(&self) -> String {
+        let count = |impact| {
+            self.operations
+                .iter()
+                .filter(|operation| operation.impact == impact)
+                .count()
+        };
+        format!(
+            "{} operations affected, {} broken, {} need review, {} safe",
+            self.operations.len(),
+            count(Impact::Broken),
+            count(Impact::NeedsReview),
+            count(Impact::Safe)
+        )
+    }
+
+    pub fn print(&self, format: ReportFormat) {
+        match format {
+            ReportFormat::Terminal => self.print_terminal(),
+            ReportFormat::Markdown => print!("{}", self.markdown()),
+            ReportFormat::Json => println!(
+                "{}",
+                serde_json::to_string_pretty(self).expect("Reports always serialize")
+            ),
+        }
+    }
+
+    fn print_terminal(&self) {
+        if self.operations.is_empty() {
+            println!(
+                "{}",
+                "No patch operations are affected by upstream changes".bright_green()
+            );
+            return;
+        }
+        for operation in &self.operations {
+            let label = operation
+                .impact
+                .colorize(format!("{:>12}", operation.impact.label()));
+            println!(
+                "{} {} #{} {} {}",
+                label, operation.file, operation.index, operation.op, operation.path
+            );
+            println!("{:>12} {}", "", operation.reason.bright_black());
+        }
+        println!();
+        println!("{}", self.summary());
+    }
+
+    fn markdown(&self) -> String {
+        let mut text = String::from("# Impact of upstream changes\n\n");
+        if self.operations.is_empty() {
+            text.push_str("No patch operations are affected by upstream changes\n");
+            return text;
+        }
+        text.push_str(&format!("{}\n\n", self.summary()));
+        text.push_str("| Impact | File | Operation | Path | Reason |\n");
+        text.push_str("| --- | --- | --- | --- | --- |\n");
+        for operation in &self.operations {
+            text.push_str(&format!(
+                "| {} | {} | #{} {} | {} | {} |\n",
+                operation.impact.label(),
+                code(&operation.file),
+                operation.index,
+                operation.op,
+                code(&operation.path),
+                operation.reason.replace('|', "\\|")
+            ));
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{from_value, json};
+
+    use super::*;
+
+    fn affected(original: Value, new: Value, patch: Value) -> Vec<(Impact, String)> {
+        let patches = [LayerPatch {
+            file: "changes/a.patch.json".to_string(),
+            patch: from_value(patch).unwrap(),
+        }];
+        analyze(&original, Some(&new), &patches, &["id".to_string()])
+            .into_iter()
+            .map(|operation| (operation.impact, operation.reason))
+            .collect()
+    }
+
+    #[test]
+    fn remove_of_a_modified_value_needs_review() {
+        let operations = affected(
+            json!({ "a": 1, "b": 1 }),
+            json!({ "a": 2, "b": 1 }),
+            json!([{ "op": "remove", "path": "/a" }, { "op": "remove", "path": "/b" }]),
+        );
+        assert_eq!(operations.len(), 1);
+        assert_eq!(operations[0].0, Impact::NeedsReview);
+        assert_eq!(
+            operations[0].1,
+            "upstream changed the value it removes from 1 to 2"
+        );
+    }
+
+    #[test]
+    fn replace_and_test_of_a_modified_value() {
+        let operations = affected(
+            json!({ "a": 1, "b": 1, "c": 1 }),
+            json!({ "a": 2, "b": 2, "c": 2 }),
+            json!([
+                { "op": "replace", "path": "/a", "value": 3 },
+                { "op": "replace", "path": "/b", "value": 2 },
+                { "op": "test", "path": "/c", "value": 1 },
+            ]),
+        );
+        let impacts: Vec<Impact> = operations.iter().map(|operation| operation.0).collect();
+        assert_eq!(impacts, [Impact::NeedsReview, Impact::Safe, Impact::Broken]);
+    }
+
+    #[test]
+    fn removed_and_moved_items() {
+        let operations = affected(
+            json!({ "items": [{ "id": "x", "v": 1 }, { "id": "y", "v": 1 }], "gone": 1 }),
+            json!({ "items": [{ "id": "y", "v": 1 }] }),
+            json!([
+                { "op": "replace", "path": "/items/1/v", "value": 2 },
+                { "op": "replace", "path": "/gone", "value": 2 },
+            ]),
+        );
+        assert_eq!(operations[0].0, Impact::Broken);
+        assert_eq!(operations[0].1, "upstream moved it to `/items/0/v`");
+        assert_eq!(operations[1].0, Impact::Broken);
+        assert_eq!(operations[1].1, "upstream removed `/gone`");
+    }
+}
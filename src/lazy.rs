@@ -0,0 +1,477 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, Read},
+    mem,
+    path::Path,
+    sync::Arc,
+};
+
+use json_patch::{PatchErrorKind, PatchOperation};
+use serde::{ser::SerializeMap, ser::SerializeSeq, Serialize, Serializer};
+use serde_json::{value::RawValue, Value};
+use thiserror::Error;
+
+/// Files above this size are patched with [`LazyValue`] instead of a [`Value`]
+pub const LAZY_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+/// Raw subtrees smaller than this are turned into a [`Value`] as a whole when serializing,
+/// bigger ones are serialized one level at a time
+#[cfg(not(test))]
+const EXPAND_THRESHOLD: usize = 64 * 1024;
+/// Low enough for the small documents of the tests to take the same path as large ones
+#[cfg(test)]
+const EXPAND_THRESHOLD: usize = 16;
+
+/// A JSON document that is only parsed where it is needed.
+///
+/// Subtrees are kept as slices of the document's text until a patch operation
+/// points into them, which takes a fraction of the memory a fully parsed [`Value`] takes.
+/// Serializing gives the exact same output as serializing the equivalent [`Value`].
+///
+/// This is lazy parsing, not streaming: the whole text stays in memory, since the raw
+/// subtrees are slices of it, so peak memory is at least the size of the file.
+/// It avoids the parsed [`Value`], which takes several times the size of the text
+#[derive(Clone, Debug)]
+pub enum LazyValue {
+    Raw(RawSlice),
+    Object(BTreeMap<String, LazyValue>),
+    Array(Vec<LazyValue>),
+    Value(Value),
+}
+
+/// Unparsed JSON text, all slices of a document share the same text
+#[derive(Clone, Debug)]
+pub struct RawSlice {
+    source: Arc<String>,
+    start: usize,
+    end: usize,
+}
+
+impl RawSlice {
+    pub fn get(&self) -> &str {
+        &self.source[self.start..self.end]
+    }
+
+    /// Slice of a value that was parsed out of this slice's text
+    fn child(&self, child: &RawValue) -> RawSlice {
+        let start = child.get().as_ptr() as usize - self.source.as_ptr() as usize;
+        RawSlice {
+            source: self.source.clone(),
+            start,
+            end: start + child.get().len(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Operation '/{operation}' failed at path '{path}': {kind}")]
+pub struct LazyPatchError {
+    pub operation: usize,
+    pub path: String,
+    pub kind: PatchErrorKind,
+}
+
+impl LazyValue {
+    /// Reads the whole text and validates it, `size_hint` avoids growing the buffer while
+    /// reading
+    pub fn from_reader(mut reader: impl Read, size_hint: usize) -> serde_json::Result<Self> {
+        let mut text = String::with_capacity(size_hint);
+        reader
+            .read_to_string(&mut text)
+            .map_err(serde_json::Error::io)?;
+
+        let source = Arc::new(text);
+        let root = serde_json::from_str::<&RawValue>(&source)?;
+        let whole = RawSlice {
+            source: source.clone(),
+            start: 0,
+            end: source.len(),
+        };
+        Ok(LazyValue::Raw(whole.child(root)))
+    }
+
+    pub fn from_path(path: &Path) -> serde_json::Result<Self> {
+        let file = File::open(path).map_err(serde_json::Error::io)?;
+        let size = file.metadata().map_err(serde_json::Error::io)?.len();
+        Self::from_reader(BufReader::new(file), size as usize)
+    }
+
+    /// Parses the whole subtree
+    pub fn into_value(self) -> Value {
+        match self {
+            LazyValue::Raw(raw) => {
+                serde_json::from_str(raw.get()).expect("Raw values are always valid JSON")
+            }
+            LazyValue::Object(map) => Value::Object(
+                map.into_iter()
+                    .map(|(key, value)| (key, value.into_value()))
+                    .collect(),
+            ),
+            LazyValue::Array(vec) => {
+                Value::Array(vec.into_iter().map(LazyValue::into_value).collect())
+            }
+            LazyValue::Value(value) => value,
+        }
+    }
+
+    /// Parses one level of the node so its children can be accessed
+    fn expand(&mut self) {
+        let expanded = match self {
+            LazyValue::Raw(raw) => {
+                let text = raw.get();
+                match text.as_bytes().first() {
+                    Some(b'{') => LazyValue::Object(
+                        serde_json::from_str::<BTreeMap<String, &RawValue>>(text)
+                            .expect("Raw values are always valid JSON")
+                            .into_iter()
+                            .map(|(key, value)| (key, LazyValue::Raw(raw.child(value))))
+                            .collect(),
+                    ),
+                    Some(b'[') => LazyValue::Array(
+                        serde_json::from_str::<Vec<&RawValue>>(text)
+                            .expect("Raw values are always valid JSON")
+                            .into_iter()
+                            .map(|value| LazyValue::Raw(raw.child(value)))
+                            .collect(),
+                    ),
+                    _ => LazyValue::Value(
+                        serde_json::from_str(text).expect("Raw values are always valid JSON"),
+                    ),
+                }
+            }
+            LazyValue::Value(Value::Object(map)) => LazyValue::Object(
+                mem::take(map)
+                    .into_iter()
+                    .map(|(key, value)| (key, LazyValue::Value(value)))
+                    .collect(),
+            ),
+            LazyValue::Value(Value::Array(vec)) => {
+                LazyValue::Array(mem::take(vec).into_iter().map(LazyValue::Value).collect())
+            }
+            _ => return,
+        };
+        *self = expanded;
+    }
+
+    /// Same as [`Value::pointer_mut`], expanding every node on the way
+    fn pointer_mut(&mut self, pointer: &str) -> Option<&mut LazyValue> {
+        if pointer.is_empty() {
+            return Some(self);
+        }
+        if !pointer.starts_with('/') {
+            return None;
+        }
+        pointer
+            .split('/')
+            .skip(1)
+            .map(unescape)
+            .try_fold(self, |target, token| {
+                target.expand();
+                match target {
+                    LazyValue::Object(map) => map.get_mut(&token),
+                    LazyValue::Array(vec) => parse_index(&token, vec.len())
+                        .ok()
+                        .and_then(move |index| vec.get_mut(index)),
+                    _ => None,
+                }
+            })
+    }
+}
+
+impl Serialize for LazyValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            LazyValue::Raw(raw) => RawRef(raw.get()).serialize(serializer),
+            LazyValue::Object(map) => {
+                let mut serializer = serializer.serialize_map(Some(map.len()))?;
+                for (key, value) in map {
+                    serializer.serialize_entry(key, value)?;
+                }
+                serializer.end()
+            }
+            LazyValue::Array(vec) => {
+                let mut serializer = serializer.serialize_seq(Some(vec.len()))?;
+                for value in vec {
+                    serializer.serialize_element(value)?;
+                }
+                serializer.end()
+            }
+            LazyValue::Value(value) => value.serialize(serializer),
+        }
+    }
+}
+
+/// Serializes raw text the same way its [`Value`] would be, one level at a time.
+///
+/// Children borrow from the parent's text, so nothing is copied
+struct RawRef<'a>(&'a str);
+
+impl Serialize for RawRef<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let text = self.0;
+        match text.as_bytes().first() {
+            Some(b'{') if text.len() >= EXPAND_THRESHOLD => {
+                let map = serde_json::from_str::<BTreeMap<String, &RawValue>>(text)
+                    .expect("Raw values are always valid JSON");
+                let mut serializer = serializer.serialize_map(Some(map.len()))?;
+                for (key, value) in map {
+                    serializer.serialize_entry(&key, &RawRef(value.get()))?;
+                }
+                serializer.end()
+            }
+            Some(b'[') if text.len() >= EXPAND_THRESHOLD => {
+                let vec = serde_json::from_str::<Vec<&RawValue>>(text)
+                    .expect("Raw values are always valid JSON");
+                let mut serializer = serializer.serialize_seq(Some(vec.len()))?;
+                for value in vec {
+                    serializer.serialize_element(&RawRef(value.get()))?;
+                }
+                serializer.end()
+            }
+            _ => serde_json::from_str::<Value>(text)
+                .expect("Raw values are always valid JSON")
+                .serialize(serializer),
+        }
+    }
+}
+
+/// Same as [`json_patch::patch`], except that a failed patch leaves the document half patched
+pub fn patch(doc: &mut LazyValue, patch: &[PatchOperation]) -> Result<(), LazyPatchError> {
+    for (operation, op) in patch.iter().enumerate() {
+        let (path, result) = match op {
+            PatchOperation::Add(op) => (
+                &op.path,
+                add(doc, &op.path, LazyValue::Value(op.value.clone())),
+            ),
+            PatchOperation::Remove(op) => (&op.path, remove(doc, &op.path).map(drop)),
+            PatchOperation::Replace(op) => (&op.path, replace(doc, &op.path, op.value.clone())),
+            PatchOperation::Move(op) => (&op.path, mov(doc, &op.from, &op.path)),
+            PatchOperation::Copy(op) => (&op.path, copy(doc, &op.from, &op.path)),
+            PatchOperation::Test(op) => (&op.path, test(doc, &op.path, &op.value)),
+        };
+        result.map_err(|kind| LazyPatchError {
+            operation,
+            path: path.clone(),
+            kind,
+        })?;
+    }
+    Ok(())
+}
+
+fn add(doc: &mut LazyValue, path: &str, value: LazyValue) -> Result<(), PatchErrorKind> {
+    if path.is_empty() {
+        *doc = value;
+        return Ok(());
+    }
+
+    let (parent, last) = split_pointer(path)?;
+    let parent = doc
+        .pointer_mut(parent)
+        .ok_or(PatchErrorKind::InvalidPointer)?;
+    parent.expand();
+
+    match parent {
+        LazyValue::Object(map) => {
+            map.insert(unescape(last), value);
+            Ok(())
+        }
+        LazyValue::Array(vec) if last == "-" => {
+            vec.push(value);
+            Ok(())
+        }
+        LazyValue::Array(vec) => {
+            let index = parse_index(last, vec.len() + 1)?;
+            vec.insert(index, value);
+            Ok(())
+        }
+        _ => Err(PatchErrorKind::InvalidPointer),
+    }
+}
+
+fn remove(doc: &mut LazyValue, path: &str) -> Result<LazyValue, PatchErrorKind> {
+    let (parent, last) = split_pointer(path)?;
+    let parent = doc
+        .pointer_mut(parent)
+        .ok_or(PatchErrorKind::InvalidPointer)?;
+    parent.expand();
+
+    match parent {
+        LazyValue::Object(map) => map
+            .remove(&unescape(last))
+            .ok_or(PatchErrorKind::InvalidPointer),
+        LazyValue::Array(vec) => {
+            let index = parse_index(last, vec.len())?;
+            Ok(vec.remove(index))
+        }
+        _ => Err(PatchErrorKind::InvalidPointer),
+    }
+}
+
+fn replace(doc: &mut LazyValue, path: &str, value: Value) -> Result<(), PatchErrorKind> {
+    let target = doc
+        .pointer_mut(path)
+        .ok_or(PatchErrorKind::InvalidPointer)?;
+    *target = LazyValue::Value(value);
+    Ok(())
+}
+
+fn mov(doc: &mut LazyValue, from: &str, path: &str) -> Result<(), PatchErrorKind> {
+    // Check we are not moving inside own child
+    if path.starts_with(from) && path[from.len()..].starts_with('/') {
+        return Err(PatchErrorKind::CannotMoveInsideItself);
+    }
+    let value = remove(doc, from).map_err(|err| match err {
+        PatchErrorKind::InvalidPointer => PatchErrorKind::InvalidFromPointer,
+        err => err,
+    })?;
+    add(doc, path, value)
+}
+
+fn copy(doc: &mut LazyValue, from: &str, path: &str) -> Result<(), PatchErrorKind> {
+    let source = doc
+        .pointer_mut(from)
+        .ok_or(PatchErrorKind::InvalidFromPointer)?
+        .clone();
+    add(doc, path, source)
+}
+
+fn test(doc: &mut LazyValue, path: &str, expected: &Value) -> Result<(), PatchErrorKind> {
+    let target = doc
+        .pointer_mut(path)
+        .ok_or(PatchErrorKind::InvalidPointer)?;
+    if target.clone().into_value() == *expected {
+        Ok(())
+    } else {
+        Err(PatchErrorKind::TestFailed)
+    }
+}
+
+fn split_pointer(pointer: &str) -> Result<(&str, &str), PatchErrorKind> {
+    pointer
+        .rfind('/')
+        .ok_or(PatchErrorKind::InvalidPointer)
+        .map(|index| (&pointer[0..index], &pointer[index + 1..]))
+}
+
+fn unescape(token: &str) -> String {
+    token.replace("~1", "/").replace("~0", "~")
+}
+
+fn parse_index(token: &str, len: usize) -> Result<usize, PatchErrorKind> {
+    // RFC 6901 prohibits leading zeroes in index
+    if (token.starts_with('0') && token.len() != 1) || token.starts_with('+') {
+        return Err(PatchErrorKind::InvalidPointer);
+    }
+    match token.parse::<usize>() {
+        Ok(index) if index < len => Ok(index),
+        _ => Err(PatchErrorKind::InvalidPointer),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use json_patch::Patch;
+    use serde_json::{from_value, json};
+
+    use super::*;
+
+    /// Big enough for every container to be above the lowered [`EXPAND_THRESHOLD`], with
+    /// keys out of order, escapes and numbers that are not written the way serde writes them
+    const DOCUMENT: &str = r#"{
+        "zeta": {"b": [1, 2.50, 1e3, -0], "a": "café \"quoted\""},
+        "actions": [
+            {"name": "Give", "tags": ["items", "player"], "cost": 1.5},
+            {"name": "Heal", "tags": [], "extra": {"deep": {"deeper": [null, true]}}},
+            {"name": "Damage", "tags": ["entity"]}
+        ],
+        "a/b": {"~tilde": 1},
+        "empty": {}
+    }"#;
+
+    fn lazy_document() -> LazyValue {
+        LazyValue::from_reader(DOCUMENT.as_bytes(), DOCUMENT.len()).unwrap()
+    }
+
+    /// Patches the document both ways and checks they give the same output, or fail at the
+    /// same operation
+    fn assert_same(operations: Value) {
+        let operations_patch: Patch = from_value(operations.clone()).unwrap();
+        let mut lazy = lazy_document();
+        let mut value: Value = serde_json::from_str(DOCUMENT).unwrap();
+        let lazy_result = patch(&mut lazy, &operations_patch.0);
+        let value_result = json_patch::patch(&mut value, &operations_patch);
+        match (lazy_result, value_result) {
+            (Ok(()), Ok(())) => {
+                assert_eq!(
+                    serde_json::to_string(&lazy).unwrap(),
+                    serde_json::to_string(&value).unwrap(),
+                    "{}",
+                    operations
+                );
+                assert_eq!(
+                    serde_json::to_string_pretty(&lazy).unwrap(),
+                    serde_json::to_string_pretty(&value).unwrap(),
+                    "{}",
+                    operations
+                );
+                assert_eq!(lazy.into_value(), value, "{}", operations);
+            }
+            (Err(lazy), Err(value)) => {
+                assert_eq!(lazy.operation, value.operation, "{}", operations);
+                assert_eq!(lazy.path, value.path, "{}", operations);
+            }
+            (lazy, value) => panic!("{}: lazy {:?}, value {:?}", operations, lazy, value),
+        }
+    }
+
+    #[test]
+    fn unpatched_document_serializes_like_value() {
+        assert_same(json!([]));
+    }
+
+    #[test]
+    fn add_remove_and_replace() {
+        assert_same(json!([
+            { "op": "add", "path": "/actions/1/tags/0", "value": "health" },
+            { "op": "add", "path": "/actions/-", "value": { "name": "Clear" } },
+            { "op": "add", "path": "/zeta/c", "value": [1, 2] },
+            { "op": "remove", "path": "/actions/0/cost" },
+            { "op": "remove", "path": "/actions/2" },
+            { "op": "replace", "path": "/actions/1/extra/deep/deeper/1", "value": false },
+            { "op": "replace", "path": "/a~1b/~0tilde", "value": 2 }
+        ]));
+    }
+
+    #[test]
+    fn move_copy_and_test() {
+        assert_same(json!([
+            { "op": "test", "path": "/zeta/b/2", "value": 1000.0 },
+            { "op": "move", "from": "/actions/1/extra", "path": "/extra" },
+            { "op": "copy", "from": "/actions/0/tags", "path": "/actions/2/tags/-" },
+            { "op": "move", "from": "/actions/0", "path": "/actions/-" },
+            { "op": "test", "path": "/actions/2/name", "value": "Give" }
+        ]));
+    }
+
+    #[test]
+    fn replace_root() {
+        assert_same(json!([
+            { "op": "replace", "path": "", "value": { "new": true } },
+            { "op": "add", "path": "/other", "value": 1 }
+        ]));
+    }
+
+    #[test]
+    fn failures_stop_at_the_same_operation() {
+        assert_same(json!([
+            { "op": "remove", "path": "/actions/0/name" },
+            { "op": "test", "path": "/actions/1/name", "value": "Give" }
+        ]));
+        assert_same(json!([{ "op": "remove", "path": "/actions/3" }]));
+        assert_same(json!([{ "op": "add", "path": "/actions/01", "value": 1 }]));
+        assert_same(json!([{ "op": "replace", "path": "/missing/key", "value": 1 }]));
+        assert_same(json!([{ "op": "move", "from": "/actions", "path": "/actions/0" }]));
+        assert_same(json!([{ "op": "copy", "from": "/missing", "path": "/copy" }]));
+    }
+}
@@ -0,0 +1,29 @@
+use std::sync::Arc;
+
+pub mod archive;
+pub mod args;
+pub mod cache;
+pub mod codegen;
+pub mod commands;
+pub mod config;
+pub mod diff;
+pub mod error;
+pub mod file_trio;
+pub mod impact;
+pub mod lazy;
+pub mod merge;
+pub mod optimize;
+pub mod patch_file;
+pub mod pool;
+pub mod reference;
+pub mod report;
+pub mod rules;
+pub mod schema;
+pub mod shape;
+pub mod transform;
+pub mod upstream;
+pub mod variables;
+
+pub type ImmutableString = Arc<str>;
+
+pub const CONFIG_FILE: &str = "project.json";
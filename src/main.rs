@@ -1,62 +1,376 @@
 use std::{
-    env::{self, args},
+    env::args,
     ffi::OsString,
-    fs::File,
-    io::Read,
-    path::PathBuf,
-    sync::Arc,
+    path::{Path, PathBuf},
 };
 
-use config::ProjectConfig;
-use error::{AppError, AppErrorIo};
-
-use serde_json::from_str;
-
-pub mod commands;
-pub mod config;
-pub mod error;
-pub mod file_trio;
-
-pub type ImmutableString = Arc<str>;
-
-pub const CONFIG_FILE: &str = "project.json";
+use json_revisor::{
+    archive::ArchiveFormat,
+    args::Arguments,
+    codegen::Language,
+    commands::{self, CodegenSource, Import, InitOptions, MergeFiles, PackageOptions},
+    config::ProjectConfig,
+    error::AppError,
+    pool,
+    report::{self, Event, MessageFormat},
+    upstream::ReportFormat,
+    variables::Variables,
+    CONFIG_FILE,
+};
 
 fn main() {
     // env::set_var("RUST_BACKTRACE", "1");
-    let args: Vec<String> = args().collect();
+    let args = Arguments::parse(args());
+
+    if let Some(format) = args.value("message-format") {
+        match MessageFormat::from_name(format) {
+            Some(format) => report::set_message_format(format),
+            None => AppError::InvalidArgument {
+                argument_pos: args.position("message-format"),
+                message: "message format must be `human` or `json`",
+            }
+            .throw(),
+        }
+    }
 
-    if args.len() != 2 {
+    let Some(command) = args.command.as_deref() else {
         commands::print_help();
         AppError::UnexpectedArgumentSize {
             expected: 1,
-            received: args.len() - 1,
+            received: 0,
         }
         .throw();
-    }
+    };
 
-    match args
-        .get(1)
-        .expect("Args length has already been checked to be a length of 1")
-        .as_str()
-    {
-        "init" => commands::init(),
-        "init-default" => commands::init_default(),
+    match command {
+        "init" => {
+            args.expect_options(&[
+                "name",
+                "description",
+                "version",
+                "author",
+                "license",
+                "original",
+                "changes",
+                "revise",
+                "import",
+                "in-place",
+                "modified",
+                "yes",
+                "force",
+            ]);
+            if args.value("import").is_none() {
+                for option in ["in-place", "modified"] {
+                    if args.flag(option) {
+                        AppError::InvalidArgument {
+                            argument_pos: args.position(option),
+                            message: "can only be used together with --import",
+                        }
+                        .throw();
+                    }
+                }
+            }
+            args.expect_positional(0);
+            let value = |name| args.value(name).map(str::to_string);
+            commands::init(InitOptions {
+                name: value("name"),
+                description: value("description"),
+                version: value("version"),
+                authors: args
+                    .values("author")
+                    .into_iter()
+                    .map(str::to_string)
+                    .collect(),
+                license: value("license"),
+                original: value("original"),
+                changes: value("changes"),
+                revise: value("revise"),
+                import: value("import").map(|source| Import {
+                    source,
+                    in_place: args.flag("in-place"),
+                    modified: value("modified"),
+                }),
+                yes: args.flag("yes"),
+                force: args.flag("force"),
+            })
+        }
+        "init-default" => {
+            args.expect_options(&["force"]);
+            args.expect_positional(0);
+            commands::init_default(args.flag("force"))
+        }
         "build" => {
+            args.expect_options(&[
+                "message-format",
+                "jobs",
+                "no-cache",
+                "profile",
+                "all-profiles",
+            ]);
+            args.expect_positional(0);
             let config = get_config();
+            let variables = get_variables(&config);
+            let variants = if args.flag("all-profiles") {
+                if args.value("profile").is_some() {
+                    AppError::InvalidArgument {
+                        argument_pos: args.position("profile"),
+                        message: "--profile cannot be used together with --all-profiles",
+                    }
+                    .throw();
+                }
+                if config.profiles.is_empty() {
+                    AppError::InvalidArgument {
+                        argument_pos: args.position("all-profiles"),
+                        message: "project.json does not have any profiles",
+                    }
+                    .throw();
+                }
+                config
+                    .profiles
+                    .keys()
+                    .map(|name| config.profile_variant(name))
+                    .collect::<Result<Vec<_>, _>>()
+            } else {
+                match args.value("profile") {
+                    Some(name) => config.profile_variant(name).map(|variant| vec![variant]),
+                    None => Ok(vec![config.default_variant()]),
+                }
+            }
+            .unwrap_or_else(|err| err.throw());
             commands::build(
-                OsString::from(config.paths.original),
-                OsString::from(config.paths.changes),
-                OsString::from(config.paths.revise),
+                OsString::from(&config.paths.original),
+                &variants,
+                get_jobs(&args),
+                args.flag("no-cache"),
+                &config.schemas,
+                &variables,
             );
         }
         "update" => {
+            args.expect_options(&["message-format", "jobs", "no-cache", "guard"]);
+            args.expect_positional(0);
             let config = get_config();
+            let variables = get_variables(&config);
             commands::update(
+                OsString::from(&config.paths.original),
+                &config.default_variant(),
+                get_jobs(&args),
+                args.flag("no-cache"),
+                args.flag("guard") || config.update.guard,
+                &variables,
+            );
+        }
+        "status" => {
+            args.expect_options(&["message-format"]);
+            args.expect_positional(0);
+            let config = get_config();
+            let variables = get_variables(&config);
+            commands::status(
                 OsString::from(config.paths.original),
-                OsString::from(config.paths.changes),
+                &config.paths.changes,
                 OsString::from(config.paths.revise),
+                &config.transforms,
+                &variables,
+            );
+        }
+        "check" => {
+            args.expect_options(&["message-format"]);
+            args.expect_positional(0);
+            let config = get_config();
+            let variables = get_variables(&config);
+            commands::check(
+                OsString::from(config.paths.original),
+                &config.paths.changes,
+                OsString::from(config.paths.revise),
+                &config.schemas,
+                &config.transforms,
+                &variables,
+            );
+        }
+        "optimize" => {
+            args.expect_options(&["message-format"]);
+            args.expect_positional(0);
+            let config = get_config();
+            let variables = get_variables(&config);
+            commands::optimize(
+                OsString::from(config.paths.original),
+                &config.paths.changes,
+                OsString::from(config.paths.revise),
+                &variables,
+            );
+        }
+        "fmt" => {
+            args.expect_options(&["message-format", "check"]);
+            let config = get_config();
+            commands::fmt(
+                &config.paths.changes,
+                args.positional.clone(),
+                args.flag("check"),
+            );
+        }
+        "upstream-diff" => {
+            args.expect_options(&["format"]);
+            args.expect_positional(2);
+            let format = get_report_format(&args);
+            // Works without a project, only the identity keys come from project.json
+            let identity_keys = if Path::new(CONFIG_FILE).exists() {
+                get_config().identity_keys
+            } else {
+                Vec::new()
+            };
+            commands::upstream_diff(
+                Path::new(&args.positional[0]),
+                Path::new(&args.positional[1]),
+                &identity_keys,
+                format,
             );
         }
+        "merge-driver" => {
+            args.expect_options(&[]);
+            if !(3..=4).contains(&args.positional.len()) {
+                args.expect_positional(4);
+            }
+            let file = |index: usize| PathBuf::from(&args.positional[index]);
+            commands::merge_driver(MergeFiles {
+                ancestor: file(0),
+                current: file(1),
+                other: file(2),
+                path: args.positional.get(3).map(PathBuf::from),
+            });
+        }
+        "textconv" => {
+            args.expect_options(&[]);
+            args.expect_positional(1);
+            commands::textconv(Path::new(&args.positional[0]));
+        }
+        "package" => {
+            args.expect_options(&["message-format", "jobs", "profile", "format", "output"]);
+            args.expect_positional(0);
+            let config = get_config();
+            let variables = get_variables(&config);
+            let output = args.value("output").map(str::to_string);
+            let format = match args.value("format") {
+                Some(name) => ArchiveFormat::from_name(name).unwrap_or_else(|| {
+                    AppError::InvalidOption {
+                        option: "format",
+                        message: "it has to be tar.gz or zip".to_string(),
+                    }
+                    .throw()
+                }),
+                None if output
+                    .as_ref()
+                    .is_some_and(|output| output.ends_with(".zip")) =>
+                {
+                    ArchiveFormat::Zip
+                }
+                None => ArchiveFormat::TarGz,
+            };
+            let variant = match args.value("profile") {
+                Some(name) => config.profile_variant(name),
+                None => Ok(config.default_variant()),
+            }
+            .unwrap_or_else(|err| err.throw());
+            commands::package(
+                &config,
+                PackageOptions {
+                    variant,
+                    format,
+                    output,
+                    jobs: get_jobs(&args),
+                },
+                &variables,
+            );
+        }
+        "impact" => {
+            args.expect_options(&["format"]);
+            args.expect_positional(1);
+            let format = get_report_format(&args);
+            let config = get_config();
+            let variables = get_variables(&config);
+            commands::impact(
+                OsString::from(&config.paths.original),
+                &config.paths.changes,
+                OsString::from(&config.paths.revise),
+                Path::new(&args.positional[0]),
+                &config.identity_keys,
+                &variables,
+                format,
+            );
+        }
+        "codegen" => {
+            args.expect_options(&["lang", "schema", "profile", "output"]);
+            args.expect_positional(0);
+            let Some(name) = args.value("lang") else {
+                AppError::InvalidArgument {
+                    argument_pos: 1,
+                    message: "codegen needs --lang rust or --lang typescript",
+                }
+                .throw();
+            };
+            let language = Language::from_name(name).unwrap_or_else(|| {
+                AppError::InvalidOption {
+                    option: "lang",
+                    message: "it has to be rust or typescript".to_string(),
+                }
+                .throw()
+            });
+            let output = args.value("output").map(Path::new);
+            match args.value("schema") {
+                // A schema is enough, no project needed
+                Some(schema) => commands::codegen(
+                    CodegenSource::Schema(PathBuf::from(schema)),
+                    language,
+                    output,
+                    &[],
+                ),
+                None => {
+                    let config = get_config();
+                    let variant = match args.value("profile") {
+                        Some(name) => config.profile_variant(name),
+                        None => Ok(config.default_variant()),
+                    }
+                    .unwrap_or_else(|err| err.throw());
+                    commands::codegen(
+                        CodegenSource::Built(variant),
+                        language,
+                        output,
+                        &config.identity_keys,
+                    );
+                }
+            }
+        }
+        "infer-schema" => {
+            args.expect_options(&["with-revise", "output"]);
+            if args.positional.len() > 1 {
+                args.expect_positional(1);
+            }
+            let config = get_config();
+            let pattern = args.positional.first().map_or("**/*.json", String::as_str);
+            let pattern = glob::Pattern::new(pattern).unwrap_or_else(|_| {
+                AppError::InvalidArgument {
+                    argument_pos: args.positional_position(0),
+                    message: "not a valid glob",
+                }
+                .throw()
+            });
+            commands::infer_schema(
+                Path::new(&config.paths.original),
+                args.flag("with-revise")
+                    .then(|| Path::new(&config.paths.revise)),
+                &pattern,
+                args.value("output").map(Path::new),
+            );
+        }
+        "migrate-config" => {
+            args.expect_options(&[]);
+            args.expect_positional(0);
+            commands::migrate_config();
+        }
+        "config-schema" => {
+            args.expect_options(&[]);
+            args.expect_positional(0);
+            commands::print_config_schema();
+        }
         "help" => {
             commands::print_help();
         }
@@ -71,28 +385,44 @@ fn main() {
     }
 }
 
-fn get_config() -> ProjectConfig {
-    let path = PathBuf::from("./project.json");
-    if let Ok(mut data) = File::open(&path) {
-        let mut buf = String::new();
-        match data.read_to_string(&mut buf) {
-            Ok(it) => it,
-            Err(err) => AppError::IoErrorPath(err.attach_path(path.into())).throw(),
-        };
-        
-        if let Ok(config) = from_str::<ProjectConfig>(&buf) {
-            config
-        } else {
-            AppError::InvalidFileFormat {
-                file_path: path.into(),
-                expected: "JSON format",
+fn get_jobs(args: &Arguments) -> usize {
+    match args.value("jobs") {
+        Some(jobs) => match jobs.parse::<usize>() {
+            Ok(jobs) if jobs > 0 => jobs,
+            _ => AppError::InvalidArgument {
+                argument_pos: args.position("jobs"),
+                message: "jobs must be a number above 0",
             }
-            .throw();
-        }
-    } else {
-        AppError::FileNotFound {
-            file_name: CONFIG_FILE,
-        }
-        .throw();
+            .throw(),
+        },
+        None => pool::default_jobs(),
+    }
+}
+
+fn get_report_format(args: &Arguments) -> ReportFormat {
+    match args.value("format") {
+        Some(name) => ReportFormat::from_name(name).unwrap_or_else(|| {
+            AppError::InvalidOption {
+                option: "format",
+                message: "it has to be terminal, markdown or json".to_string(),
+            }
+            .throw()
+        }),
+        None => ReportFormat::Terminal,
+    }
+}
+
+fn get_variables(config: &ProjectConfig) -> Variables {
+    Variables::new(config).unwrap_or_else(|err| err.throw())
+}
+
+fn get_config() -> ProjectConfig {
+    let loaded = ProjectConfig::load(Path::new(CONFIG_FILE)).unwrap_or_else(|err| err.throw());
+    if !loaded.migrations.is_empty() {
+        report::emit(Event::Warning {
+            path: Path::new(CONFIG_FILE),
+            message: "uses an older layout, run `json-revisor migrate-config` to upgrade it",
+        });
     }
+    loaded.config
 }
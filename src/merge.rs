@@ -0,0 +1,601 @@
+use std::collections::BTreeSet;
+
+use json_patch::{Patch, PatchOperation};
+use serde_json::{Map, Value};
+
+use crate::upstream::escape_token;
+
+/// Result of a three-way merge of JSON documents
+pub struct Merged {
+    /// The merge with our side of every conflict
+    pub ours: Value,
+    /// The merge with their side of every conflict
+    pub theirs: Value,
+    /// Pointers both sides changed in a different way
+    pub conflicts: Vec<String>,
+}
+
+/// Merges the changes both sides made to `base`.
+///
+/// Members of objects are merged one by one and arrays of the same length item by item.
+/// Items both sides only appended to an array are all kept, ours first. Anything else
+/// both sides changed differently is a conflict
+pub fn merge(base: &Value, ours: &Value, theirs: &Value) -> Merged {
+    let mut conflicts = Vec::new();
+    let (ours, theirs) = merge_at(Some(base), Some(ours), Some(theirs), "", &mut conflicts);
+    Merged {
+        ours: ours.unwrap_or(Value::Null),
+        theirs: theirs.unwrap_or(Value::Null),
+        conflicts,
+    }
+}
+
+/// Both resolutions of the value, `None` where it is removed
+fn merge_at(
+    base: Option<&Value>,
+    ours: Option<&Value>,
+    theirs: Option<&Value>,
+    pointer: &str,
+    conflicts: &mut Vec<String>,
+) -> (Option<Value>, Option<Value>) {
+    if ours == theirs || theirs == base {
+        return (ours.cloned(), ours.cloned());
+    }
+    if ours == base {
+        return (theirs.cloned(), theirs.cloned());
+    }
+
+    match (base, ours, theirs) {
+        (_, Some(Value::Object(ours)), Some(Value::Object(theirs))) => {
+            let empty = Map::new();
+            let base = match base {
+                Some(Value::Object(base)) => base,
+                _ => &empty,
+            };
+            let keys: BTreeSet<&String> = base
+                .keys()
+                .chain(ours.keys())
+                .chain(theirs.keys())
+                .collect();
+            let mut merged_ours = Map::new();
+            let mut merged_theirs = Map::new();
+            for key in keys {
+                let (merged, other) = merge_at(
+                    base.get(key),
+                    ours.get(key),
+                    theirs.get(key),
+                    &format!("{}/{}", pointer, escape_token(key)),
+                    conflicts,
+                );
+                if let Some(merged) = merged {
+                    merged_ours.insert(key.clone(), merged);
+                }
+                if let Some(other) = other {
+                    merged_theirs.insert(key.clone(), other);
+                }
+            }
+            (
+                Some(Value::Object(merged_ours)),
+                Some(Value::Object(merged_theirs)),
+            )
+        }
+        (Some(Value::Array(base)), Some(Value::Array(ours)), Some(Value::Array(theirs)))
+            if base.len() == ours.len() && base.len() == theirs.len() =>
+        {
+            let mut merged_ours = Vec::new();
+            let mut merged_theirs = Vec::new();
+            for (index, base) in base.iter().enumerate() {
+                let (merged, other) = merge_at(
+                    Some(base),
+                    Some(&ours[index]),
+                    Some(&theirs[index]),
+                    &format!("{}/{}", pointer, index),
+                    conflicts,
+                );
+                merged_ours.extend(merged);
+                merged_theirs.extend(other);
+            }
+            (
+                Some(Value::Array(merged_ours)),
+                Some(Value::Array(merged_theirs)),
+            )
+        }
+        (Some(Value::Array(base)), Some(Value::Array(ours)), Some(Value::Array(theirs)))
+            if ours.starts_with(base) && theirs.starts_with(base) =>
+        {
+            let mut merged = ours.clone();
+            merged.extend_from_slice(&theirs[base.len()..]);
+            (
+                Some(Value::Array(merged.clone())),
+                Some(Value::Array(merged)),
+            )
+        }
+        _ => {
+            conflicts.push(pointer.to_string());
+            (ours.cloned(), theirs.cloned())
+        }
+    }
+}
+
+/// Result of a three-way merge of patches
+pub struct MergedPatch {
+    /// The merge with our side of every conflict
+    pub ours: Patch,
+    /// The merge with their side of every conflict
+    pub theirs: Patch,
+    /// Pointers both sides changed in a different way
+    pub conflicts: Vec<String>,
+}
+
+/// An operation one side added to or removed from the ancestor patch
+struct Change<'a> {
+    op: &'a PatchOperation,
+    /// Index of the ancestor operation it removes, or that it was added before
+    position: usize,
+    removed: bool,
+    /// Touches what a change of the other side touches, in another way
+    conflicting: bool,
+    /// The other side made the same change
+    shared: bool,
+}
+
+/// Merges the operations both sides added to or removed from the `ancestor` patch.
+///
+/// Operations only one side changed are taken from that side, in their place, and where
+/// both sides added operations at the same place ours come first. Changes of both sides
+/// to the same pointer, or one inside the other, are a conflict unless they are the same.
+/// So are changes to different items of the same array when one of them adds or removes
+/// items, except appends with `-`
+pub fn merge_patches(ancestor: &Patch, ours: &Patch, theirs: &Patch) -> MergedPatch {
+    let mut ours = changes(&ancestor.0, &ours.0);
+    let mut theirs = changes(&ancestor.0, &theirs.0);
+
+    for our in &mut ours {
+        if let Some(their) = theirs
+            .iter_mut()
+            .find(|their| !their.shared && their.op == our.op && their.removed == our.removed)
+        {
+            our.shared = true;
+            their.shared = true;
+        }
+    }
+    let mut conflicts = Vec::new();
+    for our in &mut ours {
+        for their in &mut theirs {
+            if !(our.shared && their.shared) && overlap(our.op, their.op) {
+                our.conflicting = true;
+                their.conflicting = true;
+                let pointer = touches(our.op)[0].pointer.to_string();
+                if !conflicts.contains(&pointer) {
+                    conflicts.push(pointer);
+                }
+            }
+        }
+    }
+
+    MergedPatch {
+        ours: combine(&ancestor.0, &ours, &theirs, false),
+        theirs: combine(&ancestor.0, &ours, &theirs, true),
+        conflicts,
+    }
+}
+
+/// What the side did to the ancestor, by the longest common subsequence of their operations
+fn changes<'a>(ancestor: &'a [PatchOperation], side: &'a [PatchOperation]) -> Vec<Change<'a>> {
+    let mut lengths = vec![vec![0usize; side.len() + 1]; ancestor.len() + 1];
+    for i in (0..ancestor.len()).rev() {
+        for j in (0..side.len()).rev() {
+            lengths[i][j] = if ancestor[i] == side[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let change = |op, position, removed| Change {
+        op,
+        position,
+        removed,
+        conflicting: false,
+        shared: false,
+    };
+    let mut changes = Vec::new();
+    let mut added = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < ancestor.len() || j < side.len() {
+        if i < ancestor.len() && j < side.len() && ancestor[i] == side[j] {
+            changes.extend(added.drain(..).map(|op| change(op, i, false)));
+            i += 1;
+            j += 1;
+        } else if j == side.len() || (i < ancestor.len() && lengths[i + 1][j] >= lengths[i][j + 1])
+        {
+            changes.push(change(&ancestor[i], i, true));
+            i += 1;
+        } else {
+            added.push(&side[j]);
+            j += 1;
+        }
+    }
+    changes.extend(added.drain(..).map(|op| change(op, ancestor.len(), false)));
+    changes
+}
+
+/// The ancestor with the changes of both sides, without the conflicting ones of ours for
+/// `theirs_wins` and else without the conflicting ones of theirs
+fn combine(
+    ancestor: &[PatchOperation],
+    ours: &[Change],
+    theirs: &[Change],
+    theirs_wins: bool,
+) -> Patch {
+    // Both sides agree on the changes they share
+    let applies = |change: &&Change, wins: bool| wins || change.shared || !change.conflicting;
+    let ours: Vec<&Change> = ours.iter().filter(|c| applies(c, !theirs_wins)).collect();
+    let theirs: Vec<&Change> = theirs.iter().filter(|c| applies(c, theirs_wins)).collect();
+
+    let mut operations = Vec::new();
+    for position in 0..=ancestor.len() {
+        let added = |change: &&&Change| !change.removed && change.position == position;
+        operations.extend(ours.iter().filter(added).map(|change| change.op.clone()));
+        operations.extend(
+            theirs
+                .iter()
+                .filter(added)
+                .filter(|change| !change.shared)
+                .map(|change| change.op.clone()),
+        );
+        let removed = ours
+            .iter()
+            .chain(&theirs)
+            .any(|change| change.removed && change.position == position);
+        if let (Some(op), false) = (ancestor.get(position), removed) {
+            operations.push(op.clone());
+        }
+    }
+    Patch(operations)
+}
+
+/// A pointer an operation reads or writes
+struct Touch<'a> {
+    pointer: &'a str,
+    /// Adds or removes an item there, which moves the items after it
+    shifts: bool,
+    /// Adds an item to the end of an array
+    appends: bool,
+}
+
+impl<'a> Touch<'a> {
+    fn new(pointer: &'a str, shifts: bool) -> Self {
+        Touch {
+            pointer,
+            shifts,
+            appends: false,
+        }
+    }
+
+    fn add(pointer: &'a str) -> Self {
+        let appends = pointer.ends_with("/-");
+        Touch {
+            pointer,
+            shifts: !appends,
+            appends,
+        }
+    }
+}
+
+fn touches(op: &PatchOperation) -> Vec<Touch<'_>> {
+    match op {
+        PatchOperation::Add(op) => vec![Touch::add(&op.path)],
+        PatchOperation::Remove(op) => vec![Touch::new(&op.path, true)],
+        PatchOperation::Replace(op) => vec![Touch::new(&op.path, false)],
+        PatchOperation::Move(op) => vec![Touch::add(&op.path), Touch::new(&op.from, true)],
+        PatchOperation::Copy(op) => vec![Touch::add(&op.path), Touch::new(&op.from, false)],
+        PatchOperation::Test(op) => vec![Touch::new(&op.path, false)],
+    }
+}
+
+/// Whether the operations depend on each other's values
+fn overlap(a: &PatchOperation, b: &PatchOperation) -> bool {
+    let is_index = |token: &str| {
+        token == "-" || (!token.is_empty() && token.bytes().all(|byte| byte.is_ascii_digit()))
+    };
+    touches(a).iter().any(|a| {
+        touches(b).iter().any(|b| {
+            if a.appends && b.appends {
+                return false;
+            }
+            let a_tokens: Vec<&str> = a.pointer.split('/').collect();
+            let b_tokens: Vec<&str> = b.pointer.split('/').collect();
+            let common = a_tokens
+                .iter()
+                .zip(&b_tokens)
+                .take_while(|(a, b)| a == b)
+                .count();
+            if common == a_tokens.len() || common == b_tokens.len() {
+                return true;
+            }
+            is_index(a_tokens[common])
+                && is_index(b_tokens[common])
+                && ((a.shifts && a_tokens.len() == common + 1)
+                    || (b.shifts && b_tokens.len() == common + 1))
+        })
+    })
+}
+
+const OURS_MARKER: &str = "<<<<<<< ours";
+const SEPARATOR: &str = "=======";
+const THEIRS_MARKER: &str = ">>>>>>> theirs";
+
+/// Both texts with conflict markers around only the lines they differ in
+pub fn conflict_lines(ours: &str, theirs: &str) -> String {
+    let ours: Vec<&str> = ours.lines().collect();
+    let theirs: Vec<&str> = theirs.lines().collect();
+    let prefix = ours.iter().zip(&theirs).take_while(|(a, b)| a == b).count();
+    let suffix = ours[prefix..]
+        .iter()
+        .rev()
+        .zip(theirs[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let ours_middle = &ours[prefix..ours.len() - suffix];
+    let theirs_middle = &theirs[prefix..theirs.len() - suffix];
+
+    // Longest common subsequence of the lines in between
+    let mut lengths = vec![vec![0usize; theirs_middle.len() + 1]; ours_middle.len() + 1];
+    for i in (0..ours_middle.len()).rev() {
+        for j in (0..theirs_middle.len()).rev() {
+            lengths[i][j] = if ours_middle[i] == theirs_middle[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines: Vec<&str> = ours[..prefix].to_vec();
+    let mut ours_block = Vec::new();
+    let mut theirs_block = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < ours_middle.len() || j < theirs_middle.len() {
+        if i < ours_middle.len() && j < theirs_middle.len() && ours_middle[i] == theirs_middle[j] {
+            push_conflict(&mut lines, &mut ours_block, &mut theirs_block);
+            lines.push(ours_middle[i]);
+            i += 1;
+            j += 1;
+        } else if j == theirs_middle.len()
+            || (i < ours_middle.len() && lengths[i + 1][j] >= lengths[i][j + 1])
+        {
+            ours_block.push(ours_middle[i]);
+            i += 1;
+        } else {
+            theirs_block.push(theirs_middle[j]);
+            j += 1;
+        }
+    }
+    push_conflict(&mut lines, &mut ours_block, &mut theirs_block);
+    lines.extend(&ours[ours.len() - suffix..]);
+
+    let mut text = lines.join("\n");
+    text.push('\n');
+    text
+}
+
+fn push_conflict<'a>(lines: &mut Vec<&'a str>, ours: &mut Vec<&'a str>, theirs: &mut Vec<&'a str>) {
+    if ours.is_empty() && theirs.is_empty() {
+        return;
+    }
+    lines.push(OURS_MARKER);
+    lines.append(ours);
+    lines.push(SEPARATOR);
+    lines.append(theirs);
+    lines.push(THEIRS_MARKER);
+}
+
+/// Both whole texts in a single conflict, for versions that cannot be merged by their content
+pub fn conflict_file(ours: &str, theirs: &str) -> String {
+    let lines = |text: &str| {
+        if text.is_empty() || text.ends_with('\n') {
+            text.to_string()
+        } else {
+            format!("{}\n", text)
+        }
+    };
+    format!(
+        "{}\n{}{}\n{}{}\n",
+        OURS_MARKER,
+        lines(ours),
+        SEPARATOR,
+        lines(theirs),
+        THEIRS_MARKER
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{from_value, json};
+
+    use super::*;
+
+    /// Both resolutions as JSON and the conflicts
+    fn merged_patches(ancestor: Value, ours: Value, theirs: Value) -> (Value, Value, Vec<String>) {
+        let patch = |value: Value| -> Patch { from_value(value).unwrap() };
+        let merged = merge_patches(&patch(ancestor), &patch(ours), &patch(theirs));
+        (
+            serde_json::to_value(merged.ours).unwrap(),
+            serde_json::to_value(merged.theirs).unwrap(),
+            merged.conflicts,
+        )
+    }
+
+    fn clean(ancestor: Value, ours: Value, theirs: Value) -> Value {
+        let (ours, theirs, conflicts) = merged_patches(ancestor, ours, theirs);
+        assert!(conflicts.is_empty(), "{:?}", conflicts);
+        assert_eq!(ours, theirs);
+        ours
+    }
+
+    #[test]
+    fn operations_of_both_sides_are_kept_as_written() {
+        let include =
+            json!({ "op": "replace", "path": "/a", "value": { "$include": "b.json#/c" } });
+        let moved = json!({ "op": "move", "from": "/x", "path": "/y" });
+        let test = json!({ "op": "test", "path": "/t", "value": 1 });
+        let replace = json!({ "op": "replace", "path": "/n", "value": 2 });
+        assert_eq!(
+            clean(
+                json!([moved, test]),
+                json!([moved, test, include]),
+                json!([replace, moved])
+            ),
+            json!([replace, moved, include])
+        );
+    }
+
+    #[test]
+    fn additions_at_the_same_place_put_ours_first() {
+        let op = |path: &str| json!({ "op": "add", "path": path, "value": 1 });
+        assert_eq!(
+            clean(
+                json!([op("/a"), op("/d")]),
+                json!([op("/a"), op("/b"), op("/d")]),
+                json!([op("/a"), op("/c"), op("/d")])
+            ),
+            json!([op("/a"), op("/b"), op("/c"), op("/d")])
+        );
+    }
+
+    #[test]
+    fn same_change_on_both_sides_is_kept_once() {
+        let old = json!({ "op": "replace", "path": "/a", "value": 1 });
+        let new = json!({ "op": "replace", "path": "/a", "value": 2 });
+        assert_eq!(
+            clean(json!([old]), json!([new]), json!([new])),
+            json!([new])
+        );
+        assert_eq!(clean(json!([old]), json!([]), json!([])), json!([]));
+    }
+
+    #[test]
+    fn different_changes_to_the_same_pointer_conflict() {
+        let value = |value: i32| json!({ "op": "replace", "path": "/a", "value": value });
+        assert_eq!(
+            merged_patches(json!([value(1)]), json!([value(2)]), json!([value(3)])),
+            (json!([value(2)]), json!([value(3)]), vec!["/a".to_string()])
+        );
+
+        // Removing an operation the other side changed
+        assert_eq!(
+            merged_patches(json!([value(1)]), json!([]), json!([value(3)])),
+            (json!([]), json!([value(3)]), vec!["/a".to_string()])
+        );
+
+        // A change inside a value the other side replaces
+        let inner = json!({ "op": "add", "path": "/a/b", "value": 1 });
+        let (_, _, conflicts) = merged_patches(json!([]), json!([inner]), json!([value(3)]));
+        assert_eq!(conflicts, ["/a/b"]);
+    }
+
+    #[test]
+    fn items_of_the_same_array() {
+        let replace = |path: &str| json!({ "op": "replace", "path": path, "value": 1 });
+        let append = |value: i32| json!({ "op": "add", "path": "/items/-", "value": value });
+        assert_eq!(
+            clean(
+                json!([]),
+                json!([replace("/items/0")]),
+                json!([replace("/items/2/x")])
+            ),
+            json!([replace("/items/0"), replace("/items/2/x")])
+        );
+        assert_eq!(
+            clean(json!([]), json!([append(1)]), json!([append(2)])),
+            json!([append(1), append(2)])
+        );
+
+        // Removing an item moves the ones after it
+        let remove = json!({ "op": "remove", "path": "/items/0" });
+        let (_, _, conflicts) =
+            merged_patches(json!([]), json!([remove]), json!([replace("/items/2/x")]));
+        assert_eq!(conflicts, ["/items/0"]);
+    }
+
+    #[test]
+    fn edits_to_different_members_are_both_kept() {
+        let merged = merge(
+            &json!({ "a": 1, "b": 1, "items": [1, 2] }),
+            &json!({ "a": 2, "b": 1, "items": [1, 2] }),
+            &json!({ "a": 1, "b": 3, "items": [1, 4] }),
+        );
+        assert!(merged.conflicts.is_empty());
+        assert_eq!(merged.ours, json!({ "a": 2, "b": 3, "items": [1, 4] }));
+        assert_eq!(merged.theirs, merged.ours);
+    }
+
+    #[test]
+    fn same_edit_on_both_sides_is_not_a_conflict() {
+        let merged = merge(
+            &json!({ "a": 1 }),
+            &json!({ "a": 2, "new": true }),
+            &json!({ "a": 2, "new": true }),
+        );
+        assert!(merged.conflicts.is_empty());
+        assert_eq!(merged.ours, json!({ "a": 2, "new": true }));
+    }
+
+    #[test]
+    fn different_edits_to_the_same_pointer_conflict() {
+        let merged = merge(
+            &json!({ "a": { "b": 1 }, "c": 1 }),
+            &json!({ "a": { "b": 2 }, "c": 2 }),
+            &json!({ "a": { "b": 3 }, "c": 1 }),
+        );
+        assert_eq!(merged.conflicts, ["/a/b"]);
+        assert_eq!(merged.ours, json!({ "a": { "b": 2 }, "c": 2 }));
+        assert_eq!(merged.theirs, json!({ "a": { "b": 3 }, "c": 2 }));
+    }
+
+    #[test]
+    fn remove_against_modify_conflicts() {
+        let merged = merge(
+            &json!({ "a": { "b": 1 } }),
+            &json!({}),
+            &json!({ "a": { "b": 2 } }),
+        );
+        assert_eq!(merged.conflicts, ["/a"]);
+        assert_eq!(merged.ours, json!({}));
+        assert_eq!(merged.theirs, json!({ "a": { "b": 2 } }));
+    }
+
+    #[test]
+    fn appends_on_both_sides_are_concatenated() {
+        let merged = merge(&json!([1]), &json!([1, 2]), &json!([1, 3]));
+        assert!(merged.conflicts.is_empty());
+        assert_eq!(merged.ours, json!([1, 2, 3]));
+
+        // Anything else that changes the length on both sides cannot be lined up
+        let merged = merge(&json!([1, 2]), &json!([2]), &json!([1, 2, 3]));
+        assert_eq!(merged.conflicts, [""]);
+    }
+
+    #[test]
+    fn conflict_markers_only_around_differing_lines() {
+        assert_eq!(
+            conflict_lines("a\nb\nc\nd\n", "a\nx\nc\nd\ny\n"),
+            "a\n<<<<<<< ours\nb\n=======\nx\n>>>>>>> theirs\nc\nd\n<<<<<<< ours\n=======\ny\n>>>>>>> theirs\n"
+        );
+        assert_eq!(conflict_lines("same\n", "same\n"), "same\n");
+    }
+
+    #[test]
+    fn whole_file_conflict() {
+        assert_eq!(
+            conflict_file("ours", "theirs\n"),
+            "<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\n"
+        );
+        assert_eq!(
+            conflict_file("", "theirs"),
+            "<<<<<<< ours\n=======\ntheirs\n>>>>>>> theirs\n"
+        );
+    }
+}
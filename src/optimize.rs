@@ -0,0 +1,436 @@
+use std::slice;
+
+use json_patch::{AddOperation, Patch, PatchError, PatchOperation, ReplaceOperation};
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::{diff, patch_file, reference::INCLUDE_KEY, schema::is_parent};
+
+#[derive(Debug, Error)]
+pub enum OptimizeError {
+    #[error("the patch does not apply to the original, {0}")]
+    PatchFailed(PatchError),
+    #[error("the optimized patch does not give the same result as the patch")]
+    NotEquivalent,
+}
+
+/// Rewrites the patch into the smallest equivalent patch for this original.
+///
+/// Operations that change nothing, writes that a later operation overwrites and
+/// members that are added and removed again are dropped, and a remove followed by an
+/// add of the same member becomes a replace. When the plain difference between the
+/// original and the patched document is smaller still, that is used instead, unless
+/// the patch has placeholders or references, which the difference would write out.
+///
+/// Both patches are applied to the original to prove they give the same result
+pub fn optimize(original: &Value, patch: &Patch) -> Result<Patch, OptimizeError> {
+    let mut expected = original.clone();
+    json_patch::patch(&mut expected, &patch.0).map_err(OptimizeError::PatchFailed)?;
+
+    let mut operations = patch.0.clone();
+    while simplify(original, &mut operations) {}
+    let mut optimized = Patch(operations);
+
+    // A difference has no test operations, so the guards of the patch would be lost
+    if !optimized.0.iter().any(|op| match op {
+        PatchOperation::Test(_) => true,
+        PatchOperation::Add(AddOperation { value, .. })
+        | PatchOperation::Replace(ReplaceOperation { value, .. }) => is_substituted(value),
+        _ => false,
+    }) {
+        let diff = diff::diff(original, &expected);
+        if size(&diff) < size(&optimized) {
+            optimized = diff;
+        }
+    }
+
+    let mut result = original.clone();
+    match json_patch::patch(&mut result, &optimized.0) {
+        Ok(()) if result == expected => Ok(optimized),
+        _ => Err(OptimizeError::NotEquivalent),
+    }
+}
+
+/// Whether the value has a `${` placeholder or a reference, which build fills in and so
+/// are not what the patch adds
+fn is_substituted(value: &Value) -> bool {
+    match value {
+        Value::String(string) => string.contains("${"),
+        Value::Array(items) => items.iter().any(is_substituted),
+        Value::Object(members) => {
+            members.contains_key(INCLUDE_KEY) || members.values().any(is_substituted)
+        }
+        _ => false,
+    }
+}
+
+/// Operation count first, then the length of the patch file
+fn size(patch: &Patch) -> (usize, usize) {
+    (patch.0.len(), patch_file::format(patch).len())
+}
+
+/// What an operation does to the document it is applied to
+struct Facts {
+    /// Applying it leaves the document as it is
+    no_op: bool,
+    /// Its path pointed at something before it was applied
+    existed: bool,
+    /// Its path only goes through objects, so no array index can shift under it
+    member: bool,
+}
+
+/// Applies the first rule that fits, returns false once no rule fits anymore
+fn simplify(original: &Value, operations: &mut Vec<PatchOperation>) -> bool {
+    let mut document = original.clone();
+    let mut facts = Vec::with_capacity(operations.len());
+    for op in operations.iter() {
+        let path = path(op);
+        facts.push(Facts {
+            no_op: is_no_op(&document, op),
+            existed: document.pointer(path).is_some(),
+            member: is_member(&document, path),
+        });
+        if json_patch::patch(&mut document, slice::from_ref(op)).is_err() {
+            // Only the final check can tell whether the rewritten patch is still right
+            return false;
+        }
+    }
+
+    if let Some(index) = facts.iter().position(|facts| facts.no_op) {
+        operations.remove(index);
+        return true;
+    }
+    match find_rewrite(operations, &facts) {
+        Some(Rewrite::Drop(i)) => {
+            operations.remove(i);
+        }
+        Some(Rewrite::DropBoth(i, j)) => {
+            operations.remove(j);
+            operations.remove(i);
+        }
+        Some(Rewrite::Merge(i, j, merged)) => {
+            operations[j] = merged;
+            operations.remove(i);
+        }
+        None => return false,
+    }
+    true
+}
+
+/// Operations to drop or merge, `i` always comes before `j`
+enum Rewrite {
+    Drop(usize),
+    DropBoth(usize, usize),
+    /// Drops `i` and puts the merged operation in place of `j`
+    Merge(usize, usize, PatchOperation),
+}
+
+/// Looks for a write to a member that the next operation touching it overwrites
+fn find_rewrite(operations: &[PatchOperation], facts: &[Facts]) -> Option<Rewrite> {
+    for (i, op) in operations.iter().enumerate() {
+        if !facts[i].member {
+            continue;
+        }
+        let written = match op {
+            PatchOperation::Add(_) | PatchOperation::Replace(_) | PatchOperation::Remove(_) => {
+                path(op)
+            }
+            _ => continue,
+        };
+
+        // The next operation that reads or writes the member, its parents or its children
+        let Some(j) = (i + 1..operations.len()).find(|&j| {
+            touched(&operations[j])
+                .iter()
+                .any(|touched| is_related(touched, written))
+        }) else {
+            continue;
+        };
+        let later = &operations[j];
+        let same = path(later) == written;
+        if !same && !is_parent(path(later), written) {
+            continue;
+        }
+
+        let rewrite = match (op, later) {
+            // Removed and added again is the same as replaced
+            (PatchOperation::Remove(_), PatchOperation::Add(add)) if same => Rewrite::Merge(
+                i,
+                j,
+                PatchOperation::Replace(ReplaceOperation {
+                    path: add.path.clone(),
+                    value: add.value.clone(),
+                }),
+            ),
+            (PatchOperation::Remove(_), _) => continue,
+            // Added and removed again is the same as never added
+            (_, PatchOperation::Remove(_)) if same && !facts[i].existed => Rewrite::DropBoth(i, j),
+            // The replace has nothing to replace once the add is gone
+            (_, PatchOperation::Replace(replace)) if same && !facts[i].existed => Rewrite::Merge(
+                i,
+                j,
+                PatchOperation::Add(AddOperation {
+                    path: replace.path.clone(),
+                    value: replace.value.clone(),
+                }),
+            ),
+            // Overwritten by the later operation before anything could see it
+            (
+                _,
+                PatchOperation::Add(_) | PatchOperation::Replace(_) | PatchOperation::Remove(_),
+            ) => Rewrite::Drop(i),
+            _ => continue,
+        };
+        return Some(rewrite);
+    }
+    None
+}
+
+fn path(op: &PatchOperation) -> &str {
+    match op {
+        PatchOperation::Add(op) => &op.path,
+        PatchOperation::Remove(op) => &op.path,
+        PatchOperation::Replace(op) => &op.path,
+        PatchOperation::Move(op) => &op.path,
+        PatchOperation::Copy(op) => &op.path,
+        PatchOperation::Test(op) => &op.path,
+    }
+}
+
+/// Every pointer the operation reads or writes
+fn touched(op: &PatchOperation) -> Vec<&str> {
+    match op {
+        PatchOperation::Move(op) => vec![&op.from, &op.path],
+        PatchOperation::Copy(op) => vec![&op.from, &op.path],
+        op => vec![path(op)],
+    }
+}
+
+fn is_no_op(document: &Value, op: &PatchOperation) -> bool {
+    match op {
+        PatchOperation::Add(op) => {
+            (op.path.is_empty() || is_member(document, &op.path))
+                && document.pointer(&op.path) == Some(&op.value)
+        }
+        PatchOperation::Replace(op) => document.pointer(&op.path) == Some(&op.value),
+        PatchOperation::Move(op) => op.from == op.path,
+        PatchOperation::Copy(op) => {
+            is_member(document, &op.path)
+                && document.pointer(&op.path).is_some()
+                && document.pointer(&op.path) == document.pointer(&op.from)
+        }
+        PatchOperation::Remove(_) | PatchOperation::Test(_) => false,
+    }
+}
+
+/// Whether every parent of the pointer is an object, adding to an array never overwrites
+fn is_member(document: &Value, pointer: &str) -> bool {
+    let Some(tokens) = pointer.strip_prefix('/') else {
+        return false;
+    };
+    let mut tokens: Vec<String> = tokens
+        .split('/')
+        .map(|token| token.replace("~1", "/").replace("~0", "~"))
+        .collect();
+    tokens.pop();
+
+    let mut current = document;
+    for token in tokens {
+        match current.get(&token) {
+            Some(child) if current.is_object() => current = child,
+            _ => return false,
+        }
+    }
+    current.is_object()
+}
+
+fn is_related(a: &str, b: &str) -> bool {
+    a == b || is_parent(a, b) || is_parent(b, a)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{from_value, json};
+
+    use super::*;
+
+    /// Optimizes the patch, checks the result is the same and the patch did not grow, and
+    /// returns it as JSON
+    fn optimized(original: Value, patch: Value) -> Value {
+        let patch: Patch = from_value(patch).unwrap();
+        let optimized = optimize(&original, &patch).unwrap();
+
+        let mut expected = original.clone();
+        json_patch::patch(&mut expected, &patch.0).unwrap();
+        let mut result = original.clone();
+        json_patch::patch(&mut result, &optimized.0).unwrap();
+        assert_eq!(result, expected);
+        assert!(size(&optimized) <= size(&patch), "{:?} grew", patch);
+        serde_json::to_value(optimized).unwrap()
+    }
+
+    /// Keeps the difference from taking over, so only the rewrite rules apply
+    fn guard() -> Value {
+        json!({ "op": "test", "path": "/guard", "value": true })
+    }
+
+    #[test]
+    fn drops_operations_that_change_nothing() {
+        assert_eq!(
+            optimized(
+                json!({ "guard": true, "a": 1 }),
+                json!([
+                    guard(),
+                    { "op": "replace", "path": "/a", "value": 1 },
+                    { "op": "add", "path": "/a", "value": 1 },
+                    { "op": "move", "from": "/a", "path": "/a" }
+                ])
+            ),
+            json!([guard()])
+        );
+    }
+
+    #[test]
+    fn remove_then_add_becomes_replace() {
+        assert_eq!(
+            optimized(
+                json!({ "guard": true, "a": 1 }),
+                json!([
+                    guard(),
+                    { "op": "remove", "path": "/a" },
+                    { "op": "add", "path": "/a", "value": 2 }
+                ])
+            ),
+            json!([guard(), { "op": "replace", "path": "/a", "value": 2 }])
+        );
+    }
+
+    #[test]
+    fn add_then_remove_disappears() {
+        assert_eq!(
+            optimized(
+                json!({ "guard": true }),
+                json!([
+                    guard(),
+                    { "op": "add", "path": "/a", "value": 1 },
+                    { "op": "remove", "path": "/a" }
+                ])
+            ),
+            json!([guard()])
+        );
+    }
+
+    #[test]
+    fn add_then_replace_becomes_add() {
+        assert_eq!(
+            optimized(
+                json!({ "guard": true }),
+                json!([
+                    guard(),
+                    { "op": "add", "path": "/a", "value": 1 },
+                    { "op": "replace", "path": "/a", "value": 2 }
+                ])
+            ),
+            json!([guard(), { "op": "add", "path": "/a", "value": 2 }])
+        );
+    }
+
+    #[test]
+    fn overwritten_writes_are_dropped() {
+        assert_eq!(
+            optimized(
+                json!({ "guard": true, "a": { "b": 1 } }),
+                json!([
+                    guard(),
+                    { "op": "replace", "path": "/a/b", "value": 2 },
+                    { "op": "replace", "path": "/a", "value": { "c": 3 } }
+                ])
+            ),
+            json!([guard(), { "op": "replace", "path": "/a", "value": { "c": 3 } }])
+        );
+    }
+
+    #[test]
+    fn writes_read_before_they_are_overwritten_stay() {
+        let patch = json!([
+            guard(),
+            { "op": "replace", "path": "/a", "value": 2 },
+            { "op": "copy", "from": "/a", "path": "/b" },
+            { "op": "replace", "path": "/a", "value": 3 }
+        ]);
+        assert_eq!(
+            optimized(json!({ "guard": true, "a": 1 }), patch.clone()),
+            patch
+        );
+    }
+
+    #[test]
+    fn array_items_are_left_to_the_difference() {
+        // Indexes shift, so the rules leave array items alone, the difference is smaller
+        assert_eq!(
+            optimized(
+                json!({ "items": [0] }),
+                json!([
+                    { "op": "add", "path": "/items/-", "value": 1 },
+                    { "op": "add", "path": "/items/0", "value": 2 },
+                    { "op": "remove", "path": "/items/0" },
+                    { "op": "remove", "path": "/items/1" }
+                ])
+            ),
+            json!([])
+        );
+    }
+
+    #[test]
+    fn difference_wins_when_smaller() {
+        assert_eq!(
+            optimized(
+                json!({ "a": { "x": [1, 2], "y": 1 } }),
+                json!([{ "op": "replace", "path": "/a", "value": { "x": [1, 2], "y": 2 } }])
+            ),
+            json!([{ "op": "replace", "path": "/a/y", "value": 2 }])
+        );
+        assert_eq!(
+            optimized(
+                json!({ "a": { "x": [1, 2] } }),
+                json!([
+                    { "op": "remove", "path": "/a" },
+                    { "op": "add", "path": "/b", "value": { "x": [1, 2] } }
+                ])
+            ),
+            json!([{ "op": "move", "from": "/a", "path": "/b" }])
+        );
+    }
+
+    #[test]
+    fn placeholders_and_references_are_not_written_out() {
+        let original = json!({ "a": { "b": {} }, "c": "" });
+        for patch in [
+            json!([{ "op": "replace", "path": "/a/b", "value": { "$include": "actions.json#/type" } }]),
+            json!([{ "op": "replace", "path": "/a/b", "value": { "x": "${project.version}" } }]),
+        ] {
+            assert_eq!(optimized(original.clone(), patch.clone()), patch);
+        }
+
+        // The rules still apply
+        assert_eq!(
+            optimized(
+                original,
+                json!([
+                    { "op": "replace", "path": "/c", "value": "a" },
+                    { "op": "replace", "path": "/c", "value": "${project.version}" }
+                ])
+            ),
+            json!([{ "op": "replace", "path": "/c", "value": "${project.version}" }])
+        );
+    }
+
+    #[test]
+    fn patch_that_does_not_apply_fails() {
+        let patch: Patch = from_value(json!([{ "op": "remove", "path": "/missing" }])).unwrap();
+        assert!(matches!(
+            optimize(&json!({}), &patch),
+            Err(OptimizeError::PatchFailed(_))
+        ));
+    }
+}
@@ -0,0 +1,219 @@
+use std::slice;
+
+use json_patch::{Patch, PatchOperation};
+use serde_json::Value;
+
+/// Order of the members of an operation, the same as the examples of RFC 6902
+const MEMBER_ORDER: &[&str] = &["op", "from", "path", "value"];
+
+/// Writes a patch in the layout every patch file is kept in.
+///
+/// Each operation gets its own line so git diffs of the changes folder show
+/// which operations changed, and its members are always in the same order
+pub fn format(patch: &Patch) -> String {
+    if patch.0.is_empty() {
+        return "[]\n".to_string();
+    }
+
+    let lines: Vec<String> = patch
+        .0
+        .iter()
+        .map(|operation| {
+            let Value::Object(mut members) =
+                serde_json::to_value(operation).expect("Patch operations always serialize")
+            else {
+                unreachable!("Patch operations serialize to objects")
+            };
+
+            let mut ordered: Vec<(String, Value)> = MEMBER_ORDER
+                .iter()
+                .filter_map(|key| members.remove_entry(*key))
+                .collect();
+            // Members a newer json-patch may add are kept, after the known ones
+            ordered.extend(members);
+
+            let members: Vec<String> = ordered
+                .iter()
+                .map(|(key, value)| {
+                    format!(
+                        "{}:{}",
+                        Value::String(key.clone()),
+                        serde_json::to_string(value).expect("JSON values always serialize")
+                    )
+                })
+                .collect();
+            format!("  {{{}}}", members.join(","))
+        })
+        .collect();
+
+    format!("[\n{}\n]\n", lines.join(",\n"))
+}
+
+/// Values longer than this are written over several lines in a listing
+const INLINE_LENGTH: usize = 80;
+
+/// Writes a patch as a readable list of what each operation does, for reviewing diffs.
+///
+/// With the document the patch is applied to, every operation also shows the value it
+/// replaces or removes. Operations after one that does not apply only show their own values
+pub fn listing(patch: &Patch, base: Option<&Value>) -> String {
+    let mut document = base.cloned();
+    let mut text = String::new();
+    for op in &patch.0 {
+        let before = |path: &str| {
+            document
+                .as_ref()
+                .and_then(|document| document.pointer(path))
+        };
+        let mut lines = Vec::new();
+        match op {
+            PatchOperation::Add(op) => {
+                lines.push(format!("add {}", op.path));
+                if let Some(before) =
+                    before(&op.path).filter(|_| !is_array_item(&document, &op.path))
+                {
+                    lines.push(labeled("before", before));
+                }
+                lines.push(labeled("after", &op.value));
+            }
+            PatchOperation::Remove(op) => {
+                lines.push(format!("remove {}", op.path));
+                if let Some(before) = before(&op.path) {
+                    lines.push(labeled("before", before));
+                }
+            }
+            PatchOperation::Replace(op) => {
+                lines.push(format!("replace {}", op.path));
+                if let Some(before) = before(&op.path) {
+                    lines.push(labeled("before", before));
+                }
+                lines.push(labeled("after", &op.value));
+            }
+            PatchOperation::Move(op) => {
+                lines.push(format!("move {} -> {}", op.from, op.path));
+                if let Some(value) = before(&op.from) {
+                    lines.push(labeled("value", value));
+                }
+            }
+            PatchOperation::Copy(op) => {
+                lines.push(format!("copy {} -> {}", op.from, op.path));
+                if let Some(value) = before(&op.from) {
+                    lines.push(labeled("value", value));
+                }
+            }
+            PatchOperation::Test(op) => {
+                lines.push(format!("test {}", op.path));
+                lines.push(labeled("expect", &op.value));
+            }
+        }
+        if let Some(current) = &mut document {
+            if json_patch::patch(current, slice::from_ref(op)).is_err() {
+                lines.push("    (does not apply to the original)".to_string());
+                document = None;
+            }
+        }
+        text.push_str(&lines.join("\n"));
+        text.push_str("\n\n");
+    }
+    text
+}
+
+/// Whether adding at the path inserts into an array instead of setting a member
+fn is_array_item(document: &Option<Value>, path: &str) -> bool {
+    let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+    document
+        .as_ref()
+        .and_then(|document| document.pointer(parent))
+        .is_some_and(Value::is_array)
+}
+
+/// `    label: value`, with longer values pretty printed on the lines below
+fn labeled(label: &str, value: &Value) -> String {
+    let compact = value.to_string();
+    if compact.len() <= INLINE_LENGTH {
+        return format!("    {}: {}", label, compact);
+    }
+    let pretty = serde_json::to_string_pretty(value).expect("JSON values always serialize");
+    let indented: Vec<String> = pretty
+        .lines()
+        .map(|line| format!("        {}", line))
+        .collect();
+    format!("    {}:\n{}", label, indented.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{from_value, json};
+
+    use super::*;
+
+    fn patch(operations: Value) -> Patch {
+        from_value(operations).unwrap()
+    }
+
+    #[test]
+    fn empty_patch() {
+        assert_eq!(format(&Patch(Vec::new())), "[]\n");
+    }
+
+    #[test]
+    fn one_operation_per_line_in_member_order() {
+        let patch = patch(json!([
+            { "value": { "b": 1, "a": [1, 2] }, "path": "/x", "op": "add" },
+            { "path": "/y", "from": "/x", "op": "move" },
+            { "op": "remove", "path": "/z" },
+        ]));
+        let text = format(&patch);
+        assert_eq!(
+            text,
+            "[\n\
+             \x20 {\"op\":\"add\",\"path\":\"/x\",\"value\":{\"a\":[1,2],\"b\":1}},\n\
+             \x20 {\"op\":\"move\",\"from\":\"/x\",\"path\":\"/y\"},\n\
+             \x20 {\"op\":\"remove\",\"path\":\"/z\"}\n\
+             ]\n"
+        );
+        assert_eq!(serde_json::from_str::<Patch>(&text).unwrap(), patch);
+    }
+
+    #[test]
+    fn listing_shows_the_values_operations_change() {
+        let base = json!({ "a": 1, "items": [1], "long": "x".repeat(90) });
+        let patch = patch(json!([
+            { "op": "replace", "path": "/a", "value": 2 },
+            { "op": "add", "path": "/items/0", "value": 0 },
+            { "op": "remove", "path": "/long" },
+            { "op": "move", "from": "/a", "path": "/b" },
+        ]));
+        let long = format!("        \"{}\"", "x".repeat(90));
+        assert_eq!(
+            listing(&patch, Some(&base)),
+            [
+                "replace /a\n    before: 1\n    after: 2\n\n",
+                "add /items/0\n    after: 0\n\n",
+                &format!("remove /long\n    before:\n{}\n\n", long),
+                "move /a -> /b\n    value: 2\n\n",
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn listing_after_an_operation_that_does_not_apply() {
+        let base = json!({ "a": 1, "b": 1 });
+        let patch = patch(json!([
+            { "op": "remove", "path": "/missing" },
+            { "op": "replace", "path": "/a", "value": 2 },
+            { "op": "test", "path": "/b", "value": 1 },
+        ]));
+        assert_eq!(
+            listing(&patch, Some(&base)),
+            "remove /missing\n    (does not apply to the original)\n\n\
+             replace /a\n    after: 2\n\n\
+             test /b\n    expect: 1\n\n"
+        );
+        assert_eq!(
+            listing(&patch, None),
+            "remove /missing\n\nreplace /a\n    after: 2\n\ntest /b\n    expect: 1\n\n"
+        );
+    }
+}
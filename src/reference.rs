@@ -0,0 +1,378 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use json_patch::{Patch, PatchOperation};
+use serde_json::{Map, Value};
+
+use crate::{cache::hash, variables::value_mut};
+
+/// Key of an object that stands for a value of another file,
+/// `{"$include": "shared/enums.json#/Color"}`
+pub const INCLUDE_KEY: &str = "$include";
+/// Written for a plain `$include` member that is not a reference
+const ESCAPED_KEY: &str = "$$include";
+
+/// A file of the project, relative to the revise folder, and a JSON pointer into it
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Reference {
+    pub file: PathBuf,
+    pub pointer: String,
+}
+
+/// Why a reference could not be resolved
+#[derive(Debug)]
+pub struct ReferenceError {
+    pub reference: String,
+    pub message: String,
+}
+
+/// Built documents by their path relative to the revise folder, with the hash of
+/// their output or why they could not be built
+pub type BuiltDocuments = HashMap<PathBuf, Result<(Value, String), String>>;
+
+/// Where references read the documents they point into
+pub enum Documents<'a> {
+    /// Documents built earlier in the same run
+    Built(&'a BuiltDocuments),
+    /// The files of a revise folder as they are
+    Folder(&'a Path),
+}
+
+impl Documents<'_> {
+    fn read(&self, file: &Path) -> Result<Value, String> {
+        match self {
+            Documents::Built(documents) => match documents.get(file) {
+                Some(Ok((document, _))) => Ok(document.clone()),
+                Some(Err(message)) => Err(message.clone()),
+                None => Err("it is not a file of the project".to_string()),
+            },
+            Documents::Folder(root) => {
+                let bytes = fs::read(root.join(file))
+                    .map_err(|err| format!("it cannot be read, {}", err))?;
+                serde_json::from_slice(&bytes).map_err(|_| "it is not a JSON file".to_string())
+            }
+        }
+    }
+
+    /// Combined hash of the documents, so outputs that include them are rebuilt
+    /// when one of them changes
+    pub fn hash_for(&self, files: &[PathBuf]) -> String {
+        let Documents::Built(documents) = self else {
+            return String::new();
+        };
+        if files.is_empty() {
+            return String::new();
+        }
+        let hashes: Vec<String> = files
+            .iter()
+            .map(|file| match documents.get(file) {
+                Some(Ok((_, hash))) => format!("{}:{}", file.display(), hash),
+                _ => format!("{}:", file.display()),
+            })
+            .collect();
+        hash(hashes.join("\n").as_bytes())
+    }
+}
+
+impl Reference {
+    fn parse(text: &str) -> Result<Self, String> {
+        let (file, pointer) = text.split_once('#').unwrap_or((text, ""));
+        if !pointer.is_empty() && !pointer.starts_with('/') {
+            return Err("its JSON pointer has to start with `/`".to_string());
+        }
+
+        let mut normalized = PathBuf::new();
+        for component in Path::new(file).components() {
+            match component {
+                Component::Normal(part) => normalized.push(part),
+                Component::CurDir => (),
+                Component::ParentDir if normalized.pop() => (),
+                _ => return Err("its file has to be inside the project".to_string()),
+            }
+        }
+        if normalized.as_os_str().is_empty() {
+            return Err("it does not name a file".to_string());
+        }
+        Ok(Reference {
+            file: normalized,
+            pointer: pointer.to_string(),
+        })
+    }
+}
+
+/// What the value refers to if it is a reference
+fn as_reference(value: &Value) -> Option<Result<Reference, ReferenceError>> {
+    let Value::Object(object) = value else {
+        return None;
+    };
+    if object.len() != 1 {
+        return None;
+    }
+    let target = object.get(INCLUDE_KEY)?;
+    let Some(text) = target.as_str() else {
+        return Some(Err(ReferenceError {
+            reference: target.to_string(),
+            message: "it has to be a string".to_string(),
+        }));
+    };
+    Some(Reference::parse(text).map_err(|message| ReferenceError {
+        reference: text.to_string(),
+        message,
+    }))
+}
+
+/// Every file the values of the patch refer to, without duplicates
+pub fn references(patch: &Patch) -> Result<Vec<PathBuf>, ReferenceError> {
+    fn collect(value: &Value, files: &mut Vec<PathBuf>) -> Result<(), ReferenceError> {
+        if let Some(reference) = as_reference(value) {
+            let reference = reference?;
+            if !files.contains(&reference.file) {
+                files.push(reference.file);
+            }
+            return Ok(());
+        }
+        match value {
+            Value::Array(array) => array.iter().try_for_each(|item| collect(item, files)),
+            Value::Object(object) => object.values().try_for_each(|item| collect(item, files)),
+            _ => Ok(()),
+        }
+    }
+
+    let mut files = Vec::new();
+    for op in &patch.0 {
+        match op {
+            PatchOperation::Add(op) => collect(&op.value, &mut files)?,
+            PatchOperation::Replace(op) => collect(&op.value, &mut files)?,
+            _ => (),
+        }
+    }
+    Ok(files)
+}
+
+/// Replaces every reference in the values of the patch with the value it points at
+pub fn resolve(patch: &mut Patch, documents: &Documents) -> Result<(), ReferenceError> {
+    for op in &mut patch.0 {
+        if let Some(value) = value_mut(op) {
+            resolve_value(value, documents)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn resolve_value(value: &mut Value, documents: &Documents) -> Result<(), ReferenceError> {
+    if let Some(reference) = as_reference(value) {
+        let reference = reference?;
+        let error = |message: String| ReferenceError {
+            reference: if reference.pointer.is_empty() {
+                reference.file.display().to_string()
+            } else {
+                format!("{}#{}", reference.file.display(), reference.pointer)
+            },
+            message,
+        };
+        let document = documents.read(&reference.file).map_err(error)?;
+        *value = document
+            .pointer(&reference.pointer)
+            .cloned()
+            .ok_or_else(|| error(format!("there is no value at `{}`", reference.pointer)))?;
+        return Ok(());
+    }
+
+    match value {
+        Value::Array(array) => {
+            for item in array {
+                resolve_value(item, documents)?;
+            }
+        }
+        Value::Object(object) => {
+            if object.len() == 1 {
+                if let Some(target) = object.remove(ESCAPED_KEY) {
+                    object.insert(INCLUDE_KEY.to_string(), target);
+                    return Ok(());
+                }
+            }
+            for item in object.values_mut() {
+                resolve_value(item, documents)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Writes a plain object that would be read as a reference the way it stays one
+pub fn escape(object: &mut Map<String, Value>) {
+    if object.len() == 1 {
+        if let Some(target) = object.remove(INCLUDE_KEY) {
+            object.insert(ESCAPED_KEY.to_string(), target);
+        }
+    }
+}
+
+/// Groups the files so each group only refers to files of the groups before it.
+///
+/// `dependencies` holds the indices of the files each file refers to, a cycle is
+/// returned as the indices of the files in it
+pub fn levels(dependencies: &[Vec<usize>]) -> Result<Vec<Vec<usize>>, Vec<usize>> {
+    #[derive(Clone, Copy, PartialEq)]
+    enum State {
+        New,
+        Visiting,
+        Done(usize),
+    }
+
+    fn visit(
+        index: usize,
+        dependencies: &[Vec<usize>],
+        states: &mut [State],
+        path: &mut Vec<usize>,
+    ) -> Result<usize, Vec<usize>> {
+        match states[index] {
+            State::Done(level) => return Ok(level),
+            State::Visiting => {
+                let start = path
+                    .iter()
+                    .position(|&visited| visited == index)
+                    .expect("Files being visited are on the path");
+                let mut cycle = path[start..].to_vec();
+                cycle.push(index);
+                return Err(cycle);
+            }
+            State::New => (),
+        }
+        states[index] = State::Visiting;
+        path.push(index);
+        let mut level = 0;
+        for &dependency in &dependencies[index] {
+            level = level.max(visit(dependency, dependencies, states, path)? + 1);
+        }
+        path.pop();
+        states[index] = State::Done(level);
+        Ok(level)
+    }
+
+    let mut states = vec![State::New; dependencies.len()];
+    let mut levels: Vec<Vec<usize>> = Vec::new();
+    for index in 0..dependencies.len() {
+        let level = visit(index, dependencies, &mut states, &mut Vec::new())?;
+        if levels.len() <= level {
+            levels.resize(level + 1, Vec::new());
+        }
+        levels[level].push(index);
+    }
+    Ok(levels)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{from_value, json};
+
+    use super::*;
+
+    fn built() -> BuiltDocuments {
+        HashMap::from([
+            (
+                PathBuf::from("shared/enums.json"),
+                Ok((json!({ "Color": ["red", "green"] }), "a".to_string())),
+            ),
+            (
+                PathBuf::from("broken.json"),
+                Err("its patch failed".to_string()),
+            ),
+        ])
+    }
+
+    fn resolved(value: Value) -> Result<Value, String> {
+        let built = built();
+        let mut value = value;
+        resolve_value(&mut value, &Documents::Built(&built))
+            .map(|_| value)
+            .map_err(|err| format!("{}: {}", err.reference, err.message))
+    }
+
+    #[test]
+    fn resolves_references() {
+        assert_eq!(
+            resolved(json!({
+                "colors": { "$include": "shared/enums.json#/Color" },
+                "all": [{ "$include": "./shared/../shared/enums.json" }],
+                "other": { "$include": "shared/enums.json#/Color", "extra": 1 },
+            })),
+            Ok(json!({
+                "colors": ["red", "green"],
+                "all": [{ "Color": ["red", "green"] }],
+                "other": { "$include": "shared/enums.json#/Color", "extra": 1 },
+            }))
+        );
+    }
+
+    #[test]
+    fn escaped_include_is_kept_as_is() {
+        let mut object = json!({ "$include": "not a reference" });
+        escape(object.as_object_mut().unwrap());
+        assert_eq!(object, json!({ "$$include": "not a reference" }));
+        assert_eq!(
+            resolved(object),
+            Ok(json!({ "$include": "not a reference" }))
+        );
+    }
+
+    #[test]
+    fn reports_references_that_cannot_be_resolved() {
+        let message = |text: &str| resolved(json!({ "$include": text })).unwrap_err();
+        assert_eq!(
+            message("shared/enums.json#/Size"),
+            "shared/enums.json#/Size: there is no value at `/Size`"
+        );
+        assert_eq!(
+            message("shared/enums.json#Color"),
+            "shared/enums.json#Color: its JSON pointer has to start with `/`"
+        );
+        assert_eq!(
+            message("../outside.json"),
+            "../outside.json: its file has to be inside the project"
+        );
+        assert_eq!(message("#/a"), "#/a: it does not name a file");
+        assert_eq!(message("broken.json"), "broken.json: its patch failed");
+        assert_eq!(
+            message("missing.json"),
+            "missing.json: it is not a file of the project"
+        );
+        assert_eq!(
+            resolved(json!({ "$include": 1 })).unwrap_err(),
+            "1: it has to be a string"
+        );
+    }
+
+    #[test]
+    fn lists_the_referenced_files() {
+        let patch: Patch = from_value(json!([
+            { "op": "add", "path": "/a", "value": { "$include": "b.json#/x" } },
+            { "op": "replace", "path": "/b", "value": [{ "$include": "./b.json" }, { "$include": "c.json" }] },
+            { "op": "test", "path": "/c", "value": { "$include": "d.json" } },
+        ]))
+        .unwrap();
+        assert_eq!(
+            references(&patch).unwrap(),
+            [PathBuf::from("b.json"), PathBuf::from("c.json")]
+        );
+    }
+
+    #[test]
+    fn orders_files_by_their_references() {
+        assert_eq!(
+            levels(&[vec![1, 2], vec![2], vec![], vec![]]),
+            Ok(vec![vec![2, 3], vec![1], vec![0]])
+        );
+        assert_eq!(levels(&[]), Ok(vec![]));
+    }
+
+    #[test]
+    fn detects_cycles() {
+        assert_eq!(levels(&[vec![1], vec![2], vec![1]]), Err(vec![1, 2, 1]));
+        assert_eq!(levels(&[vec![0]]), Err(vec![0, 0]));
+    }
+}
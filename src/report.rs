@@ -0,0 +1,275 @@
+use std::{path::Path, sync::OnceLock};
+
+use colored::Colorize;
+use serde::Serialize;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MessageFormat {
+    Human,
+    Json,
+}
+
+impl MessageFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "human" => Some(Self::Human),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+static MESSAGE_FORMAT: OnceLock<MessageFormat> = OnceLock::new();
+
+/// Can only be set once, before any command runs
+pub fn set_message_format(format: MessageFormat) {
+    MESSAGE_FORMAT
+        .set(format)
+        .expect("Message format is only set once at startup");
+}
+
+pub fn message_format() -> MessageFormat {
+    *MESSAGE_FORMAT.get().unwrap_or(&MessageFormat::Human)
+}
+
+/// Something that happened while running a command.
+///
+/// In the JSON message format each event is written to stdout as a single line
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    FileProcessed {
+        command: &'a str,
+        original: &'a Path,
+        changes: &'a Path,
+        revise: &'a Path,
+    },
+    /// A patch file was rewritten in the canonical layout by `fmt`
+    FileFormatted {
+        path: &'a Path,
+    },
+    FileStatus {
+        original: &'a Path,
+        status: FileStatus,
+    },
+    Warning {
+        path: &'a Path,
+        message: &'a str,
+    },
+    Error {
+        code: &'a str,
+        message: &'a str,
+        paths: Vec<&'a Path>,
+        /// JSON pointer into the document the error is about
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pointer: Option<&'a str>,
+        /// Index of the patch operation the error is about
+        #[serde(skip_serializing_if = "Option::is_none")]
+        operation: Option<usize>,
+    },
+    Summary {
+        command: &'a str,
+        /// Profile the build was for, if any
+        #[serde(skip_serializing_if = "Option::is_none")]
+        profile: Option<&'a str>,
+        processed: usize,
+        up_to_date: usize,
+        warnings: usize,
+        errors: usize,
+    },
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileStatus {
+    /// The revise file matches the original with its patch applied
+    Clean,
+    /// The revise file has changes that `update` would record
+    Modified,
+    /// There is no revise file, `build` has not been run
+    NotBuilt,
+    /// There is no patch file for the original
+    NoChanges,
+}
+
+impl FileStatus {
+    fn label(self) -> &'static str {
+        match self {
+            FileStatus::Clean => "clean",
+            FileStatus::Modified => "modified",
+            FileStatus::NotBuilt => "not built",
+            FileStatus::NoChanges => "no changes",
+        }
+    }
+}
+
+/// The event as one line of the JSON message format
+fn json_line(event: &Event) -> String {
+    serde_json::to_string(event).expect("Events always serialize")
+}
+
+pub fn emit(event: Event) {
+    match message_format() {
+        MessageFormat::Json => println!("{}", json_line(&event)),
+        MessageFormat::Human => match event {
+            Event::FileProcessed { .. } | Event::FileFormatted { .. } => (),
+            Event::FileStatus { original, status } => {
+                let label = format!("{:>10}", status.label());
+                let label = match status {
+                    FileStatus::Clean => label.normal(),
+                    FileStatus::Modified => label.yellow(),
+                    FileStatus::NotBuilt | FileStatus::NoChanges => label.bright_black(),
+                };
+                println!("{} {}", label, original.to_string_lossy());
+            }
+            Event::Warning { path, message } => println!(
+                "{}{}{}",
+                "Warning: Path ".yellow(),
+                path.to_string_lossy().yellow(),
+                format!(" {}", message).yellow(),
+            ),
+            Event::Error { message, .. } => {
+                eprintln!("{}{}", "Error: ".red(), message.red())
+            }
+            Event::Summary {
+                command,
+                profile,
+                processed,
+                up_to_date,
+                errors,
+                ..
+            } => {
+                let msg = match command {
+                    "build" if errors > 0 => {
+                        format!("Generated {} files, {} files failed", processed, errors)
+                    }
+                    "build" => format!("Successfully generated {} files", processed),
+                    "update" if errors > 0 => {
+                        format!("Applied {} changes, {} files failed", processed, errors)
+                    }
+                    "update" => format!("Successfully applied {} changes", processed),
+                    "check" if errors > 0 => {
+                        format!("{} of {} files failed the check", errors, processed)
+                    }
+                    "check" => format!("All {} files passed the check", processed),
+                    "optimize" if errors > 0 => {
+                        format!(
+                            "Optimized {} patch files, {} files failed",
+                            processed, errors
+                        )
+                    }
+                    "optimize" => format!("Optimized {} patch files", processed),
+                    "fmt" if errors > 0 => {
+                        format!("{} patch files are not formatted or invalid", errors)
+                    }
+                    "fmt" => format!("Formatted {} patch files", processed),
+                    "package" => format!("Packaged {} files", processed),
+                    "codegen" => format!("Generated types for {} documents", processed),
+                    "infer-schema" => format!("Inferred a schema from {} documents", processed),
+                    _ => return,
+                };
+                let msg = if up_to_date > 0 {
+                    format!("{} ({} up to date)", msg, up_to_date)
+                } else {
+                    msg
+                };
+                let msg = match profile {
+                    Some(profile) => format!("{} for profile {}", msg, profile),
+                    None => msg,
+                };
+                if errors > 0 {
+                    println!("{}", msg.red());
+                } else {
+                    println!("{}", msg.bright_green());
+                }
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn parse(event: Event) -> Value {
+        let line = json_line(&event);
+        assert!(!line.contains('\n'));
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn events_are_tagged_in_kebab_case() {
+        assert_eq!(
+            parse(Event::FileProcessed {
+                command: "build",
+                original: Path::new("original/a.json"),
+                changes: Path::new("changes/a.patch.json"),
+                revise: Path::new("revise/a.json"),
+            }),
+            json!({
+                "event": "file-processed",
+                "command": "build",
+                "original": "original/a.json",
+                "changes": "changes/a.patch.json",
+                "revise": "revise/a.json",
+            })
+        );
+        assert_eq!(
+            parse(Event::FileStatus {
+                original: Path::new("original/a.json"),
+                status: FileStatus::NotBuilt,
+            }),
+            json!({ "event": "file-status", "original": "original/a.json", "status": "not-built" })
+        );
+    }
+
+    #[test]
+    fn optional_fields_are_left_out() {
+        assert_eq!(
+            parse(Event::Error {
+                code: "patch-failed",
+                message: "cannot apply",
+                paths: vec![Path::new("changes/a.patch.json")],
+                pointer: None,
+                operation: Some(2),
+            }),
+            json!({
+                "event": "error",
+                "code": "patch-failed",
+                "message": "cannot apply",
+                "paths": ["changes/a.patch.json"],
+                "operation": 2,
+            })
+        );
+        assert_eq!(
+            parse(Event::Summary {
+                command: "build",
+                profile: None,
+                processed: 3,
+                up_to_date: 1,
+                warnings: 0,
+                errors: 0,
+            }),
+            json!({
+                "event": "summary",
+                "command": "build",
+                "processed": 3,
+                "up_to_date": 1,
+                "warnings": 0,
+                "errors": 0,
+            })
+        );
+    }
+
+    #[test]
+    fn message_format_names() {
+        assert_eq!(MessageFormat::from_name("json"), Some(MessageFormat::Json));
+        assert_eq!(
+            MessageFormat::from_name("human"),
+            Some(MessageFormat::Human)
+        );
+        assert_eq!(MessageFormat::from_name("JSON"), None);
+    }
+}
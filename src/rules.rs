@@ -0,0 +1,820 @@
+use std::{
+    cmp::Ordering,
+    path::{Path, PathBuf},
+};
+
+use json_patch::{Patch, PatchError, PatchOperation};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Rules files sit next to the patch of the same original, `x.json` gets `x.rules.json`
+pub const RULES_EXTENSION: &str = "rules.json";
+
+/// Path of the rules file that belongs to a patch file
+pub fn rules_path(changes: &Path) -> PathBuf {
+    changes.with_extension(RULES_EXTENSION)
+}
+
+pub fn is_rules_file(path: &Path) -> bool {
+    path.to_string_lossy()
+        .ends_with(&format!(".{}", RULES_EXTENSION))
+}
+
+/// Patches applied to every node a selector matches, on top of the regular patch
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    before: Vec<RuleFile>,
+    #[serde(default)]
+    after: Vec<RuleFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    select: String,
+    /// Paths are relative to the matched node
+    patch: Patch,
+}
+
+/// When rules are applied compared to the regular patch
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Stage {
+    Before,
+    After,
+}
+
+impl Stage {
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Before => "before",
+            Stage::After => "after",
+        }
+    }
+}
+
+pub struct Rules {
+    before: Vec<Rule>,
+    after: Vec<Rule>,
+}
+
+struct Rule {
+    selector: Vec<Segment>,
+    patch: Patch,
+}
+
+/// A rule that could not be applied to one of the nodes it matched
+#[derive(Debug)]
+pub struct RuleError {
+    pub stage: Stage,
+    pub rule: usize,
+    /// Pointer of the matched node
+    pub pointer: String,
+    pub error: PatchError,
+}
+
+impl Rules {
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, String> {
+        let file: RulesFile = serde_json::from_slice(bytes).map_err(|err| err.to_string())?;
+        let compile = |stage: Stage, rules: Vec<RuleFile>| {
+            rules
+                .into_iter()
+                .enumerate()
+                .map(|(index, rule)| {
+                    let selector = parse_selector(&rule.select).map_err(|err| {
+                        format!(
+                            "{} rule {} has an invalid selector, {}",
+                            stage.name(),
+                            index,
+                            err
+                        )
+                    })?;
+                    Ok(Rule {
+                        selector,
+                        patch: rule.patch,
+                    })
+                })
+                .collect::<Result<Vec<_>, String>>()
+        };
+        Ok(Self {
+            before: compile(Stage::Before, file.before)?,
+            after: compile(Stage::After, file.after)?,
+        })
+    }
+
+    pub fn has(&self, stage: Stage) -> bool {
+        match stage {
+            Stage::Before => !self.before.is_empty(),
+            Stage::After => !self.after.is_empty(),
+        }
+    }
+
+    /// Applies the rules of the stage in order, each to every node its selector matches
+    pub fn apply(&self, stage: Stage, document: &mut Value) -> Result<(), RuleError> {
+        let rules = match stage {
+            Stage::Before => &self.before,
+            Stage::After => &self.after,
+        };
+        for (index, rule) in rules.iter().enumerate() {
+            // Last match first, so removing array items does not shift the earlier matches
+            for pointer in select(&rule.selector, document).into_iter().rev() {
+                let operations: Vec<PatchOperation> = rule
+                    .patch
+                    .0
+                    .iter()
+                    .map(|op| relative_to(op, &pointer))
+                    .collect();
+                json_patch::patch(document, &operations).map_err(|error| RuleError {
+                    stage,
+                    rule: index,
+                    pointer: pointer.clone(),
+                    error,
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn relative_to(op: &PatchOperation, pointer: &str) -> PatchOperation {
+    let mut op = op.clone();
+    match &mut op {
+        PatchOperation::Add(op) => op.path.insert_str(0, pointer),
+        PatchOperation::Remove(op) => op.path.insert_str(0, pointer),
+        PatchOperation::Replace(op) => op.path.insert_str(0, pointer),
+        PatchOperation::Move(op) => {
+            op.from.insert_str(0, pointer);
+            op.path.insert_str(0, pointer);
+        }
+        PatchOperation::Copy(op) => {
+            op.from.insert_str(0, pointer);
+            op.path.insert_str(0, pointer);
+        }
+        PatchOperation::Test(op) => op.path.insert_str(0, pointer),
+    }
+    op
+}
+
+/// One step of a JSONPath selector
+#[derive(Debug)]
+enum Segment {
+    /// `.name`, `[0]`, `[*]`, `['a', 'b']` or `[?(...)]`
+    Child(Vec<Selector>),
+    /// `..name` and the like, matching at any depth
+    Descendant(Vec<Selector>),
+}
+
+#[derive(Debug)]
+enum Selector {
+    Name(String),
+    /// Negative indexes count from the end
+    Index(i64),
+    Wildcard,
+    Filter(Expression),
+}
+
+#[derive(Debug)]
+enum Expression {
+    Or(Box<Expression>, Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Not(Box<Expression>),
+    Compare(Operand, Comparison, Operand),
+    /// `@.name` on its own, true if the node has it
+    Exists(Query),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+#[derive(Debug)]
+enum Operand {
+    Query(Query),
+    Literal(Value),
+}
+
+/// Path to a single value inside a filter, from the current node or the root
+#[derive(Debug)]
+struct Query {
+    from_root: bool,
+    steps: Vec<Step>,
+}
+
+#[derive(Debug)]
+enum Step {
+    Name(String),
+    Index(i64),
+}
+
+/// Pointers of every node the selector matches, in document order
+fn select(selector: &[Segment], document: &Value) -> Vec<String> {
+    let mut nodes = vec![(String::new(), document)];
+    for segment in selector {
+        let mut next = Vec::new();
+        for (pointer, node) in nodes {
+            match segment {
+                Segment::Child(selectors) => {
+                    select_children(selectors, &pointer, node, document, &mut next)
+                }
+                Segment::Descendant(selectors) => {
+                    descend(selectors, pointer, node, document, &mut next)
+                }
+            }
+        }
+        nodes = next;
+    }
+    nodes.into_iter().map(|(pointer, _)| pointer).collect()
+}
+
+fn descend<'a>(
+    selectors: &[Selector],
+    pointer: String,
+    node: &'a Value,
+    root: &Value,
+    out: &mut Vec<(String, &'a Value)>,
+) {
+    select_children(selectors, &pointer, node, root, out);
+    for (child_pointer, child) in children(&pointer, node) {
+        descend(selectors, child_pointer, child, root, out);
+    }
+}
+
+fn children<'a>(pointer: &str, node: &'a Value) -> Vec<(String, &'a Value)> {
+    match node {
+        Value::Array(array) => array
+            .iter()
+            .enumerate()
+            .map(|(index, item)| (format!("{}/{}", pointer, index), item))
+            .collect(),
+        Value::Object(object) => object
+            .iter()
+            .map(|(key, item)| (format!("{}/{}", pointer, escape(key)), item))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn select_children<'a>(
+    selectors: &[Selector],
+    pointer: &str,
+    node: &'a Value,
+    root: &Value,
+    out: &mut Vec<(String, &'a Value)>,
+) {
+    for selector in selectors {
+        match selector {
+            Selector::Name(name) => {
+                if let Some(child) = node.as_object().and_then(|object| object.get(name)) {
+                    out.push((format!("{}/{}", pointer, escape(name)), child));
+                }
+            }
+            Selector::Index(index) => {
+                if let Some(array) = node.as_array() {
+                    if let Some(index) = resolve_index(*index, array.len()) {
+                        out.push((format!("{}/{}", pointer, index), &array[index]));
+                    }
+                }
+            }
+            Selector::Wildcard => out.extend(children(pointer, node)),
+            Selector::Filter(expression) => out.extend(
+                children(pointer, node)
+                    .into_iter()
+                    .filter(|(_, child)| evaluate(expression, child, root)),
+            ),
+        }
+    }
+}
+
+fn resolve_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+    usize::try_from(index).ok().filter(|index| *index < len)
+}
+
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+fn evaluate(expression: &Expression, node: &Value, root: &Value) -> bool {
+    match expression {
+        Expression::Or(a, b) => evaluate(a, node, root) || evaluate(b, node, root),
+        Expression::And(a, b) => evaluate(a, node, root) && evaluate(b, node, root),
+        Expression::Not(expression) => !evaluate(expression, node, root),
+        Expression::Exists(query) => resolve(query, node, root).is_some(),
+        Expression::Compare(a, comparison, b) => {
+            let a = operand(a, node, root);
+            let b = operand(b, node, root);
+            match comparison {
+                Comparison::Equal => a == b,
+                Comparison::NotEqual => a != b,
+                Comparison::Less => order(a, b) == Some(Ordering::Less),
+                Comparison::LessOrEqual => {
+                    matches!(order(a, b), Some(Ordering::Less | Ordering::Equal))
+                }
+                Comparison::Greater => order(a, b) == Some(Ordering::Greater),
+                Comparison::GreaterOrEqual => {
+                    matches!(order(a, b), Some(Ordering::Greater | Ordering::Equal))
+                }
+            }
+        }
+    }
+}
+
+fn operand<'a>(operand: &'a Operand, node: &'a Value, root: &'a Value) -> Option<&'a Value> {
+    match operand {
+        Operand::Query(query) => resolve(query, node, root),
+        Operand::Literal(value) => Some(value),
+    }
+}
+
+/// Only numbers and strings have an order, anything else is never less or greater
+fn order(a: Option<&Value>, b: Option<&Value>) -> Option<Ordering> {
+    match (a?, b?) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+fn resolve<'a>(query: &Query, node: &'a Value, root: &'a Value) -> Option<&'a Value> {
+    let mut current = if query.from_root { root } else { node };
+    for step in &query.steps {
+        current = match step {
+            Step::Name(name) => current.as_object()?.get(name)?,
+            Step::Index(index) => {
+                let array = current.as_array()?;
+                &array[resolve_index(*index, array.len())?]
+            }
+        };
+    }
+    Some(current)
+}
+
+/// Parses the supported JSONPath subset: `$`, `.name`, `['name']`, `[0]`, `[-1]`, `*`,
+/// unions such as `['a', 'b']`, `..` for any depth and filters such as
+/// `[?(@.category == 'weapon' && @.level >= 3)]` with `==`, `!=`, `<`, `<=`, `>`, `>=`,
+/// `&&`, `||`, `!` and existence checks like `[?(@.tags)]`
+fn parse_selector(selector: &str) -> Result<Vec<Segment>, String> {
+    let mut parser = Parser {
+        chars: selector.chars().collect(),
+        position: 0,
+    };
+    parser.skip_whitespace();
+    parser.expect('$')?;
+
+    let mut segments = Vec::new();
+    loop {
+        parser.skip_whitespace();
+        match parser.peek() {
+            None => break,
+            Some('.') if parser.peek_at(1) == Some('.') => {
+                parser.position += 2;
+                let selectors = if parser.peek() == Some('[') {
+                    parser.bracket()?
+                } else {
+                    vec![parser.dot_member()?]
+                };
+                segments.push(Segment::Descendant(selectors));
+            }
+            Some('.') => {
+                parser.position += 1;
+                segments.push(Segment::Child(vec![parser.dot_member()?]));
+            }
+            Some('[') => segments.push(Segment::Child(parser.bracket()?)),
+            Some(_) => return Err(parser.unexpected("`.`, `..` or `[`")),
+        }
+    }
+    Ok(segments)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.position + offset).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.position += 1;
+        }
+    }
+
+    fn eat(&mut self, text: &str) -> bool {
+        self.skip_whitespace();
+        let matches = text
+            .chars()
+            .enumerate()
+            .all(|(offset, char)| self.peek_at(offset) == Some(char));
+        if matches {
+            self.position += text.chars().count();
+        }
+        matches
+    }
+
+    fn expect(&mut self, char: char) -> Result<(), String> {
+        if self.eat(&char.to_string()) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("`{}`", char)))
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> String {
+        match self.peek() {
+            Some(char) => format!(
+                "expected {} at character {} but found `{}`",
+                expected,
+                self.position + 1,
+                char
+            ),
+            None => format!("expected {} but the selector ended", expected),
+        }
+    }
+
+    /// The member after `.` or `..`, a name or `*`
+    fn dot_member(&mut self) -> Result<Selector, String> {
+        if self.peek() == Some('*') {
+            self.position += 1;
+            return Ok(Selector::Wildcard);
+        }
+        Ok(Selector::Name(self.name()?))
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let start = self.position;
+        while self
+            .peek()
+            .is_some_and(|char| char.is_alphanumeric() || char == '_' || char == '-')
+        {
+            self.position += 1;
+        }
+        if start == self.position {
+            return Err(self.unexpected("a member name"));
+        }
+        Ok(self.chars[start..self.position].iter().collect())
+    }
+
+    fn bracket(&mut self) -> Result<Vec<Selector>, String> {
+        self.expect('[')?;
+        self.skip_whitespace();
+        if self.eat("?") {
+            let expression = self.expression()?;
+            self.expect(']')?;
+            return Ok(vec![Selector::Filter(expression)]);
+        }
+
+        let mut selectors = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let selector = match self.peek() {
+                Some('*') => {
+                    self.position += 1;
+                    Selector::Wildcard
+                }
+                Some('\'' | '"') => Selector::Name(self.string()?),
+                Some(char) if char == '-' || char.is_ascii_digit() => {
+                    Selector::Index(self.integer()?)
+                }
+                _ => return Err(self.unexpected("a name, an index or `*`")),
+            };
+            selectors.push(selector);
+            if self.eat("]") {
+                return Ok(selectors);
+            }
+            self.expect(',')?;
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let quote = self.peek().expect("Only called at a quote");
+        self.position += 1;
+        let mut string = String::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.unexpected(&format!("`{}`", quote))),
+                Some('\\') => {
+                    self.position += 1;
+                    match self.peek() {
+                        Some(char) => string.push(char),
+                        None => return Err(self.unexpected("an escaped character")),
+                    }
+                }
+                Some(char) if char == quote => {
+                    self.position += 1;
+                    return Ok(string);
+                }
+                Some(char) => string.push(char),
+            }
+            self.position += 1;
+        }
+    }
+
+    fn integer(&mut self) -> Result<i64, String> {
+        let start = self.position;
+        if self.peek() == Some('-') {
+            self.position += 1;
+        }
+        while self.peek().is_some_and(|char| char.is_ascii_digit()) {
+            self.position += 1;
+        }
+        let text: String = self.chars[start..self.position].iter().collect();
+        text.parse()
+            .map_err(|_| format!("`{}` is not a valid index", text))
+    }
+
+    fn expression(&mut self) -> Result<Expression, String> {
+        let mut expression = self.conjunction()?;
+        while self.eat("||") {
+            expression = Expression::Or(Box::new(expression), Box::new(self.conjunction()?));
+        }
+        Ok(expression)
+    }
+
+    fn conjunction(&mut self) -> Result<Expression, String> {
+        let mut expression = self.unary()?;
+        while self.eat("&&") {
+            expression = Expression::And(Box::new(expression), Box::new(self.unary()?));
+        }
+        Ok(expression)
+    }
+
+    fn unary(&mut self) -> Result<Expression, String> {
+        if self.eat("!") {
+            return Ok(Expression::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let expression = self.expression()?;
+            self.expect(')')?;
+            return Ok(expression);
+        }
+
+        let left = self.operand()?;
+        let comparison = if self.eat("==") {
+            Comparison::Equal
+        } else if self.eat("!=") {
+            Comparison::NotEqual
+        } else if self.eat("<=") {
+            Comparison::LessOrEqual
+        } else if self.eat(">=") {
+            Comparison::GreaterOrEqual
+        } else if self.eat("<") {
+            Comparison::Less
+        } else if self.eat(">") {
+            Comparison::Greater
+        } else {
+            return match left {
+                Operand::Query(query) => Ok(Expression::Exists(query)),
+                Operand::Literal(_) => Err(self.unexpected("a comparison")),
+            };
+        };
+        Ok(Expression::Compare(left, comparison, self.operand()?))
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(start @ ('@' | '$')) => {
+                self.position += 1;
+                Ok(Operand::Query(self.query(start == '$')?))
+            }
+            Some('\'' | '"') => Ok(Operand::Literal(Value::String(self.string()?))),
+            Some(char) if char == '-' || char.is_ascii_digit() => self.number(),
+            Some(_) => {
+                for (word, value) in [
+                    ("true", Value::Bool(true)),
+                    ("false", Value::Bool(false)),
+                    ("null", Value::Null),
+                ] {
+                    if self.eat(word) {
+                        return Ok(Operand::Literal(value));
+                    }
+                }
+                Err(self.unexpected("`@`, `$` or a value"))
+            }
+            None => Err(self.unexpected("`@`, `$` or a value")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Operand, String> {
+        let start = self.position;
+        while self
+            .peek()
+            .is_some_and(|char| char.is_ascii_digit() || "-+.eE".contains(char))
+        {
+            self.position += 1;
+        }
+        let text: String = self.chars[start..self.position].iter().collect();
+        serde_json::from_str::<serde_json::Number>(&text)
+            .map(|number| Operand::Literal(Value::Number(number)))
+            .map_err(|_| format!("`{}` is not a valid number", text))
+    }
+
+    /// Singular path after `@` or `$` inside a filter
+    fn query(&mut self, from_root: bool) -> Result<Query, String> {
+        let mut steps = Vec::new();
+        loop {
+            match self.peek() {
+                Some('.') => {
+                    self.position += 1;
+                    steps.push(Step::Name(self.name()?));
+                }
+                Some('[') => {
+                    self.position += 1;
+                    self.skip_whitespace();
+                    let step = match self.peek() {
+                        Some('\'' | '"') => Step::Name(self.string()?),
+                        _ => Step::Index(self.integer()?),
+                    };
+                    self.expect(']')?;
+                    steps.push(step);
+                }
+                _ => return Ok(Query { from_root, steps }),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn document() -> Value {
+        json!({
+            "min": 2,
+            "items": [
+                { "name": "sword", "category": "weapon", "level": 3, "tags": ["sharp"] },
+                { "name": "apple", "category": "food", "level": 1 },
+                { "name": "axe", "category": "weapon", "level": 2, "tags": [] },
+                { "name": "bow", "category": "weapon", "level": 5 }
+            ],
+            "nested": { "a/b": { "name": "deep" } }
+        })
+    }
+
+    fn matches(selector: &str) -> Vec<String> {
+        select(&parse_selector(selector).unwrap(), &document())
+    }
+
+    #[test]
+    fn parses_members_indexes_and_unions() {
+        assert_eq!(matches("$"), [""]);
+        assert_eq!(matches(" $.items[0].name"), ["/items/0/name"]);
+        assert_eq!(matches("$['items'][-1]"), ["/items/3"]);
+        assert_eq!(matches("$.items[0, 2]"), ["/items/0", "/items/2"]);
+        assert_eq!(matches("$[\"min\", 'missing']"), ["/min"]);
+        assert_eq!(matches("$.nested['a/b'].name"), ["/nested/a~1b/name"]);
+        assert_eq!(matches("$.items[9]"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn parses_wildcards_and_descendants() {
+        assert_eq!(
+            matches("$.items[*].level"),
+            [
+                "/items/0/level",
+                "/items/1/level",
+                "/items/2/level",
+                "/items/3/level"
+            ]
+        );
+        assert_eq!(matches("$.nested.*"), ["/nested/a~1b"]);
+        assert_eq!(
+            matches("$..name"),
+            [
+                "/items/0/name",
+                "/items/1/name",
+                "/items/2/name",
+                "/items/3/name",
+                "/nested/a~1b/name"
+            ]
+        );
+        assert_eq!(matches("$..tags[0]"), ["/items/0/tags/0"]);
+    }
+
+    #[test]
+    fn parses_filters() {
+        assert_eq!(
+            matches("$.items[?(@.category == 'weapon' && @.level >= 3)]"),
+            ["/items/0", "/items/3"]
+        );
+        assert_eq!(
+            matches("$.items[?(@.level < 2 || @.name == \"axe\")].name"),
+            ["/items/1/name", "/items/2/name"]
+        );
+        assert_eq!(matches("$.items[?(@.tags)]"), ["/items/0", "/items/2"]);
+        assert_eq!(matches("$.items[?(!@.tags)]"), ["/items/1", "/items/3"]);
+        assert_eq!(
+            matches("$.items[?(@.level > $.min)]"),
+            ["/items/0", "/items/3"]
+        );
+        assert_eq!(matches("$.items[?(@.tags[0] == 'sharp')]"), ["/items/0"]);
+        // A missing value is not equal to anything, `null` included
+        assert_eq!(matches("$.items[?(@.tags[0] != null)]").len(), 4);
+        assert_eq!(
+            matches("$.items[?(!(@.level > 1) || @['name'] == 'bow')]"),
+            ["/items/1", "/items/3"]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_selectors() {
+        for selector in [
+            "",
+            "items",
+            "$.",
+            "$.items[",
+            "$.items[0",
+            "$['items'",
+            "$.items[1.5]",
+            "$.items[?(@.level ==)]",
+            "$.items[?(@.level == 1]",
+            "$.items[?(1)]",
+            "$.items[?(@.level == 1e)]",
+            "$ items",
+        ] {
+            assert!(parse_selector(selector).is_err(), "{}", selector);
+        }
+        assert_eq!(
+            parse_selector("$.items x").unwrap_err(),
+            "expected `.`, `..` or `[` at character 9 but found `x`"
+        );
+        assert_eq!(
+            parse_selector("$.").unwrap_err(),
+            "expected a member name but the selector ended"
+        );
+    }
+
+    #[test]
+    fn removes_several_items_of_the_same_array() {
+        let rules = Rules::from_slice(
+            json!({
+                "after": [{
+                    "select": "$.items[?(@.category == 'weapon')]",
+                    "patch": [{ "op": "remove", "path": "" }]
+                }]
+            })
+            .to_string()
+            .as_bytes(),
+        )
+        .unwrap();
+        let mut document = document();
+        rules.apply(Stage::After, &mut document).unwrap();
+        assert_eq!(
+            document["items"],
+            json!([{ "name": "apple", "category": "food", "level": 1 }])
+        );
+        assert!(!rules.has(Stage::Before));
+    }
+
+    #[test]
+    fn rules_patch_relative_to_the_match() {
+        let rules = Rules::from_slice(
+            json!({
+                "before": [{
+                    "select": "$..[?(@.tags)]",
+                    "patch": [
+                        { "op": "add", "path": "/tags/-", "value": "tagged" },
+                        { "op": "move", "from": "/level", "path": "/rank" }
+                    ]
+                }]
+            })
+            .to_string()
+            .as_bytes(),
+        )
+        .unwrap();
+        let mut document = document();
+        rules.apply(Stage::Before, &mut document).unwrap();
+        assert_eq!(document["items"][0]["tags"], json!(["sharp", "tagged"]));
+        assert_eq!(document["items"][2]["rank"], json!(2));
+        assert_eq!(document["items"][1].get("rank"), None);
+    }
+
+    #[test]
+    fn invalid_selector_names_the_rule() {
+        let error = Rules::from_slice(
+            br#"{"after": [{"select": "$.a", "patch": []}, {"select": "a", "patch": []}]}"#,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            error,
+            "after rule 1 has an invalid selector, expected `$` at character 1 but found `a`"
+        );
+    }
+}
@@ -0,0 +1,585 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use glob::{MatchOptions, Pattern};
+use json_patch::{Patch, PatchOperation};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    cache::hash,
+    error::{AppError, AppErrorIo},
+    file_trio::FilePath,
+};
+
+/// Maps output files to the JSON Schema they have to match
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SchemaMapping {
+    /// Glob of paths relative to the revise folder, such as `**/*.json`
+    pub files: String,
+    /// Path of the schema file relative to the project
+    pub schema: String,
+}
+
+pub struct Schema {
+    pattern: Pattern,
+    pub path: FilePath,
+    pub hash: String,
+    root: Value,
+}
+
+/// Every schema of the project, loaded once before processing
+#[derive(Default)]
+pub struct Schemas(Vec<Schema>);
+
+#[derive(Debug, Clone)]
+pub struct Violation {
+    /// JSON pointer of the value that does not match
+    pub pointer: String,
+    pub message: String,
+    /// Index of the patch operation that most likely caused the violation
+    pub operation: Option<usize>,
+}
+
+pub(crate) const GLOB_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+impl Schemas {
+    pub fn load(mappings: &[SchemaMapping]) -> Result<Self, AppError<'static>> {
+        let mut schemas = Vec::new();
+        for mapping in mappings {
+            let pattern = Pattern::new(&mapping.files).map_err(|err| AppError::InvalidConfig {
+                message: format!("`{}` is not a valid glob, {}", mapping.files, err.msg),
+            })?;
+            let path: FilePath = PathBuf::from(&mapping.schema).into();
+            let bytes = match fs::read(&path) {
+                Ok(it) => it,
+                Err(err) => return Err(AppError::IoErrorPath(err.attach_path(path))),
+            };
+            let root = serde_json::from_slice(&bytes).map_err(|_| AppError::InvalidFileFormat {
+                file_path: path.clone(),
+                expected: "JSON Schema file",
+            })?;
+            schemas.push(Schema {
+                pattern,
+                path,
+                hash: hash(&bytes),
+                root,
+            });
+        }
+        Ok(Schemas(schemas))
+    }
+
+    /// Schemas for a path relative to the revise folder
+    pub fn matching<'a>(&'a self, relative: &'a Path) -> impl Iterator<Item = &'a Schema> + 'a {
+        self.0
+            .iter()
+            .filter(move |schema| schema.pattern.matches_path_with(relative, GLOB_OPTIONS))
+    }
+
+    /// Combined hash of every schema of a file, empty if there are none
+    pub fn hash_for(&self, relative: &Path) -> String {
+        let hashes: Vec<&str> = self
+            .matching(relative)
+            .map(|schema| schema.hash.as_str())
+            .collect();
+        if hashes.is_empty() {
+            String::new()
+        } else {
+            hash(hashes.join("\n").as_bytes())
+        }
+    }
+}
+
+impl Schema {
+    /// Validates a patched document, blaming violations on the patch operations
+    pub fn validate(&self, document: &Value, patch: &Patch) -> Vec<Violation> {
+        let mut validator = Validator {
+            root: &self.root,
+            violations: Vec::new(),
+            resolving: Vec::new(),
+        };
+        validator.validate(&self.root, document, &mut String::new());
+        validator
+            .violations
+            .into_iter()
+            .map(|(pointer, blame, message)| Violation {
+                operation: responsible_operation(patch, &blame),
+                pointer,
+                message,
+            })
+            .collect()
+    }
+}
+
+/// The last operation that touched the pointer, one of its parents or one of its children
+pub fn responsible_operation(patch: &Patch, pointer: &str) -> Option<usize> {
+    let related =
+        |path: &str| path == pointer || is_parent(path, pointer) || is_parent(pointer, path);
+    patch.iter().rposition(|op| match op {
+        PatchOperation::Add(op) => related(&op.path),
+        PatchOperation::Remove(op) => related(&op.path),
+        PatchOperation::Replace(op) => related(&op.path),
+        PatchOperation::Move(op) => related(&op.path) || related(&op.from),
+        PatchOperation::Copy(op) => related(&op.path),
+        PatchOperation::Test(_) => false,
+    })
+}
+
+pub(crate) fn is_parent(parent: &str, child: &str) -> bool {
+    child.starts_with(parent) && child[parent.len()..].starts_with('/')
+}
+
+/// Validates the subset of JSON Schema that describes data shapes:
+/// `type`, `enum`, `const`, `properties`, `required`, `additionalProperties`,
+/// `items`, `prefixItems`, `allOf`, `anyOf`, `oneOf`, `not`, local `$ref`s
+/// and the length and range limits. Other keywords are ignored
+struct Validator<'a> {
+    root: &'a Value,
+    /// Pointer of the value, pointer to blame patch operations with and the message
+    violations: Vec<(String, String, String)>,
+    /// `$ref`s being resolved and the value each is resolved for, a `$ref` that comes
+    /// back to the same value without going into a child is a cycle
+    resolving: Vec<(&'a str, *const Value)>,
+}
+
+impl<'a> Validator<'a> {
+    fn fail(&mut self, pointer: &str, message: String) {
+        self.violations
+            .push((pointer.to_string(), pointer.to_string(), message));
+    }
+
+    /// Fails because of a property, operations on the property are blamed instead of the object
+    fn fail_property(&mut self, pointer: &str, key: &str, message: String) {
+        let blame = format!("{}/{}", pointer, key.replace('~', "~0").replace('/', "~1"));
+        self.violations.push((pointer.to_string(), blame, message));
+    }
+
+    /// Whether the instance matches, without recording violations
+    fn matches(&self, schema: &'a Value, instance: &Value) -> bool {
+        let mut validator = Validator {
+            root: self.root,
+            violations: Vec::new(),
+            resolving: self.resolving.clone(),
+        };
+        validator.validate(schema, instance, &mut String::new());
+        validator.violations.is_empty()
+    }
+
+    fn validate(&mut self, schema: &'a Value, instance: &Value, pointer: &mut String) {
+        let schema = match schema {
+            Value::Bool(true) => return,
+            Value::Bool(false) => return self.fail(pointer, "no value is allowed here".into()),
+            Value::Object(schema) => schema,
+            _ => return,
+        };
+
+        if let Some(Value::String(reference)) = schema.get("$ref") {
+            let resolving = (reference.as_str(), instance as *const Value);
+            match reference
+                .strip_prefix('#')
+                .and_then(|fragment| self.root.pointer(fragment))
+            {
+                _ if self.resolving.contains(&resolving) => self.fail(
+                    pointer,
+                    format!("$ref `{}` refers back to itself", reference),
+                ),
+                Some(target) => {
+                    self.resolving.push(resolving);
+                    self.validate(target, instance, pointer);
+                    self.resolving.pop();
+                }
+                None => self.fail(pointer, format!("cannot resolve $ref `{}`", reference)),
+            }
+        }
+
+        if let Some(types) = schema.get("type") {
+            let allowed: Vec<&str> = match types {
+                Value::String(name) => vec![name],
+                Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+                _ => vec![],
+            };
+            if !allowed.is_empty() && !allowed.iter().any(|name| has_type(instance, name)) {
+                self.fail(
+                    pointer,
+                    format!(
+                        "expected {}, found {}",
+                        allowed.join(" or "),
+                        type_name(instance)
+                    ),
+                );
+            }
+        }
+
+        if let Some(Value::Array(values)) = schema.get("enum") {
+            if !values.contains(instance) {
+                self.fail(
+                    pointer,
+                    format!(
+                        "{} is not one of {}",
+                        instance,
+                        Value::Array(values.clone())
+                    ),
+                );
+            }
+        }
+        if let Some(value) = schema.get("const") {
+            if value != instance {
+                self.fail(pointer, format!("expected {}, found {}", value, instance));
+            }
+        }
+
+        for (keyword, schemas) in ["allOf", "anyOf", "oneOf"]
+            .iter()
+            .filter_map(|keyword| Some((*keyword, schema.get(*keyword)?.as_array()?)))
+        {
+            match keyword {
+                "allOf" => {
+                    for schema in schemas {
+                        self.validate(schema, instance, pointer);
+                    }
+                }
+                "anyOf" => {
+                    if !schemas.iter().any(|schema| self.matches(schema, instance)) {
+                        self.fail(pointer, "does not match any of the `anyOf` schemas".into());
+                    }
+                }
+                _ => {
+                    let count = schemas
+                        .iter()
+                        .filter(|schema| self.matches(schema, instance))
+                        .count();
+                    if count != 1 {
+                        self.fail(
+                            pointer,
+                            format!("matches {} of the `oneOf` schemas instead of 1", count),
+                        );
+                    }
+                }
+            }
+        }
+        if let Some(not) = schema.get("not") {
+            if self.matches(not, instance) {
+                self.fail(pointer, "matches the `not` schema".into());
+            }
+        }
+
+        match instance {
+            Value::Number(number) => {
+                let number = number.as_f64().unwrap_or(f64::NAN);
+                let limit = |keyword: &str| schema.get(keyword).and_then(Value::as_f64);
+                if let Some(minimum) = limit("minimum").filter(|minimum| number < *minimum) {
+                    self.fail(pointer, format!("{} is less than {}", number, minimum));
+                }
+                if let Some(maximum) = limit("maximum").filter(|maximum| number > *maximum) {
+                    self.fail(pointer, format!("{} is more than {}", number, maximum));
+                }
+                if let Some(minimum) =
+                    limit("exclusiveMinimum").filter(|minimum| number <= *minimum)
+                {
+                    self.fail(pointer, format!("{} is not more than {}", number, minimum));
+                }
+                if let Some(maximum) =
+                    limit("exclusiveMaximum").filter(|maximum| number >= *maximum)
+                {
+                    self.fail(pointer, format!("{} is not less than {}", number, maximum));
+                }
+            }
+            Value::String(string) => {
+                let length = string.chars().count() as u64;
+                let limit = |keyword: &str| schema.get(keyword).and_then(Value::as_u64);
+                if let Some(minimum) = limit("minLength").filter(|minimum| length < *minimum) {
+                    self.fail(
+                        pointer,
+                        format!("string is shorter than {} characters", minimum),
+                    );
+                }
+                if let Some(maximum) = limit("maxLength").filter(|maximum| length > *maximum) {
+                    self.fail(
+                        pointer,
+                        format!("string is longer than {} characters", maximum),
+                    );
+                }
+            }
+            Value::Array(items) => {
+                let limit = |keyword: &str| schema.get(keyword).and_then(Value::as_u64);
+                let length = items.len() as u64;
+                if let Some(minimum) = limit("minItems").filter(|minimum| length < *minimum) {
+                    self.fail(pointer, format!("array has less than {} items", minimum));
+                }
+                if let Some(maximum) = limit("maxItems").filter(|maximum| length > *maximum) {
+                    self.fail(pointer, format!("array has more than {} items", maximum));
+                }
+                if schema.get("uniqueItems") == Some(&Value::Bool(true)) {
+                    for (index, item) in items.iter().enumerate() {
+                        if items[..index].contains(item) {
+                            self.fail(pointer, format!("item {} is a duplicate", index));
+                        }
+                    }
+                }
+
+                // `items` as an array is the older spelling of `prefixItems`
+                let (prefix, rest) = match (schema.get("prefixItems"), schema.get("items")) {
+                    (Some(Value::Array(prefix)), rest) => (prefix.as_slice(), rest),
+                    (None, Some(Value::Array(prefix))) => {
+                        (prefix.as_slice(), schema.get("additionalItems"))
+                    }
+                    (_, rest) => (&[][..], rest),
+                };
+                for (index, item) in items.iter().enumerate() {
+                    let item_schema = match prefix.get(index) {
+                        Some(schema) => schema,
+                        None => match rest {
+                            Some(schema) => schema,
+                            None => continue,
+                        },
+                    };
+                    let len = pointer.len();
+                    pointer.push_str(&format!("/{}", index));
+                    self.validate(item_schema, item, pointer);
+                    pointer.truncate(len);
+                }
+            }
+            Value::Object(object) => {
+                let limit = |keyword: &str| schema.get(keyword).and_then(Value::as_u64);
+                let length = object.len() as u64;
+                if let Some(minimum) = limit("minProperties").filter(|minimum| length < *minimum) {
+                    self.fail(
+                        pointer,
+                        format!("object has less than {} properties", minimum),
+                    );
+                }
+                if let Some(maximum) = limit("maxProperties").filter(|maximum| length > *maximum) {
+                    self.fail(
+                        pointer,
+                        format!("object has more than {} properties", maximum),
+                    );
+                }
+
+                if let Some(Value::Array(required)) = schema.get("required") {
+                    for key in required.iter().filter_map(Value::as_str) {
+                        if !object.contains_key(key) {
+                            self.fail_property(
+                                pointer,
+                                key,
+                                format!("missing required property `{}`", key),
+                            );
+                        }
+                    }
+                }
+
+                let properties = schema.get("properties").and_then(Value::as_object);
+                let additional = schema.get("additionalProperties");
+                for (key, value) in object {
+                    let property_schema =
+                        match properties.and_then(|properties| properties.get(key)) {
+                            Some(schema) => schema,
+                            None => match additional {
+                                Some(Value::Bool(false)) => {
+                                    self.fail_property(
+                                        pointer,
+                                        key,
+                                        format!("property `{}` is not allowed", key),
+                                    );
+                                    continue;
+                                }
+                                Some(schema) => schema,
+                                None => continue,
+                            },
+                        };
+                    let len = pointer.len();
+                    pointer.push('/');
+                    pointer.push_str(&key.replace('~', "~0").replace('/', "~1"));
+                    self.validate(property_schema, value, pointer);
+                    pointer.truncate(len);
+                }
+            }
+            Value::Null | Value::Bool(_) => (),
+        }
+    }
+}
+
+fn has_type(instance: &Value, name: &str) -> bool {
+    match name {
+        "null" => instance.is_null(),
+        "boolean" => instance.is_boolean(),
+        "object" => instance.is_object(),
+        "array" => instance.is_array(),
+        "string" => instance.is_string(),
+        "number" => instance.is_number(),
+        "integer" => match instance {
+            Value::Number(number) => {
+                number.is_i64()
+                    || number.is_u64()
+                    || number.as_f64().is_some_and(|n| n.fract() == 0.0)
+            }
+            _ => false,
+        },
+        _ => true,
+    }
+}
+
+pub fn type_name(instance: &Value) -> &'static str {
+    match instance {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(number) if number.is_i64() || number.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{from_value, json};
+
+    use super::*;
+
+    fn schema(root: Value) -> Schema {
+        Schema {
+            pattern: Pattern::new("**/*.json").unwrap(),
+            path: PathBuf::from("schema.json").into(),
+            hash: String::new(),
+            root,
+        }
+    }
+
+    /// Pointer, message and blamed operation of every violation
+    fn violations(
+        root: Value,
+        document: Value,
+        patch: Value,
+    ) -> Vec<(String, String, Option<usize>)> {
+        let patch: Patch = from_value(patch).unwrap();
+        schema(root)
+            .validate(&document, &patch)
+            .into_iter()
+            .map(|violation| (violation.pointer, violation.message, violation.operation))
+            .collect()
+    }
+
+    #[test]
+    fn type_mismatch() {
+        assert_eq!(
+            violations(
+                json!({ "properties": { "a": { "type": ["string", "null"] } } }),
+                json!({ "a": 1 }),
+                json!([])
+            ),
+            [(
+                "/a".to_string(),
+                "expected string or null, found integer".to_string(),
+                None
+            )]
+        );
+        assert!(violations(json!({ "type": "integer" }), json!(2.0), json!([])).is_empty());
+    }
+
+    #[test]
+    fn missing_required_property_blames_its_removal() {
+        assert_eq!(
+            violations(
+                json!({ "required": ["name"] }),
+                json!({ "other": 1 }),
+                json!([
+                    { "op": "remove", "path": "/name" },
+                    { "op": "add", "path": "/other", "value": 1 }
+                ])
+            ),
+            [(
+                String::new(),
+                "missing required property `name`".to_string(),
+                Some(0)
+            )]
+        );
+    }
+
+    #[test]
+    fn value_not_in_enum() {
+        assert_eq!(
+            violations(
+                json!({ "items": { "enum": ["a", "b"] } }),
+                json!(["a", "c"]),
+                json!([{ "op": "replace", "path": "/1", "value": "c" }])
+            ),
+            [(
+                "/1".to_string(),
+                r#""c" is not one of ["a","b"]"#.to_string(),
+                Some(0)
+            )]
+        );
+    }
+
+    #[test]
+    fn recursive_ref() {
+        let root = json!({
+            "$ref": "#/$defs/node",
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": { "next": { "$ref": "#/$defs/node" } }
+                }
+            }
+        });
+        assert!(violations(root.clone(), json!({ "next": { "next": {} } }), json!([])).is_empty());
+        assert_eq!(
+            violations(root, json!({ "next": { "next": 1 } }), json!([])),
+            [(
+                "/next/next".to_string(),
+                "expected object, found integer".to_string(),
+                None
+            )]
+        );
+    }
+
+    #[test]
+    fn ref_cycles_are_violations() {
+        assert_eq!(
+            violations(json!({ "$ref": "#" }), json!(1), json!([])),
+            [(
+                String::new(),
+                "$ref `#` refers back to itself".to_string(),
+                None
+            )]
+        );
+        let root = json!({
+            "$defs": {
+                "a": { "$ref": "#/$defs/b" },
+                "b": { "anyOf": [{ "$ref": "#/$defs/a" }] }
+            },
+            "properties": { "x": { "$ref": "#/$defs/a" } }
+        });
+        assert_eq!(violations(root, json!({ "x": 1 }), json!([])).len(), 1);
+        assert_eq!(
+            violations(json!({ "$ref": "#/missing" }), json!(1), json!([])),
+            [(
+                String::new(),
+                "cannot resolve $ref `#/missing`".to_string(),
+                None
+            )]
+        );
+    }
+
+    #[test]
+    fn blames_last_operation_on_the_value_or_its_parents() {
+        let patch: Patch = from_value(json!([
+            { "op": "add", "path": "/a", "value": {} },
+            { "op": "replace", "path": "/a/b", "value": 1 },
+            { "op": "test", "path": "/a/b", "value": 1 },
+            { "op": "move", "from": "/a/c", "path": "/d" }
+        ]))
+        .unwrap();
+        assert_eq!(responsible_operation(&patch, "/a/b"), Some(1));
+        assert_eq!(responsible_operation(&patch, "/a/c/e"), Some(3));
+        assert_eq!(responsible_operation(&patch, "/a"), Some(3));
+        assert_eq!(responsible_operation(&patch, "/other"), None);
+    }
+}
@@ -0,0 +1,577 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use serde_json::{json, Map, Value};
+
+use crate::upstream::escape_token;
+
+/// Strings with at most this many different values can become an enum
+pub const ENUM_LIMIT: usize = 32;
+
+/// What the values at one place of the documents were seen to be, or are declared to be
+/// by a JSON Schema
+#[derive(Default, Clone)]
+pub struct Shape {
+    /// Any value at all
+    pub any: bool,
+    pub null: bool,
+    pub boolean: bool,
+    pub integer: bool,
+    /// Numbers with a fraction
+    pub number: bool,
+    pub string: Option<Strings>,
+    /// Shape of the items of the arrays
+    pub array: Option<Box<Shape>>,
+    pub object: Option<Fields>,
+    /// Keys whose value is different for every item of each array with more than one item,
+    /// `None` until such an array was seen
+    pub identity_keys: Option<BTreeSet<String>>,
+}
+
+#[derive(Default, Clone)]
+pub struct Strings {
+    /// The different values, only kept up to [`ENUM_LIMIT`]
+    values: BTreeSet<String>,
+    occurrences: usize,
+    /// There were more different values than are kept
+    open: bool,
+    /// The values are all the schema allows
+    declared: bool,
+}
+
+#[derive(Default, Clone)]
+pub struct Fields {
+    /// How many objects were seen
+    pub occurrences: usize,
+    pub fields: BTreeMap<String, Field>,
+}
+
+#[derive(Default, Clone)]
+pub struct Field {
+    pub shape: Shape,
+    /// How many of the objects have the field
+    pub occurrences: usize,
+}
+
+impl Shape {
+    /// The shape of every value of the documents
+    pub fn of<'a>(documents: impl IntoIterator<Item = &'a Value>) -> Self {
+        let mut shape = Shape::default();
+        for document in documents {
+            shape.observe(document);
+        }
+        shape
+    }
+
+    pub fn observe(&mut self, value: &Value) {
+        match value {
+            Value::Null => self.null = true,
+            Value::Bool(_) => self.boolean = true,
+            Value::Number(number) if number.is_f64() => self.number = true,
+            Value::Number(_) => self.integer = true,
+            Value::String(string) => self
+                .string
+                .get_or_insert_with(Strings::default)
+                .observe(string),
+            Value::Array(items) => {
+                if items.len() > 1 {
+                    let keys = identifying_keys(items);
+                    self.identity_keys = Some(match self.identity_keys.take() {
+                        Some(known) => known.intersection(&keys).cloned().collect(),
+                        None => keys,
+                    });
+                }
+                let shape = self.array.get_or_insert_with(Default::default);
+                for item in items {
+                    shape.observe(item);
+                }
+            }
+            Value::Object(members) => {
+                let fields = self.object.get_or_insert_with(Fields::default);
+                fields.occurrences += 1;
+                for (key, value) in members {
+                    let field = fields.fields.entry(key.clone()).or_default();
+                    field.occurrences += 1;
+                    field.shape.observe(value);
+                }
+            }
+        }
+    }
+
+    /// Combines what both shapes allow
+    pub fn merge(&mut self, other: Shape) {
+        self.any |= other.any;
+        self.null |= other.null;
+        self.boolean |= other.boolean;
+        self.integer |= other.integer;
+        self.number |= other.number;
+        if let Some(other) = other.string {
+            match &mut self.string {
+                Some(strings) => strings.merge(other),
+                None => self.string = Some(other),
+            }
+        }
+        if let Some(other) = other.array {
+            match &mut self.array {
+                Some(items) => items.merge(*other),
+                None => self.array = Some(other),
+            }
+        }
+        if let Some(other) = other.object {
+            match &mut self.object {
+                Some(fields) => fields.merge(other),
+                None => self.object = Some(other),
+            }
+        }
+        if let Some(other) = other.identity_keys {
+            self.identity_keys = Some(match self.identity_keys.take() {
+                Some(known) => known.intersection(&other).cloned().collect(),
+                None => other,
+            });
+        }
+    }
+
+    /// How many kinds of values other than `null` the shape allows, integers and numbers
+    /// count as one
+    pub fn kinds(&self) -> usize {
+        [
+            self.boolean,
+            self.integer || self.number,
+            self.string.is_some(),
+            self.array.is_some(),
+            self.object.is_some(),
+        ]
+        .into_iter()
+        .filter(|&kind| kind)
+        .count()
+    }
+
+    /// A JSON Schema allowing what the shape allows. Strings that are an enum get an `enum`
+    /// and arrays whose items have identity keys list them in `x-identity-keys`
+    pub fn to_schema(&self) -> Value {
+        if self.any {
+            return json!({});
+        }
+        let mut schema = Map::new();
+        let mut types = Vec::new();
+        if self.null {
+            types.push("null");
+        }
+        if self.boolean {
+            types.push("boolean");
+        }
+        if self.number {
+            types.push("number");
+        } else if self.integer {
+            types.push("integer");
+        }
+        if let Some(strings) = &self.string {
+            types.push("string");
+            // An enum of only strings would reject the other types
+            if let Some(values) = strings.enumeration().filter(|_| self.kinds() == 1) {
+                let mut values: Vec<Value> = values.iter().cloned().map(Value::String).collect();
+                if self.null {
+                    values.push(Value::Null);
+                }
+                schema.insert("enum".to_string(), Value::Array(values));
+            }
+        }
+        if let Some(items) = &self.array {
+            types.push("array");
+            let keys: Vec<&String> = self.identity_keys.iter().flatten().collect();
+            if items.any || items.null || items.kinds() > 0 {
+                let mut items = items.to_schema();
+                // Values that tell the items apart are not a fixed set
+                for key in &keys {
+                    let pointer = format!("/properties/{}", escape_token(key));
+                    if let Some(Value::Object(property)) = items.pointer_mut(&pointer) {
+                        property.remove("enum");
+                    }
+                }
+                schema.insert("items".to_string(), items);
+            }
+            if !keys.is_empty() {
+                schema.insert("x-identity-keys".to_string(), json!(keys));
+            }
+        }
+        if let Some(fields) = &self.object {
+            types.push("object");
+            let properties: Map<String, Value> = fields
+                .fields
+                .iter()
+                .map(|(key, field)| (key.clone(), field.shape.to_schema()))
+                .collect();
+            let required: Vec<&String> = fields
+                .fields
+                .iter()
+                .filter(|(_, field)| fields.is_required(field))
+                .map(|(key, _)| key)
+                .collect();
+            if !properties.is_empty() {
+                schema.insert("properties".to_string(), Value::Object(properties));
+            }
+            if !required.is_empty() {
+                schema.insert("required".to_string(), json!(required));
+            }
+        }
+        match types.as_slice() {
+            [] => (),
+            [name] => {
+                schema.insert("type".to_string(), json!(name));
+            }
+            _ => {
+                schema.insert("type".to_string(), json!(types));
+            }
+        }
+        Value::Object(schema)
+    }
+
+    /// Reads the shape a JSON Schema declares.
+    ///
+    /// `type`, `enum`, `const`, `properties`, `required`, `items`, `anyOf`, `oneOf`,
+    /// `allOf` and `$ref`s into the same schema are understood. A `$ref` back into a schema
+    /// it is part of allows any value, the shape has no way to refer to itself
+    pub fn from_schema(schema: &Value) -> Self {
+        read_schema(schema, schema, &mut Vec::new())
+    }
+}
+
+impl Strings {
+    fn observe(&mut self, value: &str) {
+        self.occurrences += 1;
+        if !self.open && !self.values.contains(value) {
+            if self.values.len() == ENUM_LIMIT {
+                self.open = true;
+                self.values.clear();
+            } else {
+                self.values.insert(value.to_string());
+            }
+        }
+    }
+
+    fn merge(&mut self, other: Strings) {
+        self.occurrences += other.occurrences;
+        self.declared &= other.declared;
+        self.open |= other.open;
+        if !self.open {
+            self.values.extend(other.values);
+            if self.values.len() > ENUM_LIMIT && !self.declared {
+                self.open = true;
+            }
+        }
+        if self.open {
+            self.values.clear();
+        }
+    }
+
+    /// The values if there are few enough of them, and they repeat, to be an enum
+    pub fn enumeration(&self) -> Option<&BTreeSet<String>> {
+        if self.open || self.values.is_empty() {
+            return None;
+        }
+        (self.declared || self.values.len() * 2 <= self.occurrences).then_some(&self.values)
+    }
+}
+
+impl Fields {
+    fn merge(&mut self, other: Fields) {
+        self.occurrences += other.occurrences;
+        for (key, other) in other.fields {
+            let field = self.fields.entry(key).or_default();
+            field.occurrences += other.occurrences;
+            field.shape.merge(other.shape);
+        }
+    }
+
+    /// Whether every object has the field
+    pub fn is_required(&self, field: &Field) -> bool {
+        field.occurrences == self.occurrences
+    }
+}
+
+/// Keys of the first item that every item has, with a different scalar value each
+fn identifying_keys(items: &[Value]) -> BTreeSet<String> {
+    let Some(Value::Object(first)) = items.first() else {
+        return BTreeSet::new();
+    };
+    first
+        .keys()
+        .filter(|key| {
+            let mut seen = HashSet::new();
+            items.iter().all(|item| match item.get(key.as_str()) {
+                Some(value @ (Value::String(_) | Value::Number(_) | Value::Bool(_))) => {
+                    seen.insert(value.to_string())
+                }
+                _ => false,
+            })
+        })
+        .cloned()
+        .collect()
+}
+
+/// `references` are the `$ref`s being read
+fn read_schema<'a>(schema: &'a Value, root: &'a Value, references: &mut Vec<&'a str>) -> Shape {
+    let any = Shape {
+        any: true,
+        ..Shape::default()
+    };
+    let schema = match schema {
+        Value::Bool(true) => return any,
+        Value::Object(schema) => schema,
+        _ => return Shape::default(),
+    };
+
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        let target = reference
+            .strip_prefix('#')
+            .and_then(|pointer| root.pointer(pointer));
+        return match target {
+            Some(target) if !references.contains(&reference) => {
+                references.push(reference);
+                let shape = read_schema(target, root, references);
+                references.pop();
+                shape
+            }
+            _ => any,
+        };
+    }
+
+    let values = match (schema.get("enum"), schema.get("const")) {
+        (Some(Value::Array(values)), _) => Some(values.as_slice()),
+        (_, Some(value)) => Some(std::slice::from_ref(value)),
+        _ => None,
+    };
+    if let Some(values) = values {
+        let mut shape = Shape::of(values);
+        if let Some(strings) = &mut shape.string {
+            strings.declared = true;
+        }
+        return shape;
+    }
+
+    let mut shape = Shape::default();
+    let mut declared = false;
+    for keyword in ["anyOf", "oneOf", "allOf"] {
+        if let Some(Value::Array(schemas)) = schema.get(keyword) {
+            for schema in schemas {
+                shape.merge(read_schema(schema, root, references));
+            }
+            declared = true;
+        }
+    }
+
+    let types: Vec<&str> = match schema.get("type") {
+        Some(Value::String(name)) => vec![name.as_str()],
+        Some(Value::Array(names)) => names.iter().filter_map(Value::as_str).collect(),
+        _ if schema.contains_key("properties") => vec!["object"],
+        _ if schema.contains_key("items") => vec!["array"],
+        _ if declared => Vec::new(),
+        _ => return any,
+    };
+    for name in types {
+        match name {
+            "null" => shape.null = true,
+            "boolean" => shape.boolean = true,
+            "integer" => shape.integer = true,
+            "number" => shape.number = true,
+            "string" => shape.merge(Shape {
+                string: Some(Strings {
+                    open: true,
+                    ..Strings::default()
+                }),
+                ..Shape::default()
+            }),
+            "array" => {
+                let items = schema
+                    .get("items")
+                    .map_or(any.clone(), |items| read_schema(items, root, references));
+                shape.merge(Shape {
+                    array: Some(Box::new(items)),
+                    ..Shape::default()
+                });
+            }
+            "object" => {
+                let required: Vec<&str> = schema
+                    .get("required")
+                    .and_then(Value::as_array)
+                    .map(|names| names.iter().filter_map(Value::as_str).collect())
+                    .unwrap_or_default();
+                let fields = schema
+                    .get("properties")
+                    .and_then(Value::as_object)
+                    .into_iter()
+                    .flatten()
+                    .map(|(key, property)| {
+                        let field = Field {
+                            shape: read_schema(property, root, references),
+                            occurrences: usize::from(required.contains(&key.as_str())),
+                        };
+                        (key.clone(), field)
+                    })
+                    .collect();
+                shape.merge(Shape {
+                    object: Some(Fields {
+                        occurrences: 1,
+                        fields,
+                    }),
+                    ..Shape::default()
+                });
+            }
+            _ => (),
+        }
+    }
+    shape
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn strings(values: &[&str]) -> Strings {
+        let mut strings = Strings::default();
+        for value in values {
+            strings.observe(value);
+        }
+        strings
+    }
+
+    #[test]
+    fn strings_that_repeat_are_an_enum() {
+        let values = |strings: &Strings| {
+            strings
+                .enumeration()
+                .map(|values| values.iter().cloned().collect::<Vec<_>>())
+        };
+        assert_eq!(
+            values(&strings(&["a", "b", "a", "b"])),
+            Some(vec!["a".to_string(), "b".to_string()])
+        );
+        assert_eq!(values(&strings(&["a", "b", "c", "a"])), None);
+        assert_eq!(values(&strings(&[])), None);
+
+        let many: Vec<String> = (0..=ENUM_LIMIT).map(|index| index.to_string()).collect();
+        let many: Vec<&str> = many.iter().chain(&many).map(String::as_str).collect();
+        let open = strings(&many);
+        assert!(open.open);
+        assert_eq!(values(&open), None);
+
+        // Merging keeps counting towards the limit
+        let mut merged = strings(&many[..ENUM_LIMIT]);
+        merged.merge(strings(&many[ENUM_LIMIT + 1..2 * ENUM_LIMIT + 1]));
+        assert!(!merged.open);
+        merged.merge(strings(&many[ENUM_LIMIT..ENUM_LIMIT + 1]));
+        assert!(merged.open);
+    }
+
+    #[test]
+    fn fields_missing_from_some_objects_are_optional() {
+        let shape = Shape::of(&[json!({ "a": 1, "b": "x" }), json!({ "a": 2.5 })]);
+        assert_eq!(
+            shape.to_schema(),
+            json!({
+                "type": "object",
+                "properties": {
+                    "a": { "type": "number" },
+                    "b": { "type": "string" },
+                },
+                "required": ["a"],
+            })
+        );
+    }
+
+    #[test]
+    fn schema_of_arrays_with_identity_keys() {
+        let shape = Shape::of(&[json!({
+            "items": [
+                { "id": "a", "kind": "x" },
+                { "id": "b", "kind": "x" },
+                { "id": "c", "kind": "x" },
+            ],
+            "value": null,
+        })]);
+        assert_eq!(
+            shape.to_schema(),
+            json!({
+                "type": "object",
+                "properties": {
+                    "items": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "id": { "type": "string" },
+                                "kind": { "type": "string", "enum": ["x"] },
+                            },
+                            "required": ["id", "kind"],
+                        },
+                        "x-identity-keys": ["id"],
+                    },
+                    "value": { "type": "null" },
+                },
+                "required": ["items", "value"],
+            })
+        );
+    }
+
+    #[test]
+    fn reads_schemas() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "kind": { "enum": ["a", "b"] },
+                "count": { "type": ["integer", "null"] },
+                "tags": { "type": "array", "items": { "type": "string" } },
+                "extra": {},
+            },
+            "required": ["kind"],
+        });
+        let shape = Shape::from_schema(&schema);
+        let fields = shape.object.as_ref().unwrap();
+        let field = |key: &str| &fields.fields[key];
+        assert!(fields.is_required(field("kind")));
+        assert!(!fields.is_required(field("count")));
+        assert_eq!(
+            field("kind")
+                .shape
+                .string
+                .as_ref()
+                .unwrap()
+                .enumeration()
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(field("count").shape.integer && field("count").shape.null);
+        assert!(field("tags").shape.array.as_ref().unwrap().string.is_some());
+        assert!(field("extra").shape.any);
+        assert_eq!(
+            Shape::from_schema(&shape.to_schema()).to_schema(),
+            shape.to_schema()
+        );
+    }
+
+    #[test]
+    fn recursive_references_allow_any_value() {
+        let schema = json!({
+            "$ref": "#/$defs/node",
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {
+                        "name": { "type": "string" },
+                        "children": { "type": "array", "items": { "$ref": "#/$defs/node" } },
+                    },
+                },
+            },
+        });
+        let shape = Shape::from_schema(&schema);
+        let fields = &shape.object.as_ref().unwrap().fields;
+        let items = fields["children"].shape.array.as_ref().unwrap();
+        assert!(items.any);
+        assert!(fields["name"].shape.string.is_some());
+
+        // A reference that does not resolve
+        assert!(Shape::from_schema(&json!({ "$ref": "other.json" })).any);
+    }
+}
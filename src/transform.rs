@@ -0,0 +1,291 @@
+use std::{
+    fs,
+    io::{Read, Write},
+    path::Path,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{cache::hash, error::AppError, file_trio::FilePath, schema::GLOB_OPTIONS};
+
+const DEFAULT_TIMEOUT: u64 = 30;
+
+/// How often a running plugin is checked on
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// An external command that transforms built documents, run after the patches
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TransformStep {
+    /// Shown in errors
+    pub name: String,
+    /// Program and its arguments, run from the project folder
+    pub command: Vec<String>,
+    /// Glob of paths relative to the revise folder, every file if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<String>,
+    /// Seconds the command may take per file
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_timeout() -> u64 {
+    DEFAULT_TIMEOUT
+}
+
+pub struct Transform {
+    pub name: String,
+    command: Vec<String>,
+    pattern: Option<Pattern>,
+    timeout: Duration,
+    /// Hash of the step and of the files its arguments name, such as its script
+    hash: String,
+}
+
+/// Every transform of the project, in the order they are chained
+#[derive(Default)]
+pub struct Transforms(Vec<Transform>);
+
+/// Paths of the file being built, given to the plugin with the document
+pub struct TransformInput<'a> {
+    pub relative: &'a Path,
+    pub original: &'a Path,
+    pub revise: &'a Path,
+}
+
+impl Transforms {
+    pub fn load(steps: &[TransformStep]) -> Result<Self, AppError<'static>> {
+        let mut transforms = Vec::new();
+        for step in steps {
+            if step.command.is_empty() {
+                return Err(AppError::InvalidConfig {
+                    message: format!("transform `{}` has an empty command", step.name),
+                });
+            }
+            let pattern = match &step.files {
+                Some(files) => {
+                    Some(Pattern::new(files).map_err(|err| AppError::InvalidConfig {
+                        message: format!("`{}` is not a valid glob, {}", files, err.msg),
+                    })?)
+                }
+                None => None,
+            };
+
+            let mut hashed = serde_json::to_string(step).expect("Transform steps always serialize");
+            for argument in &step.command {
+                if let Ok(bytes) = fs::read(argument) {
+                    hashed.push_str(&hash(&bytes));
+                }
+            }
+
+            transforms.push(Transform {
+                name: step.name.clone(),
+                command: step.command.clone(),
+                pattern,
+                timeout: Duration::from_secs(step.timeout),
+                hash: hash(hashed.as_bytes()),
+            });
+        }
+        Ok(Transforms(transforms))
+    }
+
+    /// Transforms of a path relative to the revise folder, in order
+    pub fn matching<'a>(&'a self, relative: &'a Path) -> Vec<&'a Transform> {
+        self.0
+            .iter()
+            .filter(|transform| {
+                transform
+                    .pattern
+                    .as_ref()
+                    .is_none_or(|pattern| pattern.matches_path_with(relative, GLOB_OPTIONS))
+            })
+            .collect()
+    }
+
+    /// Combined hash of every transform of a file, empty if there are none
+    pub fn hash_for(&self, relative: &Path) -> String {
+        let hashes: Vec<&str> = self
+            .matching(relative)
+            .iter()
+            .map(|transform| transform.hash.as_str())
+            .collect();
+        if hashes.is_empty() {
+            String::new()
+        } else {
+            hash(hashes.join("\n").as_bytes())
+        }
+    }
+}
+
+impl Transform {
+    /// Runs the command with `{"file": {...}, "document": ...}` on stdin
+    /// and reads the transformed document from its stdout
+    pub fn run(&self, document: Value, input: &TransformInput) -> Result<Value, String> {
+        let stdin = serde_json::to_vec(&json!({
+            "file": {
+                "path": input.relative,
+                "original": input.original,
+                "revise": input.revise,
+            },
+            "document": document,
+        }))
+        .expect("Documents always serialize");
+
+        let mut command = Command::new(&self.command[0]);
+        command
+            .args(&self.command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        // In a group of its own, so the processes it starts can be stopped with it
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let mut child = command
+            .spawn()
+            .map_err(|err| format!("cannot run `{}`, {}", self.command[0], err))?;
+
+        // Written and read on their own threads so a plugin that streams cannot deadlock
+        let mut child_stdin = child.stdin.take().expect("stdin is piped");
+        let mut child_stdout = child.stdout.take().expect("stdout is piped");
+        let mut child_stderr = child.stderr.take().expect("stderr is piped");
+        let writer = thread::spawn(move || child_stdin.write_all(&stdin));
+        let reader = thread::spawn(move || {
+            let mut buf = Vec::new();
+            child_stdout.read_to_end(&mut buf).map(|_| buf)
+        });
+        let error_reader = thread::spawn(move || {
+            let mut buf = String::new();
+            child_stderr.read_to_string(&mut buf).map(|_| buf)
+        });
+
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) if Instant::now() >= deadline => {
+                    kill(&mut child);
+                    let _ = child.wait();
+                    // The threads are left to finish on their own, a process that left
+                    // the group could still hold the pipes open
+                    return Err(format!(
+                        "timed out after {} seconds",
+                        self.timeout.as_secs_f64()
+                    ));
+                }
+                Ok(None) => thread::sleep(POLL_INTERVAL),
+                Err(err) => return Err(err.to_string()),
+            }
+        };
+
+        // A plugin that does not read its input is fine as long as it succeeds
+        let _ = writer.join();
+        let stdout = reader
+            .join()
+            .expect("The reader thread does not panic")
+            .map_err(|err| format!("cannot read its output, {}", err))?;
+        let stderr = error_reader
+            .join()
+            .expect("The reader thread does not panic")
+            .unwrap_or_default();
+
+        if !status.success() {
+            let stderr = stderr.trim();
+            return Err(if stderr.is_empty() {
+                format!("exited with {}", status)
+            } else {
+                format!("exited with {}: {}", status, stderr)
+            });
+        }
+        serde_json::from_slice(&stdout)
+            .map_err(|err| format!("did not print a JSON document, {}", err))
+    }
+}
+
+/// Stops the plugin and every process it started
+#[cfg(unix)]
+fn kill(child: &mut Child) {
+    match libc::pid_t::try_from(child.id()) {
+        // SAFETY: only sends a signal, to the group the plugin was spawned in
+        Ok(group) if unsafe { libc::kill(-group, libc::SIGKILL) } == 0 => (),
+        _ => {
+            let _ = child.kill();
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+}
+
+/// Runs the transforms one after another, each on the output of the previous one
+pub fn run_all(
+    transforms: &[&Transform],
+    mut document: Value,
+    input: &TransformInput,
+    file_path: &FilePath,
+) -> Result<Value, AppError<'static>> {
+    for transform in transforms {
+        document = transform
+            .run(document, input)
+            .map_err(|message| AppError::TransformFailed {
+                file_path: file_path.clone(),
+                transform: transform.name.clone(),
+                message,
+            })?;
+    }
+    Ok(document)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn transform(script: &str, timeout: Duration) -> Transform {
+        Transform {
+            name: "test".to_string(),
+            command: vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+            pattern: None,
+            timeout,
+            hash: String::new(),
+        }
+    }
+
+    fn input() -> TransformInput<'static> {
+        TransformInput {
+            relative: Path::new("a.json"),
+            original: Path::new("original/a.json"),
+            revise: Path::new("revise/a.json"),
+        }
+    }
+
+    #[test]
+    fn reads_the_transformed_document() {
+        let transform = transform("cat", Duration::from_secs(DEFAULT_TIMEOUT));
+        let output = transform.run(json!({ "a": 1 }), &input()).unwrap();
+        assert_eq!(output["document"], json!({ "a": 1 }));
+        assert_eq!(output["file"]["path"], json!("a.json"));
+    }
+
+    #[test]
+    fn reports_failures() {
+        let transform = transform("echo broken >&2; exit 3", Duration::from_secs(5));
+        let message = transform.run(json!({}), &input()).unwrap_err();
+        assert!(message.ends_with(": broken"), "{}", message);
+    }
+
+    #[test]
+    fn stops_a_plugin_that_takes_too_long() {
+        // The shell waits for `sleep`, which has the pipes as well
+        let transform = transform("sleep 10; cat", Duration::from_millis(50));
+        let started = Instant::now();
+        let message = transform.run(json!({}), &input()).unwrap_err();
+        assert_eq!(message, "timed out after 0.05 seconds");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,564 @@
+use std::collections::{HashMap, HashSet};
+
+use colored::{ColoredString, Colorize};
+use serde::Serialize;
+use serde_json::Value;
+
+/// Longest preview of a value in the terminal and Markdown reports
+const PREVIEW_LENGTH: usize = 60;
+
+/// How `upstream-diff` prints its report
+#[derive(Clone, Copy)]
+pub enum ReportFormat {
+    Terminal,
+    Markdown,
+    Json,
+}
+
+impl ReportFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "terminal" => Some(ReportFormat::Terminal),
+            "markdown" => Some(ReportFormat::Markdown),
+            "json" => Some(ReportFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl ChangeKind {
+    fn sign(self) -> &'static str {
+        match self {
+            ChangeKind::Added => "+",
+            ChangeKind::Removed => "-",
+            ChangeKind::Changed => "~",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            ChangeKind::Added => "added",
+            ChangeKind::Removed => "removed",
+            ChangeKind::Changed => "changed",
+        }
+    }
+}
+
+/// A value of a document that differs between the two versions
+#[derive(Serialize)]
+pub struct EntryChange {
+    pub kind: ChangeKind,
+    /// Readable path, array items matched by an identity key are written as `[key=value]`
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_pointer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_pointer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new: Option<Value>,
+}
+
+/// A file that was added, removed or changed, changed files list their entries
+#[derive(Serialize)]
+pub struct FileChange {
+    pub path: String,
+    pub kind: ChangeKind,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<EntryChange>,
+}
+
+#[derive(Serialize)]
+pub struct UpstreamReport {
+    pub files: Vec<FileChange>,
+}
+
+/// Every entry that differs between the two documents.
+///
+/// Items of arrays are matched by the first of `identity_keys` that every item of
+/// both arrays has with a unique value, and by their index otherwise
+pub fn compare(old: &Value, new: &Value, identity_keys: &[String]) -> Vec<EntryChange> {
+    let mut changes = Vec::new();
+    compare_at(old, new, &Location::default(), identity_keys, &mut changes);
+    changes
+}
+
+/// Where the compared values are in both documents
+#[derive(Default, Clone)]
+struct Location {
+    path: String,
+    old_pointer: String,
+    new_pointer: String,
+}
+
+impl Location {
+    fn member(&self, key: &str) -> Self {
+        let token = escape_token(key);
+        Location {
+            path: format!("{}/{}", self.path, token),
+            old_pointer: format!("{}/{}", self.old_pointer, token),
+            new_pointer: format!("{}/{}", self.new_pointer, token),
+        }
+    }
+
+    fn item(&self, label: String, old_index: usize, new_index: usize) -> Self {
+        Location {
+            path: format!("{}{}", self.path, label),
+            old_pointer: format!("{}/{}", self.old_pointer, old_index),
+            new_pointer: format!("{}/{}", self.new_pointer, new_index),
+        }
+    }
+}
+
+fn added(path: String, new_pointer: String, value: &Value) -> EntryChange {
+    EntryChange {
+        kind: ChangeKind::Added,
+        path,
+        old_pointer: None,
+        new_pointer: Some(new_pointer),
+        old: None,
+        new: Some(value.clone()),
+    }
+}
+
+fn removed(path: String, old_pointer: String, value: &Value) -> EntryChange {
+    EntryChange {
+        kind: ChangeKind::Removed,
+        path,
+        old_pointer: Some(old_pointer),
+        new_pointer: None,
+        old: Some(value.clone()),
+        new: None,
+    }
+}
+
+fn compare_at(
+    old: &Value,
+    new: &Value,
+    location: &Location,
+    identity_keys: &[String],
+    changes: &mut Vec<EntryChange>,
+) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for (key, value) in old {
+                let member = location.member(key);
+                match new.get(key) {
+                    Some(new_value) => {
+                        compare_at(value, new_value, &member, identity_keys, changes)
+                    }
+                    None => changes.push(removed(member.path, member.old_pointer, value)),
+                }
+            }
+            for (key, value) in new {
+                if !old.contains_key(key) {
+                    let member = location.member(key);
+                    changes.push(added(member.path, member.new_pointer, value));
+                }
+            }
+        }
+        (Value::Array(old), Value::Array(new)) => match identity(old, new, identity_keys) {
+            Some(key) => compare_by_identity(old, new, key, location, identity_keys, changes),
+            None => compare_by_index(old, new, location, identity_keys, changes),
+        },
+        _ if old != new => changes.push(EntryChange {
+            kind: ChangeKind::Changed,
+            path: location.path.clone(),
+            old_pointer: Some(location.old_pointer.clone()),
+            new_pointer: Some(location.new_pointer.clone()),
+            old: Some(old.clone()),
+            new: Some(new.clone()),
+        }),
+        _ => (),
+    }
+}
+
+fn compare_by_identity(
+    old: &[Value],
+    new: &[Value],
+    key: &str,
+    location: &Location,
+    identity_keys: &[String],
+    changes: &mut Vec<EntryChange>,
+) {
+    let label = |value: &Value| format!("[{}={}]", key, identity_label(&value[key]));
+    let new_indices: HashMap<String, usize> = new
+        .iter()
+        .enumerate()
+        .map(|(index, item)| (label(item), index))
+        .collect();
+    let old_indices: HashMap<String, usize> = old
+        .iter()
+        .enumerate()
+        .map(|(index, item)| (label(item), index))
+        .collect();
+
+    for (index, item) in old.iter().enumerate() {
+        let label = label(item);
+        if !new_indices.contains_key(&label) {
+            changes.push(removed(
+                format!("{}{}", location.path, label),
+                format!("{}/{}", location.old_pointer, index),
+                item,
+            ));
+        }
+    }
+    for (index, item) in new.iter().enumerate() {
+        let label = label(item);
+        match old_indices.get(&label) {
+            Some(&old_index) => compare_at(
+                &old[old_index],
+                item,
+                &location.item(label, old_index, index),
+                identity_keys,
+                changes,
+            ),
+            None => changes.push(added(
+                format!("{}{}", location.path, label),
+                format!("{}/{}", location.new_pointer, index),
+                item,
+            )),
+        }
+    }
+}
+
+fn compare_by_index(
+    old: &[Value],
+    new: &[Value],
+    location: &Location,
+    identity_keys: &[String],
+    changes: &mut Vec<EntryChange>,
+) {
+    for (index, (old_item, new_item)) in old.iter().zip(new).enumerate() {
+        let item = location.item(format!("/{}", index), index, index);
+        compare_at(old_item, new_item, &item, identity_keys, changes);
+    }
+    for (index, item) in old.iter().enumerate().skip(new.len()) {
+        changes.push(removed(
+            format!("{}/{}", location.path, index),
+            format!("{}/{}", location.old_pointer, index),
+            item,
+        ));
+    }
+    for (index, item) in new.iter().enumerate().skip(old.len()) {
+        changes.push(added(
+            format!("{}/{}", location.path, index),
+            format!("{}/{}", location.new_pointer, index),
+            item,
+        ));
+    }
+}
+
+/// The first key that identifies every item of both arrays
+pub(crate) fn identity<'a>(
+    old: &[Value],
+    new: &[Value],
+    identity_keys: &'a [String],
+) -> Option<&'a str> {
+    if old.is_empty() && new.is_empty() {
+        return None;
+    }
+    identity_keys
+        .iter()
+        .find(|key| identifies(old, key) && identifies(new, key))
+        .map(String::as_str)
+}
+
+fn identifies(items: &[Value], key: &str) -> bool {
+    let mut seen = HashSet::new();
+    items.iter().all(|item| match item.get(key) {
+        Some(value @ (Value::String(_) | Value::Number(_) | Value::Bool(_))) => {
+            seen.insert(identity_label(value))
+        }
+        _ => false,
+    })
+}
+
+pub(crate) fn identity_label(value: &Value) -> String {
+    match value {
+        Value::String(string) => string.clone(),
+        other => other.to_string(),
+    }
+}
+
+pub(crate) fn escape_token(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// Compact JSON of the value, shortened to [`PREVIEW_LENGTH`] characters
+pub(crate) fn preview(value: &Value) -> String {
+    let text = value.to_string();
+    if text.chars().count() <= PREVIEW_LENGTH {
+        return text;
+    }
+    let mut short: String = text.chars().take(PREVIEW_LENGTH - 1).collect();
+    short.push('…');
+    short
+}
+
+impl UpstreamReport {
+    fn count(&self, kind: ChangeKind) -> usize {
+        self.files.iter().filter(|file| file.kind == kind).count()
+    }
+
+    fn summary(&self) -> String {
+        let entries: usize = self.files.iter().map(|file| file.changes.len()).sum();
+        format!(
+            "{} files changed ({} entries), {} added, {} removed",
+            self.count(ChangeKind::Changed),
+            entries,
+            self.count(ChangeKind::Added),
+            self.count(ChangeKind::Removed)
+        )
+    }
+
+    pub fn print(&self, format: ReportFormat) {
+        match format {
+            ReportFormat::Terminal => self.print_terminal(),
+            ReportFormat::Markdown => print!("{}", self.markdown()),
+            ReportFormat::Json => println!(
+                "{}",
+                serde_json::to_string_pretty(self).expect("Reports always serialize")
+            ),
+        }
+    }
+
+    fn print_terminal(&self) {
+        if self.files.is_empty() {
+            println!("{}", "No upstream changes".bright_green());
+            return;
+        }
+        for file in &self.files {
+            let header = format!("{} {}", file.kind.sign(), file.path);
+            println!("{}", colorize(file.kind, header).bold());
+            for change in &file.changes {
+                let line = match change.kind {
+                    ChangeKind::Added => format!(
+                        "    + {}: {}",
+                        change.path,
+                        preview(change.new.as_ref().expect("Added entries have a value"))
+                    ),
+                    ChangeKind::Removed => format!(
+                        "    - {}: {}",
+                        change.path,
+                        preview(change.old.as_ref().expect("Removed entries had a value"))
+                    ),
+                    ChangeKind::Changed => format!(
+                        "    ~ {}: {} -> {}",
+                        change.path,
+                        preview(change.old.as_ref().expect("Changed entries had a value")),
+                        preview(change.new.as_ref().expect("Changed entries have a value"))
+                    ),
+                };
+                println!("{}", colorize(change.kind, line));
+            }
+        }
+        println!();
+        println!("{}", self.summary());
+    }
+
+    fn markdown(&self) -> String {
+        let mut text = String::from("# Upstream changes\n\n");
+        if self.files.is_empty() {
+            text.push_str("No upstream changes\n");
+            return text;
+        }
+        text.push_str(&format!("{}\n", self.summary()));
+
+        for kind in [ChangeKind::Added, ChangeKind::Removed] {
+            let files: Vec<&FileChange> =
+                self.files.iter().filter(|file| file.kind == kind).collect();
+            if files.is_empty() {
+                continue;
+            }
+            text.push_str(&format!("\n## {} files\n\n", capitalize(kind.label())));
+            for file in files {
+                text.push_str(&format!("- {}\n", code(&file.path)));
+            }
+        }
+
+        for file in self
+            .files
+            .iter()
+            .filter(|file| file.kind == ChangeKind::Changed)
+        {
+            text.push_str(&format!("\n## {}\n\n", code(&file.path)));
+            text.push_str("| Change | Path | Old | New |\n| --- | --- | --- | --- |\n");
+            for change in &file.changes {
+                let cell = |value: &Option<Value>| {
+                    value
+                        .as_ref()
+                        .map(|value| code(&preview(value)))
+                        .unwrap_or_default()
+                };
+                text.push_str(&format!(
+                    "| {} | {} | {} | {} |\n",
+                    change.kind.label(),
+                    code(&change.path),
+                    cell(&change.old),
+                    cell(&change.new)
+                ));
+            }
+        }
+        text
+    }
+}
+
+fn colorize(kind: ChangeKind, text: String) -> ColoredString {
+    match kind {
+        ChangeKind::Added => text.green(),
+        ChangeKind::Removed => text.red(),
+        ChangeKind::Changed => text.yellow(),
+    }
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+/// Markdown code span that also works inside a table cell
+pub(crate) fn code(text: &str) -> String {
+    let text = text.replace('|', "\\|");
+    if text.contains('`') {
+        format!("`` {} ``", text)
+    } else {
+        format!("`{}`", text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    fn summary(changes: &[EntryChange]) -> Vec<(&'static str, &str)> {
+        changes
+            .iter()
+            .map(|change| (change.kind.label(), change.path.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn items_are_matched_by_identity_key() {
+        let old = json!({ "items": [{ "id": "a", "v": 1 }, { "id": "b", "v": 1 }] });
+        let new = json!({ "items": [{ "id": "b", "v": 2 }, { "id": "c", "v": 1 }] });
+        let changes = compare(&old, &new, &keys(&["name", "id"]));
+        assert_eq!(
+            summary(&changes),
+            [
+                ("removed", "/items[id=a]"),
+                ("changed", "/items[id=b]/v"),
+                ("added", "/items[id=c]"),
+            ]
+        );
+        assert_eq!(changes[1].old_pointer.as_deref(), Some("/items/1/v"));
+        assert_eq!(changes[1].new_pointer.as_deref(), Some("/items/0/v"));
+        assert_eq!(changes[2].new_pointer.as_deref(), Some("/items/1"));
+    }
+
+    #[test]
+    fn moved_items_are_not_changes() {
+        let old = json!([{ "id": 1, "v": "x" }, { "id": 2, "v": "y" }]);
+        let new = json!([{ "id": 2, "v": "y" }, { "id": 1, "v": "x" }]);
+        assert!(compare(&old, &new, &keys(&["id"])).is_empty());
+        assert_eq!(
+            summary(&compare(&old, &new, &[])),
+            [
+                ("changed", "/0/id"),
+                ("changed", "/0/v"),
+                ("changed", "/1/id"),
+                ("changed", "/1/v"),
+            ]
+        );
+    }
+
+    #[test]
+    fn items_without_a_unique_key_are_matched_by_index() {
+        let old = json!([{ "id": "a", "v": 1 }, { "id": "a", "v": 2 }]);
+        let new = json!([{ "id": "a", "v": 1 }, { "id": "a", "v": 3 }, { "v": 4 }]);
+        assert_eq!(
+            identity(
+                old.as_array().unwrap(),
+                new.as_array().unwrap(),
+                &keys(&["id"])
+            ),
+            None
+        );
+        assert_eq!(
+            summary(&compare(&old, &new, &keys(&["id"]))),
+            [("changed", "/1/v"), ("added", "/2")]
+        );
+
+        let items = [
+            json!({ "id": 1, "name": "x" }),
+            json!({ "id": 1, "name": "y" }),
+        ];
+        assert_eq!(identity(&items, &[], &keys(&["id", "name"])), Some("name"));
+        assert_eq!(identity(&[], &[], &keys(&["id"])), None);
+        assert_eq!(identity(&[json!({ "id": [1] })], &[], &keys(&["id"])), None);
+    }
+
+    #[test]
+    fn keys_are_escaped_in_paths() {
+        let old = json!({ "a/b": 1, "c~d": { "e": 1 } });
+        let new = json!({ "a/b": 2, "c~d": {} });
+        let changes = compare(&old, &new, &[]);
+        assert_eq!(
+            summary(&changes),
+            [("changed", "/a~1b"), ("removed", "/c~0d/e")]
+        );
+        assert_eq!(changes[1].old_pointer.as_deref(), Some("/c~0d/e"));
+        assert_eq!(escape_token("~/"), "~0~1");
+    }
+
+    #[test]
+    fn markdown_escapes_table_cells() {
+        let report = UpstreamReport {
+            files: vec![
+                FileChange {
+                    path: "new.json".to_string(),
+                    kind: ChangeKind::Added,
+                    changes: Vec::new(),
+                },
+                FileChange {
+                    path: "items.json".to_string(),
+                    kind: ChangeKind::Changed,
+                    changes: compare(&json!({ "a|b": "x|y" }), &json!({ "a|b": "`z`" }), &[]),
+                },
+            ],
+        };
+        assert_eq!(
+            report.markdown(),
+            "# Upstream changes\n\n\
+             1 files changed (1 entries), 1 added, 0 removed\n\
+             \n## Added files\n\n\
+             - `new.json`\n\
+             \n## `items.json`\n\n\
+             | Change | Path | Old | New |\n\
+             | --- | --- | --- | --- |\n\
+             | changed | `/a\\|b` | `\"x\\|y\"` | `` \"`z`\" `` |\n"
+        );
+        assert_eq!(
+            UpstreamReport { files: Vec::new() }.markdown(),
+            "# Upstream changes\n\nNo upstream changes\n"
+        );
+    }
+}
@@ -0,0 +1,567 @@
+use std::{collections::BTreeMap, env};
+
+use json_patch::{AddOperation, Patch, PatchOperation, ReplaceOperation};
+use serde_json::Value;
+
+use crate::{cache::hash, config::ProjectConfig, error::AppError, reference};
+
+const PROJECT_PREFIX: &str = "project.";
+const ENV_PREFIX: &str = "env.";
+
+/// Values of the `${name}` placeholders that strings in patch values can use.
+///
+/// `${project.version}` and the other project fields come from project.json,
+/// `${env.NAME}` from the environment and any other name from its `variables`.
+/// `$${` is written as a plain `${`. Only `add` and `replace` values are substituted
+pub struct Variables {
+    project: BTreeMap<&'static str, String>,
+    variables: BTreeMap<String, String>,
+}
+
+impl Variables {
+    pub fn new(config: &ProjectConfig) -> Result<Self, AppError<'static>> {
+        if let Some(name) = config
+            .variables
+            .keys()
+            .find(|name| name.starts_with(PROJECT_PREFIX) || name.starts_with(ENV_PREFIX))
+        {
+            return Err(AppError::InvalidConfig {
+                message: format!(
+                    "variable `{}` cannot start with `{}` or `{}`",
+                    name, PROJECT_PREFIX, ENV_PREFIX
+                ),
+            });
+        }
+        let project = BTreeMap::from([
+            ("name", config.name.clone()),
+            ("description", config.description.clone()),
+            ("version", config.version.clone()),
+            ("license", config.license.clone()),
+        ]);
+        Ok(Variables {
+            project,
+            variables: config.variables.clone(),
+        })
+    }
+
+    fn resolve(&self, name: &str) -> Option<String> {
+        if let Some(field) = name.strip_prefix(PROJECT_PREFIX) {
+            self.project.get(field).cloned()
+        } else if let Some(var) = name.strip_prefix(ENV_PREFIX) {
+            env::var(var).ok()
+        } else {
+            self.variables.get(name).cloned()
+        }
+    }
+
+    /// Hash of the `variables` and of the values of every placeholder in the file,
+    /// empty if there are neither
+    pub fn hash_for(&self, bytes: &[u8]) -> String {
+        let text = String::from_utf8_lossy(bytes);
+        let names = placeholders(&text);
+        if names.is_empty() && self.variables.is_empty() {
+            return String::new();
+        }
+
+        let mut hashed = serde_json::to_string(&self.variables).expect("Strings always serialize");
+        for name in names {
+            hashed.push_str(&format!("\n{}={:?}", name, self.resolve(name)));
+        }
+        hash(hashed.as_bytes())
+    }
+
+    /// Replaces the placeholders in the values of the patch, returns the name of the
+    /// first placeholder that is not defined
+    pub fn substitute(&self, patch: &mut Patch) -> Result<(), String> {
+        for op in &mut patch.0 {
+            if let Some(value) = value_mut(op) {
+                self.substitute_value(value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the placeholders in the strings of the value
+    pub fn substitute_value(&self, value: &mut Value) -> Result<(), String> {
+        match value {
+            Value::String(string) if string.contains("${") => {
+                *string = self.expand(string)?;
+            }
+            Value::Array(array) => {
+                for item in array {
+                    self.substitute_value(item)?;
+                }
+            }
+            Value::Object(object) => {
+                for item in object.values_mut() {
+                    self.substitute_value(item)?;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn expand(&self, text: &str) -> Result<String, String> {
+        let mut expanded = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find("${") {
+            if rest[..start].ends_with('$') {
+                expanded.push_str(&rest[..start - 1]);
+                expanded.push_str("${");
+                rest = &rest[start + 2..];
+                continue;
+            }
+            let Some(end) = rest[start..].find('}') else {
+                break;
+            };
+            let name = &rest[start + 2..start + end];
+            expanded.push_str(&rest[..start]);
+            expanded.push_str(&self.resolve(name).ok_or_else(|| name.to_string())?);
+            rest = &rest[start + end + 1..];
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+
+    /// Turns values of the patch back into placeholders.
+    ///
+    /// A value the existing patch file gives the same path keeps how it was written
+    /// there as long as `expand` still gives the same value, also when the patch, which
+    /// applies to `base`, changes it member by member. Other values that equal a variable
+    /// become its placeholder, where placeholders the patch file already used come first
+    /// and then the `variables` in order of their name
+    pub fn parameterize(
+        &self,
+        patch: &mut Patch,
+        existing: &[u8],
+        base: &Value,
+        expand: impl Fn(&mut Value) -> bool,
+    ) {
+        let written: Vec<PatchOperation> = serde_json::from_slice::<Patch>(existing)
+            .map(|patch| patch.0)
+            .unwrap_or_default();
+        let text = String::from_utf8_lossy(existing);
+        let mut candidates: Vec<(String, &str)> = Vec::new();
+        let used = placeholders(&text);
+        for name in used
+            .iter()
+            .copied()
+            .chain(self.variables.keys().map(String::as_str))
+        {
+            match self.resolve(name) {
+                Some(value) if !value.is_empty() => candidates.push((value, name)),
+                _ => (),
+            }
+        }
+
+        let restored = restore_written(patch, &written, base, &expand);
+        for (op, restored) in patch.0.iter_mut().zip(restored) {
+            if restored {
+                continue;
+            }
+            let path = match op {
+                PatchOperation::Add(op) => op.path.clone(),
+                PatchOperation::Replace(op) => op.path.clone(),
+                _ => continue,
+            };
+            let Some(value) = value_mut(op) else {
+                continue;
+            };
+            if let Some(raw) = written_value(&written, &path, value, &expand) {
+                *value = raw;
+            } else {
+                parameterize_value(value, &candidates);
+            }
+        }
+    }
+}
+
+/// Puts the written operations whose value has placeholders or references back in
+/// place of the operations the difference split that value into, as long as the patch
+/// still gives the same document.
+///
+/// Returns which operations of the patch were put back
+fn restore_written(
+    patch: &mut Patch,
+    written: &[PatchOperation],
+    base: &Value,
+    expand: impl Fn(&mut Value) -> bool,
+) -> Vec<bool> {
+    let mut restored = vec![false; patch.0.len()];
+    // The patch with the values filled in, which is what it does
+    let mut applied = patch.0.clone();
+    let mut expected = base.clone();
+    if json_patch::patch(&mut expected, &applied).is_err() {
+        return restored;
+    }
+
+    for op in written {
+        let (PatchOperation::Add(AddOperation { path, value: raw })
+        | PatchOperation::Replace(ReplaceOperation { path, value: raw })) = op
+        else {
+            continue;
+        };
+        let mut value = raw.clone();
+        if !expand(&mut value) || value == *raw {
+            continue;
+        }
+        let Some(indices) = split_into(&patch.0, path) else {
+            continue;
+        };
+
+        let first = indices[0];
+        let replace = |value: &Value| {
+            PatchOperation::Replace(ReplaceOperation {
+                path: path.clone(),
+                value: value.clone(),
+            })
+        };
+        let mut candidate = applied.clone();
+        remove_indices(&mut candidate, &indices);
+        candidate.insert(first, replace(&value));
+        let mut result = base.clone();
+        if json_patch::patch(&mut result, &candidate).is_err() || result != expected {
+            continue;
+        }
+
+        applied = candidate;
+        remove_indices(&mut patch.0, &indices);
+        remove_indices(&mut restored, &indices);
+        patch.0.insert(first, replace(raw));
+        restored.insert(first, true);
+    }
+    restored
+}
+
+/// Indices of the operations that only change what is inside the value at `path`,
+/// `None` if there are none or another operation also depends on the value
+fn split_into(patch: &[PatchOperation], path: &str) -> Option<Vec<usize>> {
+    let contains = |parent: &str, pointer: &str| {
+        pointer
+            .strip_prefix(parent)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    };
+    let mut indices = Vec::new();
+    for (index, op) in patch.iter().enumerate() {
+        let (target, from) = match op {
+            PatchOperation::Add(op) => (&op.path, None),
+            PatchOperation::Remove(op) => (&op.path, None),
+            PatchOperation::Replace(op) => (&op.path, None),
+            PatchOperation::Move(op) => (&op.path, Some(&op.from)),
+            PatchOperation::Copy(op) => (&op.path, Some(&op.from)),
+            PatchOperation::Test(op) => (&op.path, None),
+        };
+        if contains(target, path) || from.is_some_and(|from| contains(from, path)) {
+            return None;
+        }
+        let inside = contains(path, target);
+        if from.is_some_and(|from| contains(path, from) != inside) {
+            return None;
+        }
+        if inside {
+            indices.push(index);
+        }
+    }
+    (!indices.is_empty()).then_some(indices)
+}
+
+/// Removes the items at the indices, which are in ascending order
+fn remove_indices<T>(items: &mut Vec<T>, indices: &[usize]) {
+    for &index in indices.iter().rev() {
+        items.remove(index);
+    }
+}
+
+/// Value the existing patch writes to the path, if it gives `value`
+fn written_value(
+    written: &[PatchOperation],
+    path: &str,
+    value: &Value,
+    expand: impl Fn(&mut Value) -> bool,
+) -> Option<Value> {
+    written.iter().rev().find_map(|op| {
+        let (PatchOperation::Add(AddOperation {
+            path: written,
+            value: raw,
+        })
+        | PatchOperation::Replace(ReplaceOperation {
+            path: written,
+            value: raw,
+        })) = op
+        else {
+            return None;
+        };
+        if written != path {
+            return None;
+        }
+        let mut expanded = raw.clone();
+        (expand(&mut expanded) && expanded == *value).then(|| raw.clone())
+    })
+}
+
+fn parameterize_value(value: &mut Value, candidates: &[(String, &str)]) {
+    match value {
+        Value::String(string) => {
+            if let Some((_, name)) = candidates.iter().find(|(value, _)| value == string) {
+                *string = format!("${{{}}}", name);
+            } else if string.contains("${") {
+                // Would be read as a placeholder by build
+                *string = string.replace("${", "$${");
+            }
+        }
+        Value::Array(array) => {
+            for item in array {
+                parameterize_value(item, candidates);
+            }
+        }
+        Value::Object(object) => {
+            for item in object.values_mut() {
+                parameterize_value(item, candidates);
+            }
+            reference::escape(object);
+        }
+        _ => (),
+    }
+}
+
+/// Value of an operation that placeholders and references are filled into
+pub(crate) fn value_mut(op: &mut PatchOperation) -> Option<&mut Value> {
+    match op {
+        PatchOperation::Add(op) => Some(&mut op.value),
+        PatchOperation::Replace(op) => Some(&mut op.value),
+        // Tests compare with what is in the document, which has no placeholders
+        _ => None,
+    }
+}
+
+/// Names of the placeholders in the text, sorted and without duplicates
+fn placeholders(text: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        let escaped = rest[..start].ends_with('$');
+        rest = &rest[start + 2..];
+        if escaped {
+            continue;
+        }
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        names.push(&rest[..end]);
+        rest = &rest[end + 1..];
+    }
+    names.sort_unstable();
+    names.dedup();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{from_value, json};
+
+    use super::*;
+    use crate::reference::{BuiltDocuments, Documents};
+
+    fn config(variables: Value) -> ProjectConfig {
+        let config = json!({
+            "config_version": 1,
+            "name": "mod",
+            "description": "",
+            "version": "1.2.0",
+            "license": "MIT",
+            "authors": [],
+            "paths": { "original": "original", "changes": "changes", "revise": "revise" },
+            "variables": variables,
+        });
+        ProjectConfig::from_value(config).unwrap_or_else(|err| panic!("{}", err.message()))
+    }
+
+    fn variables() -> Variables {
+        Variables::new(&config(json!({ "author": "someone", "empty": "" })))
+            .unwrap_or_else(|err| panic!("{}", err.message()))
+    }
+
+    fn expanded(value: Value) -> Result<Value, String> {
+        let mut value = value;
+        variables().substitute_value(&mut value).map(|_| value)
+    }
+
+    #[test]
+    fn expands_placeholders() {
+        assert_eq!(
+            expanded(json!({
+                "title": "${project.name} v${project.version}",
+                "by": ["${author}", "x${empty}y"],
+                "path": "${env.PATH}",
+                "count": 1,
+            })),
+            Ok(json!({
+                "title": "mod v1.2.0",
+                "by": ["someone", "xy"],
+                "path": env::var("PATH").unwrap_or_default(),
+                "count": 1,
+            }))
+        );
+    }
+
+    #[test]
+    fn undefined_placeholder_is_named() {
+        assert_eq!(
+            expanded(json!("a ${missing} b")),
+            Err("missing".to_string())
+        );
+        assert_eq!(
+            expanded(json!("${project.missing}")),
+            Err("project.missing".to_string())
+        );
+    }
+
+    #[test]
+    fn escaped_and_unterminated_placeholders_are_kept() {
+        assert_eq!(expanded(json!("$${author}")), Ok(json!("${author}")));
+        assert_eq!(
+            expanded(json!("$${author} is ${author}")),
+            Ok(json!("${author} is someone"))
+        );
+        assert_eq!(expanded(json!("${author")), Ok(json!("${author")));
+        assert_eq!(expanded(json!("$ {author}")), Ok(json!("$ {author}")));
+    }
+
+    #[test]
+    fn only_add_and_replace_values_are_substituted() {
+        let mut patch: Patch = from_value(json!([
+            { "op": "add", "path": "/a", "value": "${author}" },
+            { "op": "test", "path": "/b", "value": "${author}" },
+        ]))
+        .unwrap();
+        variables().substitute(&mut patch).unwrap();
+        assert_eq!(
+            serde_json::to_value(patch).unwrap(),
+            json!([
+                { "op": "add", "path": "/a", "value": "someone" },
+                { "op": "test", "path": "/b", "value": "${author}" },
+            ])
+        );
+    }
+
+    #[test]
+    fn parameterize_turns_values_back_into_placeholders() {
+        let variables = variables();
+        let existing = br#"[{ "op": "add", "path": "/v", "value": "${project.version}" }]"#;
+        let mut patch: Patch = from_value(json!([
+            { "op": "add", "path": "/v", "value": "1.2.0" },
+            { "op": "add", "path": "/by", "value": "someone" },
+            { "op": "add", "path": "/text", "value": "costs ${price}" },
+            { "op": "add", "path": "/empty", "value": "" },
+        ]))
+        .unwrap();
+        let expected = patch.clone();
+        variables.parameterize(&mut patch, existing, &json!({}), |value| {
+            variables.substitute_value(value).is_ok()
+        });
+        assert_eq!(
+            serde_json::to_value(&patch).unwrap(),
+            json!([
+                { "op": "add", "path": "/v", "value": "${project.version}" },
+                { "op": "add", "path": "/by", "value": "${author}" },
+                { "op": "add", "path": "/text", "value": "costs $${price}" },
+                { "op": "add", "path": "/empty", "value": "" },
+            ])
+        );
+
+        variables.substitute(&mut patch).unwrap();
+        assert_eq!(patch, expected);
+    }
+
+    #[test]
+    fn hash_changes_with_the_values_used() {
+        let variables = variables();
+        let file = br#"[{ "op": "add", "path": "/a", "value": "${project.version}" }]"#;
+        assert_eq!(variables.hash_for(file), variables.hash_for(file));
+        assert_ne!(variables.hash_for(file), variables.hash_for(b"[]"));
+        assert_eq!(placeholders("${b} $${c} ${a} ${b} ${d"), ["a", "b"]);
+    }
+
+    #[test]
+    fn reserved_prefixes_are_rejected() {
+        match Variables::new(&config(json!({ "env.HOME": "x" }))) {
+            Err(AppError::InvalidConfig { message }) => {
+                assert_eq!(
+                    message,
+                    "variable `env.HOME` cannot start with `project.` or `env.`"
+                )
+            }
+            _ => panic!("the variable was accepted"),
+        }
+    }
+
+    /// Builds the document with the patch file, then writes the patch `update` would
+    /// write for `edit` of the built document
+    fn update_round_trip(existing: Value, edit: impl Fn(&mut Value), guard: bool) -> Value {
+        let base = json!({ "a": { "b": {} }, "n": 1 });
+        let built = BuiltDocuments::from([(
+            std::path::PathBuf::from("actions.json"),
+            Ok((json!({ "type": { "type": "x" } }), String::new())),
+        )]);
+        let documents = Documents::Built(&built);
+        let variables = variables();
+        let expand = |value: &mut Value| {
+            variables.substitute_value(value).is_ok()
+                && reference::resolve_value(value, &documents).is_ok()
+        };
+
+        let mut patch: Patch = from_value(existing.clone()).unwrap();
+        variables.substitute(&mut patch).unwrap();
+        reference::resolve(&mut patch, &documents).unwrap();
+        let mut revise = base.clone();
+        json_patch::patch(&mut revise, &patch).unwrap();
+        edit(&mut revise);
+
+        let mut diff = crate::diff::diff(&base, &revise);
+        let existing = serde_json::to_vec(&existing).unwrap();
+        variables.parameterize(&mut diff, &existing, &base, expand);
+        if guard {
+            diff = crate::diff::guard(&base, &diff);
+        }
+        let mut result = base.clone();
+        let mut filled = diff.clone();
+        reference::resolve(&mut filled, &documents).unwrap();
+        json_patch::patch(&mut result, &filled).unwrap();
+        assert_eq!(result, revise);
+        serde_json::to_value(diff).unwrap()
+    }
+
+    #[test]
+    fn update_keeps_references_to_values_that_exist() {
+        let existing = json!([
+            { "op": "replace", "path": "/a/b", "value": { "$include": "actions.json#/type" } }
+        ]);
+        assert_eq!(update_round_trip(existing.clone(), |_| (), false), existing);
+
+        assert_eq!(
+            update_round_trip(existing.clone(), |revise| revise["n"] = json!(2), false),
+            json!([
+                { "op": "replace", "path": "/a/b", "value": { "$include": "actions.json#/type" } },
+                { "op": "replace", "path": "/n", "value": 2 },
+            ])
+        );
+        assert_eq!(
+            update_round_trip(existing.clone(), |_| (), true),
+            json!([
+                { "op": "test", "path": "/a/b", "value": {} },
+                { "op": "replace", "path": "/a/b", "value": { "$include": "actions.json#/type" } },
+            ])
+        );
+
+        // An edit of the included value is no longer the reference
+        assert_eq!(
+            update_round_trip(
+                existing,
+                |revise| revise["a"]["b"]["type"] = json!("y"),
+                false
+            ),
+            json!([{ "op": "add", "path": "/a/b/type", "value": "y" }])
+        );
+    }
+}